@@ -0,0 +1,344 @@
+use crate::*;
+use crate::dde::*;
+use crate::integrators::SolverStatistics;
+use rand::{Rng, SeedableRng};
+use rand::seq::SliceRandom;
+use rand_distr::{Distribution, Gamma, Poisson};
+use rand_pcg::Pcg64;
+
+/// Disease status of an agent.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Status {
+    Susceptible,
+    Exposed,
+    Infected,
+    Recovered,
+    Dead
+}
+
+/// A single individual, living in a household and possibly going to a workplace or school.
+struct Agent {
+    province: usize,
+    age_group: usize,
+    household: usize,
+    setting: Option<usize>, // Workplace or school.
+    status: Status,
+    infectiousness: f32 // Relative infectiousness while infected, 1 on average.
+}
+
+/// Places where agents meet every day besides their household.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum SettingKind {
+    Workplace,
+    School
+}
+
+/// All agents of the simulation, and the places they meet.
+struct Population {
+    agents: Vec<Agent>,
+    households: usize,
+    settings: Vec<SettingKind>,
+    province_agents: Vec<Vec<usize>>,
+    province_households: Vec<Vec<usize>>
+}
+
+/// Converts a probability per day into a rate per day.
+fn rate(probability: f32) -> f32 {
+    -(1.0 - probability).ln()
+}
+
+/// Samples a group size with the given mean, at least 1.
+fn group_size(rng: &mut Pcg64, mean: f32) -> usize {
+    if mean <= 1.0 {
+        return 1;
+    }
+    1 + Poisson::new((mean - 1.0) as f64).unwrap().sample(rng) as usize
+}
+
+/// Samples an index with probability proportional to its weight.
+fn weighted_index(rng: &mut Pcg64, weights: &[f32]) -> usize {
+    let mut remaining = rng.gen::<f32>() * weights.iter().sum::<f32>();
+    for (idx, weight) in weights.iter().enumerate() {
+        remaining -= weight;
+        if remaining < 0.0 {
+            return idx;
+        }
+    }
+    weights.len() - 1
+}
+
+/// Divides agents into consecutive groups with sizes around the given mean. Returns the group of every agent.
+fn form_groups(rng: &mut Pcg64, agents: &[usize], mean: f32, first_group: usize) -> (Vec<(usize, usize)>, usize) {
+    let mut memberships = Vec::with_capacity(agents.len());
+    let mut group = first_group;
+    let mut remaining = 0;
+    for agent in agents {
+        if remaining == 0 {
+            if !memberships.is_empty() {
+                group += 1;
+            }
+            remaining = group_size(rng, mean);
+        }
+        memberships.push((*agent, group));
+        remaining -= 1;
+    }
+    let next_group = if memberships.is_empty() { first_group } else { group + 1 };
+    (memberships, next_group)
+}
+
+/// Simulates individuals with daily contacts in households, workplaces, schools and the community of their province.
+/// Produces the same results as the compartmental engine, scaled back from agents to the population.
+pub struct AgentSimulation<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    settings: &'a AgentSettings
+}
+
+impl<'a> AgentSimulation<'a> {
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario, settings: &'a AgentSettings) -> Self {
+        Self { graph, scenario, settings }
+    }
+
+    /// Creates the agents of every province, and divides them into households, workplaces and schools.
+    /// Provinces with initial spreaders get at least one exposed agent.
+    fn populate(&self, rng: &mut Pcg64, parameters: &[SimulationParameters]) -> Population {
+        let settings = self.settings;
+        let mut population = Population { agents: vec![], households: 0, settings: vec![], province_agents: vec![], province_households: vec![] };
+
+        for (province_idx, sp) in parameters.iter().enumerate() {
+            let count = (sp.initial_population as f32 * settings.agents_per_person).round() as usize;
+            let fractions: Vec<f32> = sp.age_groups.iter().map(|g| g.population_fraction).collect();
+            let first_agent = population.agents.len();
+            for _ in 0..count {
+                population.agents.push(Agent {
+                    province: province_idx,
+                    age_group: weighted_index(rng, &fractions),
+                    household: 0,
+                    setting: None,
+                    status: Status::Susceptible,
+                    infectiousness: 0.0
+                });
+            }
+            let agents: Vec<usize> = (first_agent..population.agents.len()).collect();
+
+            let (households, next_household) = form_groups(rng, &agents, settings.mean_household_size, population.households);
+            for (agent, household) in households {
+                population.agents[agent].household = household;
+            }
+            population.province_households.push((population.households..next_household).collect());
+            population.households = next_household;
+
+            // Working and school age agents are divided over workplaces and schools in random order.
+            let in_groups = |names: &[String], agent: &Agent| names.contains(&sp.age_groups[agent.age_group].name);
+            for (kind, names, mean) in [(SettingKind::Workplace, &settings.working_age_groups, settings.mean_workplace_size), (SettingKind::School, &settings.school_age_groups, settings.mean_school_size)] {
+                let mut members: Vec<usize> = agents.iter().copied()
+                    .filter(|a| in_groups(names, &population.agents[*a]))
+                    .filter(|_| kind == SettingKind::School || rng.gen::<f32>() < settings.employment_rate)
+                    .collect();
+                members.shuffle(rng);
+                let (memberships, next_setting) = form_groups(rng, &members, mean, population.settings.len());
+                for (agent, setting) in memberships {
+                    population.agents[agent].setting = Some(setting);
+                }
+                population.settings.resize(next_setting, kind);
+            }
+
+            let spreaders = if sp.initial_spreaders > 0 {
+                ((sp.initial_spreaders as f32 * settings.agents_per_person).round() as usize).max(1).min(count)
+            } else { 0 };
+            for agent in agents.choose_multiple(rng, spreaders) {
+                population.agents[*agent].status = Status::Exposed;
+            }
+            population.province_agents.push(agents);
+        }
+        population
+    }
+
+    /// Counts the agents of every age group of every province, scaled back to the population.
+    fn aggregate(&self, population: &Population, parameters: &[SimulationParameters]) -> Vec<Vec<Compartments>> {
+        let mut state: Vec<Vec<Compartments>> = parameters.iter().map(|sp| vec![Compartments::default(); sp.age_groups.len()]).collect();
+        let weight = 1.0 / self.settings.agents_per_person;
+        for agent in &population.agents {
+            let group = &mut state[agent.province][agent.age_group];
+            match agent.status {
+                Status::Susceptible => group.susceptible += weight,
+                Status::Exposed => group.exposed += weight,
+                Status::Infected => group.infected += weight,
+                Status::Recovered => group.recovered += weight,
+                Status::Dead => group.dead += weight
+            }
+            if agent.status != Status::Dead {
+                group.population += weight;
+            }
+        }
+        for (sp, province) in parameters.iter().zip(state.iter_mut()) {
+            for (group_parameters, group) in sp.age_groups.iter().zip(province.iter_mut()) {
+                group.hospitalizations = group.infected * group_parameters.hospitalization_rate;
+            }
+        }
+        state
+    }
+
+    /// Executes the simulation with steps of one day.
+    pub fn run(&self) -> SimulationResults {
+        let graph = self.graph;
+        let scenario = self.scenario;
+        let settings = self.settings;
+        let mut rng = Pcg64::seed_from_u64(settings.seed);
+
+        let mean_density = mean_density(graph);
+        let parameters: Vec<SimulationParameters> = graph.into_iter().map(|p| scenario.parameters_for(p, mean_density)).collect();
+        let mut population = self.populate(&mut rng, &parameters);
+        let infectiousness = Gamma::new(settings.infectiousness_dispersion, 1.0 / settings.infectiousness_dispersion).unwrap();
+
+        let mut state = self.aggregate(&population, &parameters);
+        let repeating_before = state.iter().map(|p| p.iter().map(|g| Compartments { population: g.population, ..Compartments::default() }).collect()).collect();
+        let mut history = History::new(0.0, state.clone(), PreHistory::Constant(repeating_before));
+        let max_delay = parameters.iter().flat_map(|p| p.delays()).fold(0.0, f32::max);
+        let mut measures: Vec<ProvinceMeasures> = parameters.iter().map(ProvinceMeasures::new).collect();
+        update_measures(&mut measures, &parameters, &state, &history, 0.0);
+
+        let output_times = output_times(scenario);
+        let mut outputs: Vec<Vec<Vec<Compartments>>> = state.iter().map(|s| vec![s.clone()]).collect();
+
+        for day in 0..scenario.time_span_in_days {
+            let t = day as f32;
+
+            // Infectious pressure in every household and setting, and of every age group in the community.
+            let mut household_pressure = vec![0.0f32; population.households];
+            let mut setting_pressure = vec![0.0f32; population.settings.len()];
+            let mut community_pressure: Vec<Vec<f32>> = parameters.iter().map(|sp| vec![0.0; sp.age_groups.len()]).collect();
+            let mut alive: Vec<Vec<f32>> = community_pressure.clone();
+            for agent in &population.agents {
+                if agent.status == Status::Dead {
+                    continue;
+                }
+                alive[agent.province][agent.age_group] += 1.0;
+                if agent.status == Status::Infected {
+                    household_pressure[agent.household] += agent.infectiousness;
+                    if let Some(setting) = agent.setting {
+                        setting_pressure[setting] += agent.infectiousness;
+                    }
+                    community_pressure[agent.province][agent.age_group] += agent.infectiousness;
+                }
+            }
+
+            // Measures reduce transmission outside of households.
+            let reductions: Vec<f32> = parameters.iter().enumerate()
+                .map(|(idx, sp)| 1.0 - measures[idx].change(sp, t))
+                .collect();
+            let community_forces: Vec<Vec<f32>> = parameters.iter().enumerate().map(|(idx, sp)| {
+                sp.contact_matrix.iter().map(|row| {
+                    let infected_contacts: f32 = row.iter().zip(&community_pressure[idx]).zip(&alive[idx])
+                        .map(|((contacts, pressure), alive)| if *alive > 0.0 { contacts * pressure / alive } else { 0.0 })
+                        .sum();
+                    settings.community_contacts * rate(settings.community_transmission) * reductions[idx] * infected_contacts
+                }).collect()
+            }).collect();
+
+            let setting_kinds = &population.settings;
+            for agent in population.agents.iter_mut() {
+                let sp = &parameters[agent.province];
+                if agent.status == Status::Dead {
+                    continue;
+                }
+                if rng.gen::<f32>() < 1.0 - (-sp.natural_death_rate).exp() {
+                    agent.status = Status::Dead;
+                    continue;
+                }
+                match agent.status {
+                    Status::Susceptible => {
+                        let setting_force = agent.setting.map_or(0.0, |setting| {
+                            let transmission = match setting_kinds[setting] {
+                                SettingKind::Workplace => settings.workplace_transmission,
+                                SettingKind::School => settings.school_transmission
+                            };
+                            rate(transmission) * reductions[agent.province] * setting_pressure[setting]
+                        });
+                        let force = rate(settings.household_transmission) * household_pressure[agent.household]
+                            + setting_force
+                            + community_forces[agent.province][agent.age_group];
+                        if rng.gen::<f32>() < 1.0 - (-force).exp() {
+                            agent.status = Status::Exposed;
+                        }
+                    },
+                    Status::Exposed => {
+                        if rng.gen::<f32>() < 1.0 - (-1.0 / sp.incubation_period_in_days as f32).exp() {
+                            agent.status = Status::Infected;
+                            agent.infectiousness = infectiousness.sample(&mut rng);
+                        }
+                    },
+                    Status::Infected => {
+                        if rng.gen::<f32>() < 1.0 - (-1.0 / sp.sickness_period_in_days as f32).exp() {
+                            let mortality_rate = sp.age_groups[agent.age_group].mortality_rate;
+                            agent.status = if rng.gen::<f32>() < mortality_rate { Status::Dead } else { Status::Recovered };
+                        }
+                    },
+                    Status::Recovered => {
+                        if rng.gen::<f32>() < 1.0 - (-1.0 / sp.immunity_waning_period_in_days as f32).exp() {
+                            agent.status = Status::Susceptible;
+                        }
+                    },
+                    Status::Dead => {}
+                }
+            }
+
+            // Newborns join a random household of their province, in the youngest age group.
+            for (province_idx, sp) in parameters.iter().enumerate() {
+                let living: f32 = alive[province_idx].iter().sum();
+                let births = Poisson::new((sp.natural_birth_rate * living).max(f32::MIN_POSITIVE) as f64).unwrap().sample(&mut rng) as usize;
+                for _ in 0..births {
+                    let household = match population.province_households[province_idx].choose(&mut rng) {
+                        Some(household) => *household,
+                        None => break
+                    };
+                    population.province_agents[province_idx].push(population.agents.len());
+                    population.agents.push(Agent { province: province_idx, age_group: 0, household, setting: None, status: Status::Susceptible, infectiousness: 0.0 });
+                }
+            }
+
+            // Travelling exposed agents infect random agents of connected provinces, as in the compartmental engine.
+            if scenario.enable_traffic {
+                for province_idx in 0..parameters.len() {
+                    let exposed = population.province_agents[province_idx].iter().filter(|a| population.agents[**a].status == Status::Exposed).count();
+                    let travelling = parameters[province_idx].traffic_rate * exposed as f32;
+                    for (connected_idx, share) in graph[province_idx].traffic_shares() {
+                        if travelling * share <= 0.0 {
+                            continue;
+                        }
+                        let cases = Poisson::new((travelling * share) as f64).unwrap().sample(&mut rng) as usize;
+                        for _ in 0..cases {
+                            if let Some(&agent) = population.province_agents[connected_idx].choose(&mut rng) {
+                                if population.agents[agent].status == Status::Susceptible {
+                                    population.agents[agent].status = Status::Exposed;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            state = self.aggregate(&population, &parameters);
+            let t = t + 1.0;
+            history.push(t, state.clone());
+            history.discard_before(t - max_delay);
+            update_measures(&mut measures, &parameters, &state, &history, t);
+
+            while outputs[0].len() < output_times.len() && output_times[outputs[0].len()] <= t + 1e-3 {
+                for (province_outputs, province_state) in outputs.iter_mut().zip(&state) {
+                    province_outputs.push(province_state.clone());
+                }
+            }
+        }
+
+        SimulationResults {
+            level: graph.level(),
+            time_span_in_days: scenario.time_span_in_days,
+            start_date: scenario.start_date,
+            times: output_times,
+            statistics: SolverStatistics { accepted_steps: scenario.time_span_in_days, ..SolverStatistics::default() },
+            provinces: province_results(graph, &parameters, outputs, measures),
+            sewage: scenario.sewage
+        }
+    }
+}
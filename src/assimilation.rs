@@ -0,0 +1,381 @@
+use crate::*;
+use crate::integrators::*;
+use crate::simulation::{Model, ModelState};
+use chrono::{Duration, NaiveDate};
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+
+/// Observed standard deviation of the logarithm of the prevalence is raised to at least this.
+const MIN_LOG_PREVALENCE_DEVIATION: f32 = 0.05;
+
+/// Stored history of a single member. The last value is its current state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberState {
+    pub times: Vec<f32>,
+    pub values: Vec<Vec<Vec<Compartments>>>,
+    #[serde(default)]
+    pub infections: Vec<f32>, // National new infections per 100 000 inhabitants of the last days, the most recent last.
+    #[serde(default)]
+    pub measures: Vec<ProvinceMeasures>,
+    #[serde(default = "default_transmission")]
+    pub transmission: f32 // Factor on the infection rate of the scenario.
+}
+
+fn default_transmission() -> f32 { 1.0 }
+
+/// Ensemble at the end of an assimilation run, from which a later run continues when new observations arrive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnsembleState {
+    pub start_date: NaiveDate,
+    pub day: usize, // Last day the ensemble was stepped to, since the start date.
+    pub members: Vec<MemberState>
+}
+
+/// Computes the observed quantity of the whole country from the state of every province and
+/// the national new infections per 100 000 inhabitants of the last days, the most recent last.
+pub fn observe(observation: ObservationKind, sewage: &SewageModel, state: &[Vec<Compartments>], infections: &[f32]) -> f32 {
+    match observation {
+        ObservationKind::Prevalence => state.iter().flatten().map(|g| g.infected).sum(),
+        ObservationKind::Sewage => sewage.expected(infections)
+    }
+}
+
+/// Model state of a member, with the daily new infections that sewage measurements depend on.
+#[derive(Clone)]
+struct Member {
+    state: ModelState,
+    infections: Vec<f32> // National new infections per 100 000 inhabitants of the last days, the most recent last.
+}
+
+/// Keeps an ensemble of model states of every province, steps them with the model and updates them at every
+/// date with observations of the whole country. Observations are compared on a logarithmic scale.
+pub struct Assimilation<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    settings: &'a AssimilationSettings,
+    observations: &'a Observations
+}
+
+impl<'a> Assimilation<'a> {
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario, settings: &'a AssimilationSettings, observations: &'a Observations) -> Self {
+        Self { graph, scenario, settings, observations }
+    }
+
+    /// Observed value at a date with the standard deviation of its logarithm, if observed on that date.
+    /// Sewage is only observed on the days plants measured, as the days in between add no information.
+    fn observed(&self, observation: ObservationKind, date: NaiveDate) -> Option<(f32, f32)> {
+        match observation {
+            ObservationKind::Prevalence => self.observations.prevalence.as_ref()?.estimates.get(date).map(|e| {
+                let deviation = (((e.high + 1.0).ln() - (e.low + 1.0).ln()) / (2.0 * 1.96)).max(MIN_LOG_PREVALENCE_DEVIATION);
+                (e.central(), deviation)
+            }),
+            ObservationKind::Sewage => self.observations.sewage_samples.as_ref()?.get(date).map(|rna| (rna, self.scenario.sewage.noise))
+        }
+    }
+
+    /// Observed quantity of a member.
+    fn observe(&self, observation: ObservationKind, member: &Member) -> f32 {
+        observe(observation, &self.scenario.sewage, &member.state.value, &member.infections)
+    }
+
+    /// Multiplies exposed and infected people of every province by a random factor, moving the difference from or to susceptible people.
+    /// The infection rate of the member changes by a random factor as well.
+    fn perturb(&self, parameters: &[SimulationParameters], member: &mut ModelState, rng: &mut Pcg64) {
+        member.transmission *= (self.settings.transmission_noise * rng.sample::<f32, _>(StandardNormal)).exp();
+        let factors: Vec<f32> = member.value.iter().map(|_| (self.settings.process_noise * rng.sample::<f32, _>(StandardNormal)).exp()).collect();
+        let updated: Vec<Vec<(f32, f32)>> = member.value.iter().zip(&factors)
+            .map(|(province, factor)| province.iter().map(|g| (g.exposed * factor, g.infected * factor)).collect())
+            .collect();
+        set_infections(parameters, member, &updated);
+    }
+
+    /// Updates the ensemble with an observation on a logarithmic scale. Returns the effective sample size of the particle filter.
+    fn update(&self, parameters: &[SimulationParameters], members: &mut Vec<Member>, observation: ObservationKind, observed: f32, deviation: f32, rng: &mut Pcg64) -> Option<f32> {
+        let y = (observed.max(0.0) + 1.0).ln();
+        let predicted: Vec<f32> = members.iter().map(|m| (self.observe(observation, m).max(0.0) + 1.0).ln()).collect();
+
+        match self.settings.method {
+            AssimilationMethod::ParticleFilter => {
+                // Systematic resampling by the likelihood of the observation.
+                let log_weights: Vec<f32> = predicted.iter().map(|h| -0.5 * ((y - h) / deviation).powi(2)).collect();
+                let max = log_weights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let weights: Vec<f32> = log_weights.iter().map(|w| (w - max).exp()).collect();
+                let total: f32 = weights.iter().sum();
+                let weights: Vec<f32> = weights.iter().map(|w| w / total).collect();
+                let effective_sample_size = 1.0 / weights.iter().map(|w| w * w).sum::<f32>();
+
+                let count = members.len();
+                let offset = rng.gen::<f32>() / count as f32;
+                let mut cumulative = 0.0;
+                let mut idx = 0;
+                let mut resampled = Vec::with_capacity(count);
+                for (member_idx, weight) in weights.iter().enumerate() {
+                    cumulative += weight;
+                    while idx < count && offset + idx as f32 / count as f32 <= cumulative {
+                        resampled.push(members[member_idx].clone());
+                        idx += 1;
+                    }
+                }
+                // Rounding may leave the last positions empty.
+                while resampled.len() < count {
+                    resampled.push(members[count - 1].clone());
+                }
+                *members = resampled;
+                Some(effective_sample_size)
+            },
+            AssimilationMethod::EnsembleKalmanFilter => {
+                // State of every member: the logarithms of exposed and infected people of every province and age group,
+                // followed by those of the daily new infections the sewage depends on and of the infection rate factor.
+                let states: Vec<Vec<f32>> = members.iter()
+                    .map(|m| m.state.value.iter().flatten()
+                        .flat_map(|g| [g.exposed, g.infected])
+                        .chain(m.infections.iter().copied())
+                        .map(|x| (x.max(0.0) + 1.0).ln())
+                        .chain([m.state.transmission.ln()])
+                        .collect())
+                    .collect();
+                let count = members.len() as f32;
+                let mean_predicted = predicted.iter().sum::<f32>() / count;
+                let variance = predicted.iter().map(|h| (h - mean_predicted).powi(2)).sum::<f32>() / (count - 1.0);
+                let gains: Vec<f32> = (0..states[0].len()).map(|j| {
+                    let mean = states.iter().map(|s| s[j]).sum::<f32>() / count;
+                    let covariance = states.iter().zip(&predicted).map(|(s, h)| (s[j] - mean) * (h - mean_predicted)).sum::<f32>() / (count - 1.0);
+                    covariance / (variance + deviation * deviation)
+                }).collect();
+
+                for ((member, state), h) in members.iter_mut().zip(&states).zip(&predicted) {
+                    let perturbed = y + deviation * rng.sample::<f32, _>(StandardNormal);
+                    let innovation = perturbed - h;
+                    let mut updated: Vec<f32> = state.iter().zip(&gains).map(|(x, gain)| x + gain * innovation).collect();
+                    member.state.transmission = updated.pop().unwrap().exp();
+                    let mut updated = updated.into_iter().map(|x| x.exp() - 1.0);
+                    let values: Vec<Vec<(f32, f32)>> = member.state.value.iter()
+                        .map(|province| province.iter().map(|_| (updated.next().unwrap(), updated.next().unwrap())).collect())
+                        .collect();
+                    member.infections = updated.map(|x| x.max(0.0)).collect();
+                    set_infections(parameters, &mut member.state, &values);
+                }
+                None
+            }
+        }
+    }
+
+    /// Quantiles of a value over all members.
+    fn quantiles(&self, values: impl Iterator<Item = f32>) -> Vec<f32> {
+        let mut values: Vec<f32> = values.collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        self.settings.quantiles.iter().map(|q| quantile(&values, *q)).collect()
+    }
+
+    /// Runs the assimilation from the start of the scenario, or continues an earlier run.
+    /// Returns the results together with the final ensemble, to continue from later.
+    pub fn run(&self, resume: Option<&EnsembleState>) -> Result<(AssimilationResults, EnsembleState), String> {
+        match self.scenario.integrator {
+            IntegratorKind::Euler => self.run_with(&Euler, resume),
+            IntegratorKind::Heun => self.run_with(&Heun, resume),
+            IntegratorKind::Rk4 => self.run_with(&Rk4, resume),
+            IntegratorKind::DormandPrince { absolute_tolerance, relative_tolerance, max_step_size } =>
+                self.run_with(&DormandPrince { absolute_tolerance, relative_tolerance, max_step_size }, resume)
+        }
+    }
+
+    /// Runs the assimilation using the given integrator. Members are stepped in parallel, a day at a time.
+    pub fn run_with<I: Integrator + Sync>(&self, integrator: &I, resume: Option<&EnsembleState>) -> Result<(AssimilationResults, EnsembleState), String> {
+        let start_date = self.scenario.start_date.ok_or("Data assimilation needs a start date in the scenario")?;
+        if self.scenario.engine != EngineKind::Compartmental {
+            return Err(String::from("Data assimilation needs the compartmental engine"));
+        }
+        let model = Model::new(self.graph, self.scenario);
+        let parameters = &model.province_parameters;
+
+        let (mut members, first_day) = match resume {
+            Some(state) => {
+                if state.start_date != start_date {
+                    return Err(format!("Ensemble state starts at {}, the scenario at {}", state.start_date, start_date));
+                }
+                if state.members.iter().any(|m| m.times.is_empty() || m.values.len() != m.times.len() || m.values.iter().any(|v| v.len() != self.graph.len())) {
+                    return Err(String::from("Ensemble state does not match the provinces of the dataset"));
+                }
+                let members: Vec<Member> = state.members.iter()
+                    .map(|m| {
+                        let mut state = model.resumed_state(m.times.clone(), m.values.clone(), m.measures.clone());
+                        state.transmission = m.transmission;
+                        Member { state, infections: m.infections.clone() }
+                    })
+                    .collect();
+                (members, state.day + 1)
+            },
+            None => (vec![Member { state: model.initial_state(None), infections: vec![] }; self.settings.members], 0)
+        };
+        if members.is_empty() {
+            return Err(String::from("Ensemble state contains no members"));
+        }
+
+        let days: Vec<usize> = (first_day..=self.scenario.time_span_in_days).collect();
+        let mut updates = vec![];
+        let mut snapshots: Vec<Vec<Vec<Compartments>>> = vec![]; // Summed over age groups, per day, member and province.
+        let mut observed_values: Vec<Vec<Vec<f32>>> = vec![]; // Per day, kind of observation and member.
+        for &day in &days {
+            let mut rng = Pcg64::seed_from_u64(self.settings.seed.wrapping_add(day as u64));
+            if day > 0 {
+                let shedding_days = self.scenario.sewage.shedding.duration_in_days;
+                members.par_iter_mut().for_each(|member| {
+                    let before = member.state.value.clone();
+                    model.advance_to(integrator, &mut member.state, day as f32);
+                    member.infections.push(daily_infections(parameters, &before, &member.state.value));
+                    let excess = member.infections.len().saturating_sub(shedding_days);
+                    member.infections.drain(..excess);
+                });
+                for member in members.iter_mut() {
+                    self.perturb(parameters, &mut member.state, &mut rng);
+                }
+            }
+
+            let date = start_date + Duration::days(day as i64);
+            if self.settings.assimilate_until.is_none_or(|until| date <= until) {
+                for &observation in &self.settings.observations {
+                    let (observed, deviation) = match self.observed(observation, date) {
+                        Some(v) => v,
+                        None => continue
+                    };
+                    let mean = |members: &[Member]| members.iter().map(|m| self.observe(observation, m)).sum::<f32>() / members.len() as f32;
+                    let forecast = mean(&members);
+                    let effective_sample_size = self.update(parameters, &mut members, observation, observed, deviation, &mut rng);
+                    updates.push(AssimilationUpdate { date, observation, observed, forecast, analysis: mean(&members), effective_sample_size });
+                }
+            }
+
+            snapshots.push(members.iter().map(|m| m.state.value.iter().map(|p| p.iter().copied().sum()).collect()).collect());
+            observed_values.push(self.settings.observations.iter().map(|o| members.iter().map(|m| self.observe(*o, m)).collect()).collect());
+        }
+
+        // Quantiles over the members, for every day and compartment.
+        let quantile_results = |select: &dyn Fn(&Vec<Compartments>) -> Compartments| -> Vec<QuantileResults> {
+            let mut results: Vec<QuantileResults> = self.settings.quantiles.iter()
+                .map(|q| QuantileResults { quantile: *q, values: vec![Compartments::default(); days.len()] })
+                .collect();
+            for (day_idx, snapshot) in snapshots.iter().enumerate() {
+                for compartment in Compartment::ALL.iter() {
+                    let values = self.quantiles(snapshot.iter().map(|m| select(m)[*compartment]));
+                    for (result, value) in results.iter_mut().zip(values) {
+                        result.values[day_idx][*compartment] = value;
+                    }
+                }
+            }
+            results
+        };
+
+        let results = AssimilationResults {
+            method: self.settings.method,
+            members: members.len(),
+            seed: self.settings.seed,
+            start_date,
+            times: days.iter().map(|d| *d as f32).collect(),
+            national: quantile_results(&|m| m.iter().copied().sum()),
+            provinces: self.graph.into_iter().enumerate().map(|(idx, province)| ProvinceAssimilationResults {
+                name: province.name.clone(),
+                quantiles: quantile_results(&|m| m[idx])
+            }).collect(),
+            observables: self.settings.observations.iter().enumerate().map(|(idx, observation)| {
+                let per_day: Vec<Vec<f32>> = observed_values.iter().map(|day| self.quantiles(day[idx].iter().copied())).collect();
+                ObservableResults {
+                    observation: *observation,
+                    quantiles: self.settings.quantiles.iter().enumerate()
+                        .map(|(q_idx, q)| ObservableQuantile { quantile: *q, values: per_day.iter().map(|v| v[q_idx]).collect() })
+                        .collect()
+                }
+            }).collect(),
+            updates
+        };
+
+        let state = EnsembleState {
+            start_date,
+            day: days.last().copied().unwrap_or(first_day.saturating_sub(1)),
+            members: members.iter().map(|m| {
+                let (times, values) = m.state.history.stored();
+                MemberState { times: times.to_vec(), values: values.to_vec(), infections: m.infections.clone(), measures: m.state.measures.clone(), transmission: m.state.transmission }
+            }).collect()
+        };
+        Ok((results, state))
+    }
+}
+
+/// National new infections per 100 000 inhabitants during a day, from the state of every province before and after it.
+fn daily_infections(parameters: &[SimulationParameters], before: &[Vec<Compartments>], after: &[Vec<Compartments>]) -> f32 {
+    let (mut infections, mut population) = (0.0, 0.0);
+    for ((sp, before), after) in parameters.iter().zip(before).zip(after) {
+        let (before, after): (Compartments, Compartments) = (before.iter().copied().sum(), after.iter().copied().sum());
+        infections += new_infections(&before, &after, sp.incubation_period_in_days, 1.0);
+        population += after.population;
+    }
+    if population > 0.0 { infections / population * 100_000.0 } else { 0.0 }
+}
+
+/// Sets exposed and infected people of every province and age group of a member, moving the difference from or to
+/// susceptible people. Protected people change in proportion, hospitalizations follow the unprotected infected people.
+fn set_infections(parameters: &[SimulationParameters], member: &mut ModelState, values: &[Vec<(f32, f32)>]) {
+    for ((province, sp), province_values) in member.value.iter_mut().zip(parameters).zip(values) {
+        for ((group, group_parameters), (exposed, infected)) in province.iter_mut().zip(&sp.age_groups).zip(province_values) {
+            let (exposed, infected) = (exposed.max(0.0), infected.max(0.0));
+            let ratio = |new: f32, old: f32| if old > 0.0 { new / old } else { 0.0 };
+            group.protected_exposed *= ratio(exposed, group.exposed);
+            group.protected_infected *= ratio(infected, group.infected);
+            group.susceptible = (group.susceptible - (exposed - group.exposed) - (infected - group.infected)).max(0.0);
+            group.exposed = exposed;
+            group.infected = infected;
+            group.hospitalizations = (group.infected - group.protected_infected) * group_parameters.hospitalization_rate;
+        }
+    }
+    member.history.replace_last(member.value.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> ProvinceGraph {
+        let provinces = load_file::<Vec<ProvinceData>>("dataset/provinces.json").unwrap();
+        ProvinceGraphBuilder::new(provinces).build().unwrap().0
+    }
+
+    /// Mean observed quantity of the ensemble before and after an update with an observation below it.
+    fn update(method: AssimilationMethod, observation: ObservationKind) -> (f32, f32, f32) {
+        let graph = graph();
+        let scenario = Scenario::default();
+        let settings = AssimilationSettings { method, ..AssimilationSettings::default() };
+        let observations = Observations::default();
+        let assimilation = Assimilation::new(&graph, &scenario, &settings, &observations);
+        let model = Model::new(&graph, &scenario);
+        let parameters = &model.province_parameters;
+
+        // Members differ from a state with infected people by the process noise.
+        let mut start = model.initial_state(None);
+        model.advance_to(&Rk4, &mut start, 10.0);
+        let mut rng = Pcg64::seed_from_u64(0);
+        let mut members: Vec<Member> = (0..settings.members).map(|_| {
+            let mut state = start.clone();
+            assimilation.perturb(parameters, &mut state, &mut rng);
+            let infections = (0..21).map(|_| 10.0 * (0.2 * rng.sample::<f32, _>(StandardNormal)).exp()).collect();
+            Member { state, infections }
+        }).collect();
+        let mean = |members: &[Member]| members.iter().map(|m| assimilation.observe(observation, m)).sum::<f32>() / members.len() as f32;
+
+        let forecast = mean(&members);
+        let observed = 0.8 * forecast;
+        assimilation.update(parameters, &mut members, observation, observed, 0.05, &mut rng);
+        (forecast, observed, mean(&members))
+    }
+
+    #[test]
+    fn updates_move_towards_the_observation() {
+        for method in [AssimilationMethod::ParticleFilter, AssimilationMethod::EnsembleKalmanFilter] {
+            for observation in [ObservationKind::Prevalence, ObservationKind::Sewage] {
+                let (forecast, observed, analysis) = update(method, observation);
+                assert!(analysis < forecast && analysis > observed * 0.9, "{:?} {:?}: {} -> {}, observed {}", method, observation, forecast, analysis, observed);
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use crate::*;
+use chrono::{Duration, NaiveDate};
+use std::cell::Cell;
+
+/// Best point found by a minimization.
+#[derive(Debug, Clone)]
+pub struct Minimum {
+    pub point: Vec<f32>,
+    pub value: f32,
+    pub evaluations: usize,
+    pub converged: bool
+}
+
+/// Minimizes `f` with the Nelder-Mead simplex method, which only needs function values.
+/// The initial simplex consists of `initial` and the step of every axis along it, which may be negative. Stops when the values
+/// of the simplex differ less than `tolerance` relative to the best value, or after `max_evaluations` evaluations.
+pub fn nelder_mead<F: FnMut(&[f32]) -> f32>(mut f: F, initial: &[f32], steps: &[f32], max_evaluations: usize, tolerance: f32) -> Minimum {
+    const REFLECTION: f32 = 1.0;
+    const EXPANSION: f32 = 2.0;
+    const CONTRACTION: f32 = 0.5;
+    const SHRINK: f32 = 0.5;
+
+    let evaluations = Cell::new(0);
+    let mut evaluate = |x: &[f32]| {
+        evaluations.set(evaluations.get() + 1);
+        let value = f(x);
+        if value.is_nan() { f32::INFINITY } else { value }
+    };
+    let along = |from: &[f32], to: &[f32], factor: f32| -> Vec<f32> { from.iter().zip(to).map(|(a, b)| a + factor * (b - a)).collect() };
+
+    let mut simplex: Vec<(Vec<f32>, f32)> = vec![(initial.to_vec(), evaluate(initial))];
+    for axis in 0..initial.len() {
+        let mut vertex = initial.to_vec();
+        vertex[axis] += steps[axis];
+        let value = evaluate(&vertex);
+        simplex.push((vertex, value));
+    }
+
+    let mut converged = false;
+    loop {
+        simplex.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let (best, worst) = (simplex[0].1, simplex[simplex.len() - 1].1);
+        if worst - best <= tolerance * best.abs().max(1.0) {
+            converged = true;
+            break;
+        }
+        if evaluations.get() >= max_evaluations {
+            break;
+        }
+
+        // Centroid of all vertices except the worst.
+        let n = simplex.len() - 1;
+        let centroid: Vec<f32> = (0..initial.len()).map(|axis| simplex[..n].iter().map(|(x, _)| x[axis]).sum::<f32>() / n as f32).collect();
+        let worst_point = simplex[n].0.clone();
+
+        let reflected = along(&centroid, &worst_point, -REFLECTION);
+        let reflected_value = evaluate(&reflected);
+        if reflected_value < best {
+            let expanded = along(&centroid, &reflected, EXPANSION);
+            let expanded_value = evaluate(&expanded);
+            simplex[n] = if expanded_value < reflected_value { (expanded, expanded_value) } else { (reflected, reflected_value) };
+        } else if reflected_value < simplex[n - 1].1 {
+            simplex[n] = (reflected, reflected_value);
+        } else {
+            // Contract towards the better of the reflected and worst point.
+            let (towards, towards_value) = if reflected_value < worst { (&reflected, reflected_value) } else { (&worst_point, worst) };
+            let contracted = along(&centroid, towards, CONTRACTION);
+            let contracted_value = evaluate(&contracted);
+            if contracted_value < towards_value {
+                simplex[n] = (contracted, contracted_value);
+            } else {
+                let best_point = simplex[0].0.clone();
+                for vertex in simplex.iter_mut().skip(1) {
+                    let shrunk = along(&best_point, &vertex.0, SHRINK);
+                    let value = evaluate(&shrunk);
+                    *vertex = (shrunk, value);
+                }
+            }
+        }
+    }
+
+    let (point, value) = simplex.swap_remove(0);
+    Minimum { point, value, evaluations: evaluations.get(), converged }
+}
+
+/// Steps of the initial simplex for a point in the unit interval. Every step goes inward, as a step beyond a bound
+/// is clamped back onto it and would leave that axis unexplored.
+pub(crate) fn unit_steps(point: &[f32], step: f32) -> Vec<f32> {
+    point.iter().map(|x| if *x > 0.5 { -step } else { step }).collect()
+}
+
+/// Infected people of the whole country at every step.
+pub(crate) fn national_infected(results: &SimulationResults) -> Vec<f32> {
+    (0..results.times.len()).map(|step| results.provinces.iter().map(|p| p.values[step].infected).sum()).collect()
+}
+
+/// Copy of a scenario with the given values of the parameters, and the seed offset in days.
+/// The seed offset can not be set in the scenario, as it moves the observations relative to the start date instead.
+/// The start date of the copy moves back by the seed offset rounded to whole days, such that dated measures stay on their dates.
+pub(crate) fn scenario_with(scenario: &Scenario, parameters: &[CalibratedParameter], values: &[f32]) -> (Scenario, f32) {
+    let mut scenario = scenario.clone();
+    let mut offset = 0.0;
+    for (parameter, value) in parameters.iter().zip(values) {
+        match parameter {
+            CalibratedParameter::RNaught => scenario.r_naught = *value,
+            CalibratedParameter::SeedOffsetInDays => offset = *value,
+            CalibratedParameter::InitialSpreaders => scenario.initial_spreaders = value.round().max(1.0) as usize,
+            CalibratedParameter::MeasureEffectiveness => scenario.measure_effectiveness = *value
+        }
+    }
+    scenario.start_date = scenario.start_date.map(|date| date - Duration::days(offset.round() as i64));
+    (scenario, offset)
+}
+
+/// Value of the objective for model values next to the observed estimates.
+fn objective_value(objective: Objective, pairs: &[(f32, Estimate)]) -> f32 {
+    match objective {
+        Objective::LogLeastSquares => pairs.iter()
+            .map(|(model, observed)| ((model.max(0.0) + 1.0).ln() - (observed.central() + 1.0).ln()).powi(2))
+            .sum(),
+        Objective::GaussianLikelihood => pairs.iter()
+            .map(|(model, observed)| {
+                let sigma = ((observed.high - observed.low) / (2.0 * 1.96)).max(1.0);
+                (model - observed.central()).powi(2) / (2.0 * sigma * sigma) + sigma.ln()
+            })
+            .sum()
+    }
+}
+
+/// Value of the objective for expected RNA per ml next to the measured sewage.
+fn sewage_objective_value(objective: Objective, model: &SewageModel, pairs: &[(f32, f32)]) -> f32 {
+    match objective {
+        Objective::LogLeastSquares => pairs.iter().map(|(expected, measured)| ((expected.max(0.0) + 1.0).ln() - (measured.max(0.0) + 1.0).ln()).powi(2)).sum(),
+        Objective::GaussianLikelihood => pairs.iter().map(|(expected, measured)| -model.log_likelihood(*expected, *measured)).sum()
+    }
+}
+
+/// Fits scenario values to the observed prevalence, the amount of infectious people in the whole country,
+/// and optionally to the national sewage measurements through the sewage model of the scenario.
+/// Parameters are searched within their bounds, which Nelder-Mead sees as the unit interval.
+pub struct Calibration<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    settings: &'a CalibrationSettings,
+    prevalence: &'a ObservedData,
+    sewage: Option<&'a DailySeries<f32>>
+}
+
+/// Model values next to the observed prevalence and next to the measured sewage.
+type Pairs = (Vec<(f32, Estimate)>, Vec<(f32, f32)>);
+
+impl<'a> Calibration<'a> {
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario, settings: &'a CalibrationSettings, prevalence: &'a ObservedData) -> Self {
+        Self { graph, scenario, settings, prevalence, sewage: None }
+    }
+
+    /// Also fits the national RNA per ml in sewage, weighted by the sewage weight of the settings.
+    pub fn with_sewage(mut self, sewage: &'a DailySeries<f32>) -> Self {
+        self.sewage = Some(sewage);
+        self
+    }
+
+    /// Whether a date lies within the fitted period.
+    fn fitted(&self, date: NaiveDate) -> bool {
+        self.settings.fit_from.is_none_or(|from| date >= from) && self.settings.fit_until.is_none_or(|until| date <= until)
+    }
+
+    /// Observed days within the fitted period.
+    fn observed(&self) -> Vec<(NaiveDate, Estimate)> {
+        self.prevalence.estimates.dated().filter(|(date, _)| self.fitted(*date)).collect()
+    }
+
+    /// Measured sewage within the fitted period. Empty when sewage is not fitted.
+    fn measured(&self) -> Vec<(NaiveDate, f32)> {
+        match self.sewage {
+            Some(sewage) if self.settings.sewage_weight > 0.0 => sewage.dated().filter(|(date, _)| self.fitted(*date)).collect(),
+            _ => vec![]
+        }
+    }
+
+    /// Current value of a parameter in the scenario.
+    fn current(&self, parameter: CalibratedParameter) -> f32 {
+        match parameter {
+            CalibratedParameter::RNaught => self.scenario.r_naught,
+            CalibratedParameter::SeedOffsetInDays => 0.0,
+            CalibratedParameter::InitialSpreaders => self.scenario.initial_spreaders as f32,
+            CalibratedParameter::MeasureEffectiveness => self.scenario.measure_effectiveness
+        }
+    }
+
+    /// Parameter values at a point of the unit cube.
+    fn values(&self, point: &[f32]) -> Vec<f32> {
+        self.settings.parameters.iter().zip(point).map(|(b, x)| b.lower + x.clamp(0.0, 1.0) * (b.upper - b.lower)).collect()
+    }
+
+    /// Scenario with the given parameter values, and the seed offset in days.
+    fn scenario_with(&self, values: &[f32]) -> (Scenario, f32) {
+        let parameters: Vec<CalibratedParameter> = self.settings.parameters.iter().map(|b| b.parameter).collect();
+        scenario_with(self.scenario, &parameters, values)
+    }
+
+    /// Simulates the scenario with the given parameter values. Returns the model value next to every observed estimate,
+    /// and the expected RNA per ml next to every sewage measurement. Days the simulation does not cover count as no infected people.
+    fn evaluate(&self, values: &[f32], start_date: NaiveDate, observed: &[(NaiveDate, Estimate)], measured: &[(NaiveDate, f32)]) -> Pairs {
+        let (mut scenario, offset) = self.scenario_with(values);
+        // Only simulate as long as needed to cover all observations.
+        let last = observed.iter().map(|(date, _)| *date).chain(measured.iter().map(|(date, _)| *date)).max()
+            .map(|date| (date - start_date).num_days())
+            .unwrap_or(0);
+        scenario.time_span_in_days = (last as f32 + offset).ceil().max(1.0) as usize + 1;
+        scenario.stochastic = None;
+
+        let results = Simulation::new(self.graph, &scenario).run();
+        let at = |date: NaiveDate, values: &[f32]| interpolate(&results.times, values, (date - start_date).num_days() as f32 + offset).unwrap_or(0.0);
+        let infected = national_infected(&results);
+        let rna = if measured.is_empty() { vec![] } else { self.scenario.sewage.national_rna(&results) };
+        (
+            observed.iter().map(|(date, estimate)| (at(*date, &infected), *estimate)).collect(),
+            measured.iter().map(|(date, value)| (at(*date, &rna), *value)).collect()
+        )
+    }
+
+    /// Objective of the model values next to the observations.
+    fn objective(&self, (prevalence, sewage): &Pairs) -> f32 {
+        let objective = objective_value(self.settings.objective, prevalence);
+        if sewage.is_empty() {
+            return objective;
+        }
+        objective + self.settings.sewage_weight * sewage_objective_value(self.settings.objective, &self.scenario.sewage, sewage)
+    }
+
+    /// Fits the parameters. Returns the statistics of the fit together with the fitted scenario.
+    pub fn run(&self) -> Result<(CalibrationResults, Scenario), String> {
+        let start_date = self.scenario.start_date.ok_or("Calibration needs a start date in the scenario")?;
+        if self.scenario.engine != EngineKind::Compartmental {
+            return Err(String::from("Calibration needs the compartmental engine"));
+        }
+        let observed = self.observed();
+        if observed.is_empty() {
+            return Err(String::from("No observed prevalence within the fitted period"));
+        }
+
+        let initial: Vec<f32> = self.settings.parameters.iter()
+            .map(|b| ((self.current(b.parameter) - b.lower) / (b.upper - b.lower)).clamp(0.0, 1.0))
+            .collect();
+        let measured = self.measured();
+        let minimum = nelder_mead(
+            |point| self.objective(&self.evaluate(&self.values(point), start_date, &observed, &measured)),
+            &initial,
+            &unit_steps(&initial, 0.25),
+            self.settings.max_evaluations,
+            self.settings.tolerance
+        );
+
+        // The seed offset moves the start date, so the statistics are those of the offset rounded to whole days.
+        let values: Vec<f32> = self.settings.parameters.iter().zip(self.values(&minimum.point))
+            .map(|(b, value)| if b.parameter == CalibratedParameter::SeedOffsetInDays { value.round() } else { value })
+            .collect();
+        let fitted = self.evaluate(&values, start_date, &observed, &measured);
+        let objective_value = self.objective(&fitted);
+        let (pairs, sewage_pairs) = fitted;
+        let count = pairs.len() as f32;
+        let mean: f32 = pairs.iter().map(|(_, o)| o.central()).sum::<f32>() / count;
+        let residual: f32 = pairs.iter().map(|(m, o)| (m - o.central()).powi(2)).sum();
+        let total: f32 = pairs.iter().map(|(_, o)| (o.central() - mean).powi(2)).sum();
+
+        let (mut scenario, _) = self.scenario_with(&values);
+        let fitted_start_date = scenario.start_date.unwrap_or(start_date);
+        scenario.calibration = None;
+
+        let results = CalibrationResults {
+            parameters: self.settings.parameters.iter().zip(&values).map(|(b, value)| FittedValue { parameter: b.parameter, value: *value }).collect(),
+            start_date: fitted_start_date,
+            objective: self.settings.objective,
+            objective_value,
+            observations: pairs.len(),
+            rmse: (residual / count).sqrt(),
+            r_squared: if total > 0.0 { 1.0 - residual / total } else { 0.0 },
+            interval_coverage: pairs.iter().filter(|(m, o)| o.contains(*m)).count() as f32 / count,
+            sewage_observations: sewage_pairs.len(),
+            sewage_log_rmse: if sewage_pairs.is_empty() { None } else {
+                Some((sewage_objective_value(Objective::LogLeastSquares, &self.scenario.sewage, &sewage_pairs) / sewage_pairs.len() as f32).sqrt())
+            },
+            evaluations: minimum.evaluations,
+            converged: minimum.converged
+        };
+        Ok((results, scenario))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nelder_mead_finds_the_minimum_of_a_quadratic() {
+        let f = |x: &[f32]| (x[0] - 1.0).powi(2) + 10.0 * (x[1] + 2.0).powi(2) + (x[0] - 1.0) * (x[1] + 2.0) + 3.0;
+        let minimum = nelder_mead(f, &[0.0, 0.0], &[0.5, 0.5], 1000, 1e-7);
+        assert!(minimum.converged);
+        assert!((minimum.point[0] - 1.0).abs() < 1e-2 && (minimum.point[1] + 2.0).abs() < 1e-2, "{:?}", minimum.point);
+        assert!((minimum.value - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn nelder_mead_stops_after_the_maximum_evaluations() {
+        let minimum = nelder_mead(|x: &[f32]| x.iter().map(|v| (v - 5.0).powi(2)).sum(), &[0.0; 3], &[0.1; 3], 20, 0.0);
+        assert!(!minimum.converged);
+        // The last iteration starts below the maximum, and evaluates at most a reflection, a contraction and a shrink of 3 vertices.
+        assert!(minimum.evaluations >= 20 && minimum.evaluations < 20 + 5);
+    }
+
+    #[test]
+    fn explores_a_parameter_starting_at_its_upper_bound() {
+        // As in the calibration, points outside the unit interval are clamped onto it.
+        let f = |x: &[f32]| (x[0].clamp(0.0, 1.0) - 0.3).powi(2) + (x[1].clamp(0.0, 1.0) - 0.6).powi(2);
+        let initial = [1.0, 0.0];
+        assert_eq!(unit_steps(&initial, 0.25), vec![-0.25, 0.25]);
+        let minimum = nelder_mead(f, &initial, &unit_steps(&initial, 0.25), 1000, 1e-7);
+        assert!((minimum.point[0] - 0.3).abs() < 1e-2 && (minimum.point[1] - 0.6).abs() < 1e-2, "{:?}", minimum.point);
+    }
+}
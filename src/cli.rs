@@ -0,0 +1,263 @@
+use covid19_simulator::{AsymmetricConnections, RegionLevel};
+
+/// Usage text shown for `help` and on invalid arguments.
+pub const USAGE: &str = "Usage: covid-19_simulator <command> [options]
+
+Commands:
+  run [scenario]            Simulate a scenario (default scenario if omitted), write results and plots
+  calibrate <scenario>      Fit a scenario to the observed prevalence and sewage, write the fitted scenario and its results
+  infer <scenario>          Sample the posterior of scenario values given the observed prevalence and reproduction number
+  assimilate <scenario>     Update an ensemble of model states with the observed prevalence and sewage, day by day
+  plot <results>            Plot previously written results
+  export <results>          Export previously written results
+  validate [dataset]        Check a province dataset for errors
+  help                      Show this text
+
+Options, each accepted only by the commands which use it:
+  --dataset <path>          Province dataset to use (default: ./dataset/provinces.json)
+  --output <dir>            Directory to write results, plots and exports to (default: ./output)
+  --step-size <days>        Overrides the step size of the scenario
+  --days <days>             Overrides the time span of the scenario
+  --realizations <n>        Runs a stochastic ensemble of n realizations next to the deterministic run
+  --seed <n>                Seed of the stochastic ensemble and of the inference chains
+  --format <csv|json>       Format used by export (default: csv)
+  --level <level>           Converts results to municipality, safety_region, province or country after simulating,
+                            then plots and exports them per region. Safety regions are also plotted against their sewage measurements
+  --dataset-level <level>   Level of the regions in the dataset (default: province). Scenarios with another level are
+                            simulated on the regions at their level, derived from the dataset with the region hierarchy
+  --regions <path>          Region hierarchy used to convert between levels (default: ./dataset/regions.json)
+  --observations <dir>      RIVM datasets to calibrate and infer with, and to plot results with a start date against (default: ./dataset)
+  --resume <state>          Ensemble state written by an earlier assimilate, to continue from instead of day 0
+  --asymmetric <policy>     Connections in one direction only: warn, fix or reject (default: warn)";
+
+/// Formats which results can be exported to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json
+}
+
+/// The subcommand to execute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run { scenario: Option<String> },
+    Calibrate { scenario: String },
+    Infer { scenario: String },
+    Assimilate { scenario: String, resume: Option<String> },
+    Plot { results: String },
+    Export { results: String, format: ExportFormat },
+    Validate,
+    Help
+}
+
+impl Command {
+    /// Name of the command on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Run { .. } => "run",
+            Command::Calibrate { .. } => "calibrate",
+            Command::Infer { .. } => "infer",
+            Command::Assimilate { .. } => "assimilate",
+            Command::Plot { .. } => "plot",
+            Command::Export { .. } => "export",
+            Command::Validate => "validate",
+            Command::Help => "help"
+        }
+    }
+
+    /// Options which the command uses. Any other option is rejected, so that a mistyped invocation does not silently ignore it.
+    fn options(&self) -> &'static [&'static str] {
+        match self {
+            Command::Run { .. } => &["--dataset", "--asymmetric", "--output", "--step-size", "--days", "--realizations", "--seed",
+                                     "--observations", "--level", "--dataset-level", "--regions"],
+            Command::Calibrate { .. } => &["--dataset", "--asymmetric", "--output", "--step-size", "--days",
+                                           "--observations", "--level", "--dataset-level", "--regions"],
+            Command::Infer { .. } => &["--dataset", "--asymmetric", "--output", "--step-size", "--days", "--seed", "--observations",
+                                       "--dataset-level", "--regions"],
+            Command::Assimilate { .. } => &["--dataset", "--asymmetric", "--output", "--step-size", "--days", "--seed", "--observations", "--resume",
+                                            "--dataset-level", "--regions"],
+            Command::Plot { .. } => &["--output", "--observations", "--level", "--regions"],
+            Command::Export { .. } => &["--output", "--format", "--level", "--regions"],
+            Command::Validate => &["--dataset", "--asymmetric", "--dataset-level"],
+            Command::Help => &[]
+        }
+    }
+}
+
+/// Parsed command line arguments.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub command: Command,
+    pub dataset: String,
+    pub output: String,
+    pub step_size: Option<f32>,
+    pub days: Option<usize>,
+    pub realizations: Option<usize>,
+    pub seed: Option<u64>,
+    pub asymmetric_connections: AsymmetricConnections,
+    pub level: Option<RegionLevel>,
+    pub dataset_level: RegionLevel,
+    pub regions: String,
+    pub observations: String
+}
+
+impl Options {
+    /// Parses the arguments, excluding the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut positional: Vec<String> = vec![];
+        let mut dataset = None;
+        let mut output = String::from("./output");
+        let mut step_size = None;
+        let mut days = None;
+        let mut realizations = None;
+        let mut seed = None;
+        let mut format = ExportFormat::Csv;
+        let mut asymmetric_connections = AsymmetricConnections::Warn;
+        let mut level = None;
+        let mut dataset_level = RegionLevel::Province;
+        let mut regions = String::from("./dataset/regions.json");
+        let mut observations = String::from("./dataset");
+        let mut resume = None;
+        let mut given: Vec<String> = vec![];
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg);
+                continue;
+            }
+
+            let value = match args.next() {
+                Some(v) => v,
+                None => return Err(format!("Missing value for {}", arg))
+            };
+            given.push(arg.clone());
+            match arg.as_str() {
+                "--dataset" => dataset = Some(value),
+                "--output" => output = value,
+                "--step-size" => step_size = Some(value.parse::<f32>()
+                    .ok()
+                    .filter(|v| *v > 0.0)
+                    .ok_or(format!("Invalid step size: {}", value))?),
+                "--days" => days = Some(value.parse::<usize>()
+                    .ok()
+                    .filter(|v| *v > 0)
+                    .ok_or(format!("Invalid amount of days: {}", value))?),
+                "--realizations" => realizations = Some(value.parse::<usize>()
+                    .ok()
+                    .filter(|v| *v > 0)
+                    .ok_or(format!("Invalid amount of realizations: {}", value))?),
+                "--seed" => seed = Some(value.parse::<u64>()
+                    .map_err(|_| format!("Invalid seed: {}", value))?),
+                "--format" => format = match value.as_str() {
+                    "csv" => ExportFormat::Csv,
+                    "json" => ExportFormat::Json,
+                    _ => return Err(format!("Unknown export format: {}", value))
+                },
+                "--asymmetric" => asymmetric_connections = match value.as_str() {
+                    "warn" => AsymmetricConnections::Warn,
+                    "fix" => AsymmetricConnections::Fix,
+                    "reject" => AsymmetricConnections::Reject,
+                    _ => return Err(format!("Unknown policy for asymmetric connections: {}", value))
+                },
+                "--level" => level = Some(RegionLevel::from_key(&value).ok_or(format!("Unknown region level: {}", value))?),
+                "--dataset-level" => dataset_level = RegionLevel::from_key(&value).ok_or(format!("Unknown region level: {}", value))?,
+                "--regions" => regions = value,
+                "--observations" => observations = value,
+                "--resume" => resume = Some(value),
+                _ => return Err(format!("Unknown option: {}", arg))
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            Some("run") => Command::Run { scenario: positional.next() },
+            Some("calibrate") => Command::Calibrate { scenario: positional.next().ok_or("calibrate requires a scenario file")? },
+            Some("infer") => Command::Infer { scenario: positional.next().ok_or("infer requires a scenario file")? },
+            Some("assimilate") => Command::Assimilate { scenario: positional.next().ok_or("assimilate requires a scenario file")?, resume },
+            Some("plot") => Command::Plot { results: positional.next().ok_or("plot requires a results file")? },
+            Some("export") => Command::Export { results: positional.next().ok_or("export requires a results file")?, format },
+            Some("validate") => {
+                // The dataset may be given positionally as well.
+                if let Some(path) = positional.next() {
+                    dataset = Some(path);
+                }
+                Command::Validate
+            },
+            Some("help") | None => Command::Help,
+            Some(other) => return Err(format!("Unknown command: {}", other))
+        };
+
+        if let Some(extra) = positional.next() {
+            return Err(format!("Unexpected argument: {}", extra));
+        }
+        if let Some(option) = given.iter().find(|o| !command.options().contains(&o.as_str())) {
+            return Err(format!("Option {} does not apply to {}", option, command.name()));
+        }
+
+        Ok(Options {
+            command,
+            dataset: dataset.unwrap_or_else(|| String::from("./dataset/provinces.json")),
+            output,
+            step_size,
+            days,
+            realizations,
+            seed,
+            asymmetric_connections,
+            level,
+            dataset_level,
+            regions,
+            observations
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_commands_with_their_options() {
+        let options = parse("run scenarios/default.json --days 30 --output out --level country").unwrap();
+        assert_eq!(options.command, Command::Run { scenario: Some(String::from("scenarios/default.json")) });
+        assert_eq!((options.days, options.output.as_str(), options.level), (Some(30), "out", Some(RegionLevel::Country)));
+        assert_eq!(parse("export results.json --format json").unwrap().command, Command::Export { results: String::from("results.json"), format: ExportFormat::Json });
+        assert_eq!(parse("").unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        for (args, error) in [
+            ("run --days", "Missing value for --days"),
+            ("run --days 0", "Invalid amount of days: 0"),
+            ("run --step-size -1", "Invalid step size: -1"),
+            ("run --seed x", "Invalid seed: x"),
+            ("run --level county", "Unknown region level: county"),
+            ("run --asymmetric ignore", "Unknown policy for asymmetric connections: ignore"),
+            ("export results.json --format xml", "Unknown export format: xml"),
+            ("run --verbose 1", "Unknown option: --verbose"),
+            ("simulate", "Unknown command: simulate"),
+            ("calibrate", "calibrate requires a scenario file"),
+            ("plot results.json extra", "Unexpected argument: extra")
+        ] {
+            assert_eq!(parse(args).unwrap_err(), error, "{}", args);
+        }
+    }
+
+    #[test]
+    fn rejects_options_of_other_commands() {
+        for (args, error) in [
+            ("run --format json", "Option --format does not apply to run"),
+            ("run --resume state.json", "Option --resume does not apply to run"),
+            ("validate --level country", "Option --level does not apply to validate"),
+            ("plot results.json --days 10", "Option --days does not apply to plot"),
+            ("--output out", "Option --output does not apply to help")
+        ] {
+            assert_eq!(parse(args).unwrap_err(), error, "{}", args);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::integrators::OdeState;
+use crate::predefined_color;
+use plotters::style::RGBColor;
+use plotters::style::colors::*;
+use serde::{Serialize, Deserialize};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Index, IndexMut, Mul, Sub};
+
+predefined_color!(ORANGE, 255, 165, 0, "The predefined orange color");
+predefined_color!(PURPLE, 128, 0, 128, "The predefined purple color");
+predefined_color!(TEAL, 0, 128, 128, "The predefined teal color");
+predefined_color!(BROWN, 139, 69, 19, "The predefined brown color");
+predefined_color!(OLIVE, 128, 128, 0, "The predefined olive color");
+
+/// Compartments of the model. Population and hospitalizations are tracked alongside the SEIRD compartments.
+/// Protected exposed and infected are the part of exposed and infected protected against severe disease by vaccination.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Compartment {
+    Susceptible,
+    Exposed,
+    Infected,
+    Recovered,
+    Dead,
+    Population,
+    Hospitalizations,
+    VaccinatedPartially,
+    VaccinatedFully,
+    ProtectedExposed,
+    ProtectedInfected
+}
+
+impl Compartment {
+    /// All compartments, in the order they are plotted and exported.
+    pub const ALL: [Compartment; 11] = [
+        Compartment::Susceptible,
+        Compartment::Exposed,
+        Compartment::Infected,
+        Compartment::Recovered,
+        Compartment::Dead,
+        Compartment::Population,
+        Compartment::Hospitalizations,
+        Compartment::VaccinatedPartially,
+        Compartment::VaccinatedFully,
+        Compartment::ProtectedExposed,
+        Compartment::ProtectedInfected
+    ];
+
+    /// Name used for plot legends.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Compartment::Susceptible => "Susceptible",
+            Compartment::Exposed => "Exposed",
+            Compartment::Infected => "Infected",
+            Compartment::Recovered => "Recovered",
+            Compartment::Dead => "Deaths",
+            Compartment::Population => "Population",
+            Compartment::Hospitalizations => "Hospitalizations",
+            Compartment::VaccinatedPartially => "Partially vaccinated",
+            Compartment::VaccinatedFully => "Fully vaccinated",
+            Compartment::ProtectedExposed => "Protected exposed",
+            Compartment::ProtectedInfected => "Protected infected"
+        }
+    }
+
+    /// Name used for exports and scenario files.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Compartment::Susceptible => "susceptible",
+            Compartment::Exposed => "exposed",
+            Compartment::Infected => "infected",
+            Compartment::Recovered => "recovered",
+            Compartment::Dead => "dead",
+            Compartment::Population => "population",
+            Compartment::Hospitalizations => "hospitalizations",
+            Compartment::VaccinatedPartially => "vaccinated_partially",
+            Compartment::VaccinatedFully => "vaccinated_fully",
+            Compartment::ProtectedExposed => "protected_exposed",
+            Compartment::ProtectedInfected => "protected_infected"
+        }
+    }
+
+    /// Color used for plotting.
+    pub fn color(&self) -> RGBColor {
+        match self {
+            Compartment::Susceptible => ORANGE,
+            Compartment::Exposed => MAGENTA,
+            Compartment::Infected => RED,
+            Compartment::Recovered => GREEN,
+            Compartment::Dead => BLACK,
+            Compartment::Population => BLUE,
+            Compartment::Hospitalizations => CYAN,
+            Compartment::VaccinatedPartially => TEAL,
+            Compartment::VaccinatedFully => PURPLE,
+            Compartment::ProtectedExposed => OLIVE,
+            Compartment::ProtectedInfected => BROWN
+        }
+    }
+}
+
+/// State of a single population, holding a value for every compartment.
+/// Missing compartments default to zero, so results written before vaccination was added can still be loaded.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Compartments {
+    pub susceptible: f32,
+    pub exposed: f32,
+    pub infected: f32,
+    pub recovered: f32,
+    pub dead: f32,
+    pub population: f32,
+    pub hospitalizations: f32,
+    pub vaccinated_partially: f32,
+    pub vaccinated_fully: f32,
+    pub protected_exposed: f32,
+    pub protected_infected: f32
+}
+
+impl Index<Compartment> for Compartments {
+    type Output = f32;
+
+    fn index(&self, index: Compartment) -> &Self::Output {
+        match index {
+            Compartment::Susceptible => &self.susceptible,
+            Compartment::Exposed => &self.exposed,
+            Compartment::Infected => &self.infected,
+            Compartment::Recovered => &self.recovered,
+            Compartment::Dead => &self.dead,
+            Compartment::Population => &self.population,
+            Compartment::Hospitalizations => &self.hospitalizations,
+            Compartment::VaccinatedPartially => &self.vaccinated_partially,
+            Compartment::VaccinatedFully => &self.vaccinated_fully,
+            Compartment::ProtectedExposed => &self.protected_exposed,
+            Compartment::ProtectedInfected => &self.protected_infected
+        }
+    }
+}
+
+impl IndexMut<Compartment> for Compartments {
+    fn index_mut(&mut self, index: Compartment) -> &mut Self::Output {
+        match index {
+            Compartment::Susceptible => &mut self.susceptible,
+            Compartment::Exposed => &mut self.exposed,
+            Compartment::Infected => &mut self.infected,
+            Compartment::Recovered => &mut self.recovered,
+            Compartment::Dead => &mut self.dead,
+            Compartment::Population => &mut self.population,
+            Compartment::Hospitalizations => &mut self.hospitalizations,
+            Compartment::VaccinatedPartially => &mut self.vaccinated_partially,
+            Compartment::VaccinatedFully => &mut self.vaccinated_fully,
+            Compartment::ProtectedExposed => &mut self.protected_exposed,
+            Compartment::ProtectedInfected => &mut self.protected_infected
+        }
+    }
+}
+
+impl Compartments {
+    /// Applies `f` to every pair of matching compartments.
+    pub fn zip_with<F: Fn(f32, f32) -> f32>(&self, other: &Compartments, f: F) -> Compartments {
+        let mut result = Compartments::default();
+        for c in Compartment::ALL.iter() {
+            result[*c] = f(self[*c], other[*c]);
+        }
+        result
+    }
+}
+
+impl Add for Compartments {
+    type Output = Compartments;
+
+    fn add(self, rhs: Compartments) -> Self::Output {
+        self.zip_with(&rhs, |a, b| a + b)
+    }
+}
+
+impl Sub for Compartments {
+    type Output = Compartments;
+
+    fn sub(self, rhs: Compartments) -> Self::Output {
+        self.zip_with(&rhs, |a, b| a - b)
+    }
+}
+
+impl Mul<f32> for Compartments {
+    type Output = Compartments;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        self.zip_with(&self, |a, _| a * rhs)
+    }
+}
+
+impl AddAssign for Compartments {
+    fn add_assign(&mut self, rhs: Compartments) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sum for Compartments {
+    fn sum<I: Iterator<Item = Compartments>>(iter: I) -> Self {
+        iter.fold(Compartments::default(), |acc, c| acc + c)
+    }
+}
+
+impl OdeState for Compartments {
+    fn scaled_add(&self, factor: f32, other: &Self) -> Self {
+        *self + *other * factor
+    }
+
+    fn scale(&self, factor: f32) -> Self {
+        *self * factor
+    }
+
+    fn zip_fold<A, F: FnMut(A, f32, f32) -> A>(&self, other: &Self, init: A, mut f: F) -> A {
+        Compartment::ALL.iter().fold(init, |acc, c| f(acc, self[*c], other[*c]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compartments with a different value in every field, 1 to 11 in the order of `Compartment::ALL`.
+    fn numbered() -> Compartments {
+        let mut compartments = Compartments::default();
+        for (idx, compartment) in Compartment::ALL.iter().enumerate() {
+            compartments[*compartment] = idx as f32 + 1.0;
+        }
+        compartments
+    }
+
+    #[test]
+    fn indexes_every_compartment_by_its_own_field() {
+        let compartments = numbered();
+        let fields = [compartments.susceptible, compartments.exposed, compartments.infected, compartments.recovered, compartments.dead,
+                      compartments.population, compartments.hospitalizations, compartments.vaccinated_partially, compartments.vaccinated_fully,
+                      compartments.protected_exposed, compartments.protected_infected];
+        assert_eq!(fields.to_vec(), (1..=11).map(|v| v as f32).collect::<Vec<f32>>());
+        assert_eq!(compartments.zip_fold(&compartments, 0, |count, _, _| count + 1), 11);
+    }
+
+    #[test]
+    fn operates_on_every_compartment() {
+        let compartments = numbered();
+        let sum: Compartments = vec![compartments, compartments * 2.0].into_iter().sum();
+        assert_eq!(sum, compartments * 3.0);
+        assert_eq!(sum - compartments, compartments + compartments);
+        assert_eq!(compartments.scaled_add(-1.0, &compartments), Compartments::default());
+        assert_eq!(Compartment::ALL.iter().map(|c| sum[*c]).sum::<f32>(), 3.0 * 66.0);
+    }
+
+    #[test]
+    fn loads_values_without_the_vaccination_compartments() {
+        let values: Compartments = serde_json::from_str(r#"{ "susceptible": 10.0, "infected": 2.0, "population": 12.0 }"#).unwrap();
+        assert_eq!(values, Compartments { susceptible: 10.0, infected: 2.0, population: 12.0, ..Compartments::default() });
+    }
+}
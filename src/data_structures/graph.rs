@@ -1,26 +1,108 @@
-use crate::ProvinceData;
+use crate::{ProvinceData, RegionLevel};
+use serde::{Serialize, Deserialize};
+use std::convert::TryFrom;
 use std::ops::Index;
 
+/// Directed edge to another province. The flow is the amount of people travelling along it every day, if known.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Connection {
+    pub province: usize,
+    pub daily_flow: Option<f32>
+}
+
+/// Geographic center of a province, in degrees.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Centroid {
+    pub latitude: f32,
+    pub longitude: f32
+}
+
+impl Centroid {
+    /// Great-circle distance to another centroid in kilometers, using the haversine formula.
+    pub fn distance_in_km(&self, other: &Centroid) -> f32 {
+        const EARTH_RADIUS_IN_KM: f32 = 6371.0;
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_IN_KM * a.sqrt().asin()
+    }
+}
+
 /// Represents a province and it's properties
 #[derive(Debug)]
 pub struct Province {
     pub name: String,
     pub population: u32,
     pub density_per_square_km: u16,
-    pub connected_provinces: Vec<usize>
+    pub age_distribution: Vec<f32>,
+    pub centroid: Option<Centroid>,
+    pub connections: Vec<Connection>
+}
+
+impl Province {
+    /// Destinations of people travelling from this province, with the fraction of travellers going to each.
+    /// Travellers are divided by flow when every connection has one, and equally otherwise.
+    pub fn traffic_shares(&self) -> Vec<(usize, f32)> {
+        let flows: Option<Vec<f32>> = self.connections.iter().map(|c| c.daily_flow).collect();
+        let total: f32 = flows.iter().flatten().sum();
+        match flows {
+            Some(flows) if total > 0.0 => self.connections.iter().zip(flows).map(|(c, flow)| (c.province, flow / total)).collect(),
+            _ => self.connections.iter().map(|c| (c.province, 1.0 / self.connections.len() as f32)).collect()
+        }
+    }
+}
+
+/// Daily amount of people travelling between provinces, `flows[origin][destination]` in the order of `provinces`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OriginDestinationMatrix {
+    pub provinces: Vec<String>,
+    pub flows: Vec<Vec<f32>>
 }
 
 /// Represents a graph of provinces, where nodes are provinces and edges connect nearby provinces.
+/// The nodes may be regions at another level, such as safety regions.
 #[derive(Debug)]
 pub struct ProvinceGraph {
-    nodes: Vec<Province>
+    nodes: Vec<Province>,
+    level: RegionLevel
 }
 
 impl ProvinceGraph {
+    /// Returns the level of the regions in the graph
+    pub fn level(&self) -> RegionLevel {
+        self.level
+    }
+
     /// Returns a mount of nodes
     pub fn len(&self) -> usize {
         self.nodes.len()
     }
+
+    /// Returns whether the graph has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Replaces the connections of every province in the matrix by an edge to every destination with a positive flow.
+    /// Provinces which are not in the matrix keep their connections.
+    pub fn apply_flows(&mut self, matrix: &OriginDestinationMatrix) -> Result<(), String> {
+        let size = matrix.provinces.len();
+        if matrix.flows.len() != size || matrix.flows.iter().any(|row| row.len() != size) {
+            return Err(format!("Origin-destination matrix needs to be {0}x{0} for {0} provinces", size));
+        }
+        let indices = matrix.provinces.iter()
+            .map(|name| self.nodes.iter().position(|p| p.name == *name).ok_or(format!("Origin-destination matrix contains unknown province {}", name)))
+            .collect::<Result<Vec<usize>, String>>()?;
+
+        for (origin, row) in indices.iter().zip(&matrix.flows) {
+            self.nodes[*origin].connections = indices.iter().zip(row)
+                .filter(|(destination, flow)| *destination != origin && **flow > 0.0)
+                .map(|(destination, flow)| Connection { province: *destination, daily_flow: Some(*flow) })
+                .collect();
+        }
+        Ok(())
+    }
 }
 
 /// Trait for indexing into the graph
@@ -32,40 +114,180 @@ impl Index<usize> for ProvinceGraph {
     }
 }
 
-/// Builds the graph based on an array of provincedata objects
-impl From<Vec<ProvinceData>> for ProvinceGraph {
-    fn from(provinces: Vec<ProvinceData>) -> Self {
-        let mut graph = Self {
-            nodes: vec![]
-        };
+/// Problems found while building a graph from province data. Each names the entry at fault.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    EmptyName,
+    DuplicateProvince(String),
+    UnknownConnection { province: String, connected: String },
+    SelfLoop(String),
+    DuplicateConnection { province: String, connected: String },
+    AsymmetricConnection { province: String, connected: String },
+    NegativeFlow { province: String, connected: String },
+    ImplausiblePopulation { province: String, population: u32 },
+    ImplausibleDensity { province: String, density_per_square_km: u16 },
+    ImplausibleArea { province: String, area_in_square_km: f32 },
+    InvalidAgeDistribution(String),
+    InvalidCentroid(String)
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::EmptyName => write!(f, "province without a name"),
+            GraphError::DuplicateProvince(name) => write!(f, "{} occurs more than once", name),
+            GraphError::UnknownConnection { province, connected } => write!(f, "{}: connected province {} does not exist", province, connected),
+            GraphError::SelfLoop(name) => write!(f, "{} is connected to itself", name),
+            GraphError::DuplicateConnection { province, connected } => write!(f, "{}: connected to {} more than once", province, connected),
+            GraphError::AsymmetricConnection { province, connected } => write!(f, "{} is connected to {}, but {} is not connected to {}", province, connected, connected, province),
+            GraphError::NegativeFlow { province, connected } => write!(f, "{}: flow to {} is negative", province, connected),
+            GraphError::ImplausiblePopulation { province, population } => write!(f, "{}: population of {} is implausible", province, population),
+            GraphError::ImplausibleDensity { province, density_per_square_km } => write!(f, "{}: density of {} per km2 is implausible", province, density_per_square_km),
+            GraphError::ImplausibleArea { province, area_in_square_km } => write!(f, "{}: population and density give an implausible area of {:.0} km2", province, area_in_square_km),
+            GraphError::InvalidAgeDistribution(name) => write!(f, "{}: age distribution needs non-negative fractions summing to 1", name),
+            GraphError::InvalidCentroid(name) => write!(f, "{}: centroid is not a valid latitude and longitude", name)
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// What to do with connections which only exist in one direction.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum AsymmetricConnections {
+    /// Keep them, and report them as warnings.
+    #[default]
+    Warn,
+    /// Add the missing reverse connection with the same flow, and report them as warnings.
+    Fix,
+    /// Fail with an error.
+    Reject
+}
+
+/// Limits of plausible province values. Populations and areas are far outside those of any province,
+/// densities outside those of any city.
+const MAX_POPULATION: u32 = 100_000_000;
+const MAX_DENSITY_PER_SQUARE_KM: u16 = 30_000;
+const MAX_AREA_IN_SQUARE_KM: f32 = 2_000_000.0;
+
+/// Builds a graph from province data, validating the data on the way.
+pub struct ProvinceGraphBuilder {
+    provinces: Vec<ProvinceData>,
+    asymmetric_connections: AsymmetricConnections,
+    level: RegionLevel
+}
+
+impl ProvinceGraphBuilder {
+    pub fn new(provinces: Vec<ProvinceData>) -> Self {
+        Self { provinces, asymmetric_connections: AsymmetricConnections::default(), level: RegionLevel::default() }
+    }
+
+    /// Level of the regions in the data, provinces by default.
+    pub fn level(mut self, level: RegionLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn asymmetric_connections(mut self, policy: AsymmetricConnections) -> Self {
+        self.asymmetric_connections = policy;
+        self
+    }
+
+    /// Checks a single province on its own.
+    fn check_province(province: &ProvinceData) -> Result<(), GraphError> {
+        let name = &province.name;
+        if province.population == 0 || province.population > MAX_POPULATION {
+            return Err(GraphError::ImplausiblePopulation { province: name.clone(), population: province.population });
+        }
+        if province.density_per_square_km == 0 || province.density_per_square_km > MAX_DENSITY_PER_SQUARE_KM {
+            return Err(GraphError::ImplausibleDensity { province: name.clone(), density_per_square_km: province.density_per_square_km });
+        }
+        let area_in_square_km = province.population as f32 / province.density_per_square_km as f32;
+        if area_in_square_km > MAX_AREA_IN_SQUARE_KM {
+            return Err(GraphError::ImplausibleArea { province: name.clone(), area_in_square_km });
+        }
+        let fractions: f32 = province.age_distribution.iter().sum();
+        if !province.age_distribution.is_empty() && ((fractions - 1.0).abs() > 0.01 || province.age_distribution.iter().any(|f| *f < 0.0)) {
+            return Err(GraphError::InvalidAgeDistribution(name.clone()));
+        }
+        if let Some(centroid) = province.centroid {
+            if !(-90.0..=90.0).contains(&centroid.latitude) || !(-180.0..=180.0).contains(&centroid.longitude) {
+                return Err(GraphError::InvalidCentroid(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the graph. Returns the graph together with the problems which were accepted or fixed.
+    pub fn build(self) -> Result<(ProvinceGraph, Vec<GraphError>), GraphError> {
+        let mut graph = ProvinceGraph { nodes: vec![], level: self.level };
+        let mut warnings = vec![];
 
-        for province in &provinces {
-            let mut node = Province {
+        for province in &self.provinces {
+            if province.name.is_empty() {
+                return Err(GraphError::EmptyName);
+            }
+            if graph.nodes.iter().any(|p| p.name == province.name) {
+                return Err(GraphError::DuplicateProvince(province.name.clone()));
+            }
+            Self::check_province(province)?;
+            graph.nodes.push(Province {
                 name: province.name.clone(),
                 population: province.population,
                 density_per_square_km: province.density_per_square_km,
-                connected_provinces: vec![]
-            };
-            graph.nodes.push(node);
+                age_distribution: province.age_distribution.clone(),
+                centroid: province.centroid,
+                connections: vec![]
+            });
         }
 
-        for province in &provinces {
+        for (idx, province) in self.provinces.iter().enumerate() {
             for connected in &province.connected_provinces {
                 let connected_idx = graph.nodes
                     .iter()
-                    .enumerate()
-                    .find(|(i, p)| p.name == *connected)
-                    .map(|(i,p)| i)
-                    .unwrap();
-                let mut node = graph.nodes
-                    .iter_mut()
-                    .find(|p|p.name == province.name)
-                    .unwrap();
-                node.connected_provinces.push(connected_idx);
+                    .position(|p| p.name == connected.name())
+                    .ok_or_else(|| GraphError::UnknownConnection { province: province.name.clone(), connected: connected.name().to_string() })?;
+                if connected_idx == idx {
+                    return Err(GraphError::SelfLoop(province.name.clone()));
+                }
+                if graph.nodes[idx].connections.iter().any(|c| c.province == connected_idx) {
+                    return Err(GraphError::DuplicateConnection { province: province.name.clone(), connected: connected.name().to_string() });
+                }
+                if connected.daily_flow().is_some_and(|flow| flow < 0.0) {
+                    return Err(GraphError::NegativeFlow { province: province.name.clone(), connected: connected.name().to_string() });
+                }
+                graph.nodes[idx].connections.push(Connection { province: connected_idx, daily_flow: connected.daily_flow() });
             }
         }
 
-        graph
+        // Connections which only exist in one direction, collected before fixing any of them.
+        let asymmetric: Vec<(usize, Connection)> = graph.nodes.iter().enumerate()
+            .flat_map(|(idx, node)| node.connections.iter().map(move |c| (idx, *c)))
+            .filter(|(idx, c)| !graph.nodes[c.province].connections.iter().any(|back| back.province == *idx))
+            .collect();
+        for (idx, connection) in asymmetric {
+            let error = GraphError::AsymmetricConnection { province: graph.nodes[idx].name.clone(), connected: graph.nodes[connection.province].name.clone() };
+            match self.asymmetric_connections {
+                AsymmetricConnections::Warn => warnings.push(error),
+                AsymmetricConnections::Fix => {
+                    graph.nodes[connection.province].connections.push(Connection { province: idx, daily_flow: connection.daily_flow });
+                    warnings.push(error);
+                },
+                AsymmetricConnections::Reject => return Err(error)
+            }
+        }
+
+        Ok((graph, warnings))
+    }
+}
+
+/// Builds the graph based on an array of provincedata objects. Asymmetric connections are rejected,
+/// use `ProvinceGraphBuilder` to accept or fix them and get them back as warnings.
+impl TryFrom<Vec<ProvinceData>> for ProvinceGraph {
+    type Error = GraphError;
+
+    fn try_from(provinces: Vec<ProvinceData>) -> Result<Self, Self::Error> {
+        ProvinceGraphBuilder::new(provinces).asymmetric_connections(AsymmetricConnections::Reject).build().map(|(graph, _)| graph)
     }
 }
 
@@ -97,4 +319,92 @@ impl<'a> IntoIterator for &'a mut ProvinceGraph {
     fn into_iter(self) -> Self::IntoIter {
         self.nodes.iter_mut()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConnectionData;
+
+    fn province(name: &str, connected: &[&str]) -> ProvinceData {
+        ProvinceData {
+            name: String::from(name),
+            population: 100_000,
+            density_per_square_km: 100,
+            age_distribution: vec![],
+            centroid: None,
+            connected_provinces: connected.iter().map(|c| ConnectionData::Name(String::from(*c))).collect()
+        }
+    }
+
+    fn build(provinces: Vec<ProvinceData>, policy: AsymmetricConnections) -> Result<(ProvinceGraph, Vec<GraphError>), GraphError> {
+        ProvinceGraphBuilder::new(provinces).asymmetric_connections(policy).build()
+    }
+
+    #[test]
+    fn reports_the_province_at_fault() {
+        let name = |n: &str| String::from(n);
+        type Change = fn(&mut Vec<ProvinceData>);
+        let cases: Vec<(Change, GraphError)> = vec![
+            (|p| p[0].name = String::new(), GraphError::EmptyName),
+            (|p| p[1].name = String::from("A"), GraphError::DuplicateProvince(name("A"))),
+            (|p| p[0].connected_provinces.push(ConnectionData::Name(String::from("C"))), GraphError::UnknownConnection { province: name("A"), connected: name("C") }),
+            (|p| p[0].connected_provinces.push(ConnectionData::Name(String::from("A"))), GraphError::SelfLoop(name("A"))),
+            (|p| p[0].connected_provinces.push(ConnectionData::Name(String::from("B"))), GraphError::DuplicateConnection { province: name("A"), connected: name("B") }),
+            (|p| p[1].connected_provinces.clear(), GraphError::AsymmetricConnection { province: name("A"), connected: name("B") }),
+            (|p| p[0].connected_provinces[0] = ConnectionData::Weighted { name: String::from("B"), daily_flow: -1.0 }, GraphError::NegativeFlow { province: name("A"), connected: name("B") }),
+            (|p| p[0].population = 0, GraphError::ImplausiblePopulation { province: name("A"), population: 0 }),
+            (|p| p[0].density_per_square_km = 40_000, GraphError::ImplausibleDensity { province: name("A"), density_per_square_km: 40_000 }),
+            (|p| { p[0].population = 10_000_000; p[0].density_per_square_km = 1 }, GraphError::ImplausibleArea { province: name("A"), area_in_square_km: 10_000_000.0 }),
+            (|p| p[0].age_distribution = vec![0.5, 0.4], GraphError::InvalidAgeDistribution(name("A"))),
+            (|p| p[0].centroid = Some(Centroid { latitude: 95.0, longitude: 5.0 }), GraphError::InvalidCentroid(name("A")))
+        ];
+
+        assert!(build(vec![province("A", &["B"]), province("B", &["A"])], AsymmetricConnections::Reject).is_ok());
+        for (change, error) in cases {
+            let mut provinces = vec![province("A", &["B"]), province("B", &["A"])];
+            change(&mut provinces);
+            assert_eq!(build(provinces, AsymmetricConnections::Reject).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn warns_about_or_fixes_asymmetric_connections() {
+        let provinces = || vec![province("A", &["B"]), province("B", &[])];
+        let warning = GraphError::AsymmetricConnection { province: String::from("A"), connected: String::from("B") };
+
+        let (graph, warnings) = build(provinces(), AsymmetricConnections::Warn).unwrap();
+        assert_eq!((warnings, graph[1].connections.len()), (vec![warning.clone()], 0));
+
+        let (graph, warnings) = build(provinces(), AsymmetricConnections::Fix).unwrap();
+        assert_eq!((warnings, graph[1].connections.clone()), (vec![warning], vec![Connection { province: 0, daily_flow: None }]));
+    }
+
+    #[test]
+    fn divides_traffic_by_flow_when_every_connection_has_one() {
+        let provinces = || vec![province("A", &["B", "C"]), province("B", &["A"]), province("C", &["A"])];
+        let (graph, _) = build(provinces(), AsymmetricConnections::Reject).unwrap();
+        assert_eq!(graph[0].traffic_shares(), vec![(1, 0.5), (2, 0.5)]);
+
+        let mut provinces = provinces();
+        provinces[0].connected_provinces = vec![
+            ConnectionData::Weighted { name: String::from("B"), daily_flow: 3000.0 },
+            ConnectionData::Weighted { name: String::from("C"), daily_flow: 1000.0 }
+        ];
+        let (graph, _) = build(provinces, AsymmetricConnections::Reject).unwrap();
+        assert_eq!(graph[0].traffic_shares(), vec![(1, 0.75), (2, 0.25)]);
+    }
+
+    #[test]
+    fn replaces_connections_by_the_flows_of_a_matrix() {
+        let (mut graph, _) = build(vec![province("A", &["B"]), province("B", &["A", "C"]), province("C", &["B"])], AsymmetricConnections::Reject).unwrap();
+        let matrix = |provinces: &[&str], flows: Vec<Vec<f32>>| OriginDestinationMatrix { provinces: provinces.iter().map(|p| String::from(*p)).collect(), flows };
+
+        graph.apply_flows(&matrix(&["A", "C"], vec![vec![0.0, 500.0], vec![200.0, 0.0]])).unwrap();
+        assert_eq!(graph[0].connections, vec![Connection { province: 2, daily_flow: Some(500.0) }]);
+        assert_eq!(graph[1].connections.len(), 2, "provinces outside the matrix keep their connections");
+
+        assert!(graph.apply_flows(&matrix(&["A", "D"], vec![vec![0.0, 1.0], vec![1.0, 0.0]])).is_err());
+        assert!(graph.apply_flows(&matrix(&["A", "B"], vec![vec![0.0, 1.0]])).is_err());
+    }
+}
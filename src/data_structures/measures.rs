@@ -0,0 +1,330 @@
+use crate::{Compartment, Compartments, SimulationParameters};
+use crate::dde::ProvinceHistory;
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+
+/*
+  {
+    "name": "social_distancing",
+    "trigger": { "compartment": "hospitalizations", "comparison": "at_least", "threshold": 0.1, "release_threshold": 0.05, "relative_to": "hospital_capacity" },
+    "effect": 0.2,
+    "start_date": "2020-03-12",
+    "minimum_duration_in_days": 14,
+    "ramp_in_days": 3,
+    "ramp_out_days": 7
+  }
+*/
+
+/// Comparison of the value of a compartment with the threshold of a trigger.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdComparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost
+}
+
+impl ThresholdComparison {
+    pub fn holds(&self, value: f32, threshold: f32) -> bool {
+        match self {
+            ThresholdComparison::Above => value > threshold,
+            ThresholdComparison::AtLeast => value >= threshold,
+            ThresholdComparison::Below => value < threshold,
+            ThresholdComparison::AtMost => value <= threshold
+        }
+    }
+}
+
+/// What the threshold of a trigger is expressed in.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdUnit {
+    #[default]
+    People,
+    HospitalCapacity, // Fraction of the hospital capacity of the province.
+    Population // Fraction of the population of the province at the time it looks back to.
+}
+
+/// Condition on the state of a province, summed over all age groups, which activates a measure.
+/// An active measure is released once the comparison no longer holds against the release threshold.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Trigger {
+    pub compartment: Compartment,
+    pub comparison: ThresholdComparison,
+    pub threshold: f32,
+    #[serde(default)]
+    pub release_threshold: Option<f32>, // Same as the threshold if not given. Lies beyond it to keep measures from switching back and forth.
+    #[serde(default)]
+    pub relative_to: ThresholdUnit,
+    /// Looks this far back into the history of the province, as the state is only known after a while.
+    /// The incubation period of the province if not given, 0 looks at the current state.
+    #[serde(default)]
+    pub delay_in_days: Option<f32>
+}
+
+/// Measure defined in scenario data. Reduces the infection rate of a province by its effect from the moment its trigger holds
+/// until it is released, between its start and end date. The effect builds up and wears off linearly over the ramps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Measure {
+    pub name: String,
+    #[serde(default)]
+    pub trigger: Option<Trigger>, // Without a trigger, the measure is active during all of its dates.
+    pub effect: f32, // Reduction of the infection rate while active.
+    #[serde(default)]
+    pub start_date: Option<NaiveDate>, // First day the measure can be active.
+    #[serde(default)]
+    pub end_date: Option<NaiveDate>, // First day the measure is no longer active.
+    #[serde(default)]
+    pub minimum_duration_in_days: f32, // An activated measure is not released before this, unless its end date is reached.
+    #[serde(default)]
+    pub ramp_in_days: f32,
+    #[serde(default)]
+    pub ramp_out_days: f32
+}
+
+impl Measure {
+    /// Parameters of the measure in a province. Dates become days since `start_date`, the date of time 0.
+    pub fn parameters(&self, start_date: Option<NaiveDate>, incubation_period_in_days: usize, max_hospital_capacity: usize) -> MeasureParameters {
+        let time = |date: Option<NaiveDate>| date.zip(start_date).map(|(date, start)| (date - start).num_days() as f32);
+        MeasureParameters {
+            name: self.name.clone(),
+            trigger: self.trigger,
+            delay_in_days: self.trigger.and_then(|t| t.delay_in_days).unwrap_or(incubation_period_in_days as f32),
+            hospital_capacity: max_hospital_capacity as f32,
+            effect: self.effect,
+            start_time: time(self.start_date),
+            end_time: time(self.end_date),
+            minimum_duration_in_days: self.minimum_duration_in_days,
+            ramp_in_days: self.ramp_in_days,
+            ramp_out_days: self.ramp_out_days
+        }
+    }
+}
+
+/// Measures which are predefined, and can be enabled from a scenario file by name.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MeasureKind {
+    HandWashing,
+    SocialDistancing,
+    SoftLockDown,
+    HardLockDown
+}
+
+impl MeasureKind {
+    /// Returns the definition of this measure.
+    pub fn measure(&self) -> Measure {
+        let (name, compartment, threshold, relative_to, effect) = match self {
+            // Hand washing starts once more than 1000 people are infected.
+            MeasureKind::HandWashing => ("hand_washing", Compartment::Infected, 1000.0, ThresholdUnit::People, 0.15),
+            // Social distancing reduces transmission by having more distance between people and limits visits etc.
+            MeasureKind::SocialDistancing => ("social_distancing", Compartment::Hospitalizations, 0.1, ThresholdUnit::HospitalCapacity, 0.2),
+            // Lock downs are triggered based on hospital capacity, the hard lock down adding to the soft one.
+            MeasureKind::SoftLockDown => ("soft_lock_down", Compartment::Hospitalizations, 0.3, ThresholdUnit::HospitalCapacity, 0.3),
+            MeasureKind::HardLockDown => ("hard_lock_down", Compartment::Hospitalizations, 0.5, ThresholdUnit::HospitalCapacity, 0.15)
+        };
+        let comparison = if *self == MeasureKind::HandWashing { ThresholdComparison::Above } else { ThresholdComparison::AtLeast };
+        Measure {
+            name: String::from(name),
+            trigger: Some(Trigger { compartment, comparison, threshold, release_threshold: None, relative_to, delay_in_days: None }),
+            effect,
+            start_date: None,
+            end_date: None,
+            minimum_duration_in_days: 0.0,
+            ramp_in_days: 0.0,
+            ramp_out_days: 0.0
+        }
+    }
+}
+
+/// Measure of a scenario: either a predefined measure by name, or a complete definition.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MeasureDefinition {
+    Predefined(MeasureKind),
+    Defined(Measure)
+}
+
+impl MeasureDefinition {
+    pub fn measure(&self) -> Measure {
+        match self {
+            MeasureDefinition::Predefined(kind) => kind.measure(),
+            MeasureDefinition::Defined(measure) => measure.clone()
+        }
+    }
+}
+
+/// Whether a measure is active in a province, and since when.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub struct MeasureState {
+    pub active: bool,
+    pub since: f32, // Time the measure was last activated or released.
+    pub level_at_switch: f32 // Fraction of the effect at that time, from which it ramps in or out.
+}
+
+/// Activation or release of a measure.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MeasureEvent {
+    pub time: f32,
+    pub measure: String,
+    pub activated: bool
+}
+
+/// Measure of a single province, with its dates as days since the start of the simulation.
+#[derive(Debug, Clone)]
+pub struct MeasureParameters {
+    pub name: String,
+    pub trigger: Option<Trigger>,
+    pub delay_in_days: f32, // Of the trigger, the incubation period of the province if the trigger does not give one.
+    pub hospital_capacity: f32,
+    pub effect: f32,
+    pub start_time: Option<f32>,
+    pub end_time: Option<f32>,
+    pub minimum_duration_in_days: f32,
+    pub ramp_in_days: f32,
+    pub ramp_out_days: f32
+}
+
+impl MeasureParameters {
+    /// Whether the comparison of the trigger holds against `threshold`, in the unit of the trigger.
+    /// `current` is the current state of the province, summed over all age groups.
+    fn holds(&self, trigger: &Trigger, threshold: f32, current: &Compartments, history: &ProvinceHistory, time: f32) -> bool {
+        let state = if self.delay_in_days > 0.0 { history.state_at(time - self.delay_in_days) } else { *current };
+        let threshold = match trigger.relative_to {
+            ThresholdUnit::People => threshold,
+            ThresholdUnit::HospitalCapacity => threshold * self.hospital_capacity,
+            ThresholdUnit::Population => threshold * state.population
+        };
+        trigger.comparison.holds(state[trigger.compartment], threshold)
+    }
+
+    /// Fraction of the effect at `time`, ramping in after activation and out after release.
+    pub fn level(&self, state: &MeasureState, time: f32) -> f32 {
+        let elapsed = (time - state.since).max(0.0);
+        if state.active {
+            if self.ramp_in_days > 0.0 { (state.level_at_switch + elapsed / self.ramp_in_days).min(1.0) } else { 1.0 }
+        } else if self.ramp_out_days > 0.0 {
+            (state.level_at_switch - elapsed / self.ramp_out_days).max(0.0)
+        } else { 0.0 }
+    }
+
+    /// Activates or releases the measure at `time`. Returns whether it did either.
+    pub fn update(&self, state: &mut MeasureState, current: &Compartments, history: &ProvinceHistory, time: f32) -> bool {
+        // Times of steps add up rounding errors, which should not delay a date by a whole step.
+        let within_dates = !(self.start_time.is_some_and(|start| time < start - 1e-2) || self.end_time.is_some_and(|end| time >= end - 1e-2));
+        let switch = match (&self.trigger, state.active) {
+            (_, false) if !within_dates => false,
+            (None, active) => active != within_dates,
+            (Some(trigger), false) => self.holds(trigger, trigger.threshold, current, history, time),
+            (Some(trigger), true) => !within_dates || (time - state.since >= self.minimum_duration_in_days
+                && !self.holds(trigger, trigger.release_threshold.unwrap_or(trigger.threshold), current, history, time))
+        };
+        if switch {
+            *state = MeasureState { active: !state.active, since: time, level_at_switch: self.level(state, time) };
+        }
+        switch
+    }
+}
+
+/// State of every measure of a province during a simulation, with a log of their activations and releases.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProvinceMeasures {
+    pub states: Vec<MeasureState>,
+    pub events: Vec<MeasureEvent>
+}
+
+impl ProvinceMeasures {
+    /// Every measure of the province, none of them active yet.
+    pub fn new(parameters: &SimulationParameters) -> Self {
+        Self { states: vec![MeasureState::default(); parameters.measures.len()], events: vec![] }
+    }
+
+    /// Activates or releases the measures of the province at `time`, given the current value of every age group.
+    pub fn update(&mut self, parameters: &SimulationParameters, current: &[Compartments], history: &ProvinceHistory, time: f32) {
+        let total: Compartments = current.iter().copied().sum();
+        for (measure, state) in parameters.measures.iter().zip(self.states.iter_mut()) {
+            if measure.update(state, &total, history, time) {
+                self.events.push(MeasureEvent { time, measure: measure.name.clone(), activated: state.active });
+            }
+        }
+    }
+
+    /// Reduction of the infection rate by all measures at `time`, scaled by their effectiveness. Measures act on the province as a whole.
+    pub fn change(&self, parameters: &SimulationParameters, time: f32) -> f32 {
+        let change: f32 = parameters.measures.iter().zip(&self.states).map(|(measure, state)| measure.effect * measure.level(state, time)).sum();
+        (change * parameters.measure_effectiveness).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dde::{History, PreHistory};
+
+    fn infected(infected: f32) -> Compartments {
+        Compartments { infected, population: 1000.0, ..Compartments::default() }
+    }
+
+    #[test]
+    fn releases_after_the_minimum_duration_below_the_release_threshold() {
+        let trigger = Trigger {
+            compartment: Compartment::Infected,
+            comparison: ThresholdComparison::AtLeast,
+            threshold: 100.0,
+            release_threshold: Some(50.0),
+            relative_to: ThresholdUnit::People,
+            delay_in_days: Some(0.0)
+        };
+        let measure = Measure { trigger: Some(trigger), minimum_duration_in_days: 10.0, ..MeasureKind::HandWashing.measure() };
+        let parameters = measure.parameters(None, 7, 1000);
+        let history = History::new(0.0, vec![vec![infected(0.0)]], PreHistory::Constant(vec![vec![infected(0.0)]]));
+        let history = ProvinceHistory::new(&history, 0);
+
+        let mut state = MeasureState::default();
+        let mut update = |time, value| {
+            parameters.update(&mut state, &infected(value), &history, time);
+            state.active
+        };
+        assert!(!update(0.0, 99.0));
+        assert!(update(1.0, 150.0));
+        assert!(update(5.0, 20.0), "released before its minimum duration");
+        assert!(update(12.0, 70.0), "released above the release threshold");
+        assert!(!update(13.0, 40.0));
+        assert!(!update(14.0, 80.0), "activated again below the threshold");
+    }
+
+    #[test]
+    fn ramps_out_from_the_level_it_was_released_at() {
+        let measure = Measure { ramp_in_days: 4.0, ramp_out_days: 2.0, ..MeasureKind::HandWashing.measure() };
+        let parameters = measure.parameters(None, 7, 1000);
+        let active = MeasureState { active: true, since: 10.0, level_at_switch: 0.0 };
+        assert_eq!(parameters.level(&active, 12.0), 0.5);
+        assert_eq!(parameters.level(&active, 20.0), 1.0);
+
+        let released = MeasureState { active: false, since: 12.0, level_at_switch: parameters.level(&active, 12.0) };
+        assert_eq!(parameters.level(&released, 12.5), 0.25);
+        assert_eq!(parameters.level(&released, 14.0), 0.0);
+    }
+
+    #[test]
+    fn loads_predefined_and_defined_measures() {
+        let definitions: Vec<MeasureDefinition> = serde_json::from_str(r#"[
+            "soft_lock_down",
+            { "name": "curfew", "effect": 0.1, "start_date": "2021-01-23", "end_date": "2021-04-28" }
+        ]"#).unwrap();
+        assert_eq!(definitions[0].measure(), MeasureKind::SoftLockDown.measure());
+        assert_eq!(definitions[0].measure().parameters(None, 7, 1000).delay_in_days, 7.0);
+
+        // A measure without a trigger is active between its dates, as days since the start of the simulation.
+        let curfew = definitions[1].measure().parameters(NaiveDate::from_ymd_opt(2021, 1, 1), 7, 1000);
+        assert_eq!((curfew.start_time, curfew.end_time), (Some(22.0), Some(117.0)));
+        let history = History::new(0.0, vec![vec![infected(0.0)]], PreHistory::Constant(vec![vec![infected(0.0)]]));
+        let history = ProvinceHistory::new(&history, 0);
+        let mut state = MeasureState::default();
+        let switches: Vec<f32> = (0..150).map(|day| day as f32)
+            .filter(|time| curfew.update(&mut state, &infected(0.0), &history, *time))
+            .collect();
+        assert_eq!(switches, vec![22.0, 117.0]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{Centroid, OriginDestinationMatrix, ProvinceGraph};
+use serde::{Serialize, Deserialize};
+
+/// Models generating the daily flows between provinces from their population and centroid,
+/// for regions without measured origin-destination data.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum MobilityModel {
+    /// Flow from i to j is `scale * N_i^origin_exponent * N_j^destination_exponent / d_ij^distance_exponent`, with d in km.
+    Gravity {
+        #[serde(default = "default_gravity_scale")]
+        scale: f32,
+        #[serde(default = "default_population_exponent")]
+        origin_exponent: f32,
+        #[serde(default = "default_population_exponent")]
+        destination_exponent: f32,
+        #[serde(default = "default_distance_exponent")]
+        distance_exponent: f32
+    },
+    /// Radiation model of Simini et al. Travellers choose the nearest destination with better opportunities than their origin,
+    /// so flows depend on the population living closer to the origin than the destination.
+    Radiation {
+        #[serde(default = "default_travelling_fraction")]
+        travelling_fraction: f32 // Fraction of the population of a province travelling every day.
+    }
+}
+
+fn default_gravity_scale() -> f32 { 1e-5 }
+fn default_population_exponent() -> f32 { 1.0 }
+fn default_distance_exponent() -> f32 { 2.0 }
+fn default_travelling_fraction() -> f32 { 0.05 }
+
+/// Distances closer than this are treated as this distance, so provinces sharing a centroid do not get infinite flows.
+const MIN_DISTANCE_IN_KM: f32 = 1.0;
+
+impl MobilityModel {
+    /// Generates the daily flows between every pair of provinces. Every province needs a centroid.
+    pub fn flows(&self, graph: &ProvinceGraph) -> Result<OriginDestinationMatrix, String> {
+        let centroids = graph.into_iter()
+            .map(|p| p.centroid.ok_or(format!("{} has no centroid to generate mobility from", p.name)))
+            .collect::<Result<Vec<Centroid>, String>>()?;
+        let populations: Vec<f32> = graph.into_iter().map(|p| p.population as f32).collect();
+        let distances: Vec<Vec<f32>> = centroids.iter()
+            .map(|a| centroids.iter().map(|b| a.distance_in_km(b).max(MIN_DISTANCE_IN_KM)).collect())
+            .collect();
+        let count = graph.len();
+
+        let flows = match *self {
+            MobilityModel::Gravity { scale, origin_exponent, destination_exponent, distance_exponent } => (0..count).map(|i| (0..count).map(|j| {
+                if i == j { return 0.0; }
+                scale * populations[i].powf(origin_exponent) * populations[j].powf(destination_exponent) / distances[i][j].powf(distance_exponent)
+            }).collect()).collect(),
+            MobilityModel::Radiation { travelling_fraction } => {
+                let total: f32 = populations.iter().sum();
+                (0..count).map(|i| (0..count).map(|j| {
+                    if i == j { return 0.0; }
+                    let (m, n) = (populations[i], populations[j]);
+                    // Population within the circle around the origin which reaches the destination, excluding both.
+                    let s: f32 = (0..count)
+                        .filter(|k| *k != i && *k != j && distances[i][*k] < distances[i][j])
+                        .map(|k| populations[k])
+                        .sum();
+                    // Normalized for a finite region, such that all travellers of the origin arrive somewhere.
+                    travelling_fraction * m * (m * n) / ((m + s) * (m + n + s)) / (1.0 - m / total)
+                }).collect()).collect()
+            }
+        };
+
+        Ok(OriginDestinationMatrix {
+            provinces: graph.into_iter().map(|p| p.name.clone()).collect(),
+            flows
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_file, ProvinceData, ProvinceGraphBuilder};
+
+    fn graph() -> ProvinceGraph {
+        let provinces = load_file::<Vec<ProvinceData>>("dataset/provinces.json").unwrap();
+        ProvinceGraphBuilder::new(provinces).build().unwrap().0
+    }
+
+    #[test]
+    fn gravity_flows_are_symmetric_and_fall_with_distance() {
+        let graph = graph();
+        let model = MobilityModel::Gravity { scale: 1e-5, origin_exponent: 1.0, destination_exponent: 1.0, distance_exponent: 2.0 };
+        let matrix = model.flows(&graph).unwrap();
+        let idx = |name: &str| matrix.provinces.iter().position(|p| p == name).unwrap();
+        let (utrecht, gelderland, groningen) = (idx("Utrecht"), idx("Gelderland"), idx("Groningen"));
+
+        assert_eq!(matrix.flows[utrecht][utrecht], 0.0);
+        assert!((matrix.flows[utrecht][gelderland] - matrix.flows[gelderland][utrecht]).abs() <= 1e-3 * matrix.flows[utrecht][gelderland]);
+        // Gelderland is the larger and the nearer of the two.
+        assert!(matrix.flows[utrecht][gelderland] > matrix.flows[utrecht][groningen]);
+    }
+
+    #[test]
+    fn radiation_flows_send_every_traveller_somewhere() {
+        let graph = graph();
+        let matrix = MobilityModel::Radiation { travelling_fraction: 0.05 }.flows(&graph).unwrap();
+        for (province, row) in graph.into_iter().zip(&matrix.flows) {
+            let travellers: f32 = row.iter().sum();
+            let expected = 0.05 * province.population as f32;
+            assert!((travellers - expected).abs() < 1e-3 * expected, "{}: {} travellers instead of {}", province.name, travellers, expected);
+        }
+    }
+}
@@ -1,8 +1,28 @@
+pub mod compartments;
 pub mod graph;
+pub mod measures;
+pub mod mobility;
+pub mod observations;
 pub mod params;
+pub mod regions;
+pub mod results;
+pub mod scenario;
+pub mod series;
+pub mod sewage;
+pub mod vaccination;
 
+pub use compartments::*;
 pub use graph::*;
+pub use measures::*;
+pub use mobility::*;
+pub use observations::*;
 pub use params::*;
+pub use regions::*;
+pub use results::*;
+pub use scenario::*;
+pub use series::*;
+pub use sewage::*;
+pub use vaccination::*;
 
 use serde::{Serialize, Deserialize};
 
@@ -11,14 +31,46 @@ use serde::{Serialize, Deserialize};
     "name": "Groningen",
     "population": 583990,
     "density_per_square_km": 194,
-    "connected_provinces": ["Friesland", "Drenthe"]
+    "age_distribution": [0.21, 0.26, 0.25, 0.23, 0.05],
+    "centroid": { "latitude": 53.22, "longitude": 6.74 },
+    "connected_provinces": ["Friesland", { "name": "Drenthe", "daily_flow": 15000 }]
   }
 */
 
+/// Connection to another province in the dataset, optionally with the amount of people travelling there every day.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ConnectionData {
+    Name(String),
+    Weighted { name: String, daily_flow: f32 }
+}
+
+impl ConnectionData {
+    pub fn name(&self) -> &str {
+        match self {
+            ConnectionData::Name(name) => name,
+            ConnectionData::Weighted { name, .. } => name
+        }
+    }
+
+    pub fn daily_flow(&self) -> Option<f32> {
+        match self {
+            ConnectionData::Name(_) => None,
+            ConnectionData::Weighted { daily_flow, .. } => Some(*daily_flow)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProvinceData {
-    name: String,
-    population: u32,
-    density_per_square_km: u16,
-    connected_provinces: Vec<String>
+    pub name: String,
+    pub population: u32,
+    pub density_per_square_km: u16,
+    /// Fraction of the population in each age group, in the order of the age groups of the scenario.
+    #[serde(default)]
+    pub age_distribution: Vec<f32>,
+    /// Geographic center, used to generate mobility between provinces.
+    #[serde(default)]
+    pub centroid: Option<Centroid>,
+    pub connected_provinces: Vec<ConnectionData>
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+use crate::{interpolate, load_file, DailySeries, SewageData};
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+
+/*
+  { "Date": "2020-02-20", "Rt_low": 0.83, "Rt_avg": 1.74, "Rt_up": 2.83, "population": "hosp" }
+  { "Date": "2020-02-20", "prev_low": 2313, "prev_avg": 3346, "prev_up": 4370, "population": "hosp" }
+*/
+
+/// Data RIVM based an estimate on.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EstimateSource {
+    #[serde(rename = "hosp")]
+    HospitalAdmissions,
+    #[serde(rename = "testpos")]
+    PositiveTests,
+    #[serde(other)]
+    Unknown
+}
+
+/// Estimate with its uncertainty interval. RIVM sometimes only publishes the interval, without the average.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Estimate {
+    pub low: f32,
+    pub average: Option<f32>,
+    pub high: f32
+}
+
+impl Estimate {
+    /// The average, or the middle of the interval without one.
+    pub fn central(&self) -> f32 {
+        self.average.unwrap_or((self.low + self.high) / 2.0)
+    }
+
+    /// Whether a value lies within the uncertainty interval.
+    pub fn contains(&self, value: f32) -> bool {
+        (self.low..=self.high).contains(&value)
+    }
+}
+
+/// Record of the reproduction number and prevalence datasets, which only differ in the names of the values.
+#[derive(Deserialize, Debug)]
+struct EstimateRecord {
+    #[serde(rename = "Date")]
+    date: NaiveDate,
+    #[serde(rename = "Rt_low", alias = "prev_low", default)]
+    low: Option<f32>,
+    #[serde(rename = "Rt_avg", alias = "prev_avg", default)]
+    average: Option<f32>,
+    #[serde(rename = "Rt_up", alias = "prev_up", default)]
+    high: Option<f32>,
+    #[serde(rename = "population", default)]
+    source: Option<EstimateSource>
+}
+
+/// Model value next to the observed estimate of the same day.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Comparison {
+    pub date: NaiveDate,
+    pub model: f32,
+    pub observed: Estimate
+}
+
+/// Daily estimates published by RIVM for the whole country.
+/// Days without an interval are missing, e.g. the most recent days of the reproduction number.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObservedData {
+    pub estimates: DailySeries<Estimate>,
+    pub sources: DailySeries<EstimateSource>
+}
+
+impl ObservedData {
+    fn load(path: &str) -> Result<ObservedData, String> {
+        let records = load_file::<Vec<EstimateRecord>>(path).map_err(|e| format!("Could not load observed data {}: {}", path, e))?;
+        let estimates = DailySeries::from_dated(records.iter().filter_map(|r| match (r.low, r.high) {
+            (Some(low), Some(high)) => Some((r.date, Estimate { low, average: r.average, high })),
+            _ => None
+        }));
+        let estimates = estimates.ok_or(format!("Observed data {} contains no estimates", path))?;
+        let sources = DailySeries::from_dated(records.iter().filter_map(|r| r.source.map(|s| (r.date, s))))
+            .unwrap_or(DailySeries { start: estimates.start, values: vec![] });
+        Ok(ObservedData { estimates, sources })
+    }
+
+    /// Loads the reproduction number dataset, `COVID-19_reproductiegetal.json`.
+    pub fn load_reproduction_number(path: &str) -> Result<ObservedData, String> {
+        Self::load(path)
+    }
+
+    /// Loads the prevalence dataset, `COVID-19_prevalentie.json`. Prevalence is the amount of infectious people.
+    pub fn load_prevalence(path: &str) -> Result<ObservedData, String> {
+        Self::load(path)
+    }
+
+    /// Pairs every observed day within the simulated time with the model value of that day.
+    /// `start_date` is the date of time 0, `values` are the model values at `times`, in days.
+    pub fn compare(&self, start_date: NaiveDate, times: &[f32], values: &[f32]) -> Vec<Comparison> {
+        self.estimates.dated()
+            .filter_map(|(date, observed)| {
+                let t = (date - start_date).num_days() as f32;
+                interpolate(times, values, t).map(|model| Comparison { date, model, observed })
+            })
+            .collect()
+    }
+}
+
+/// Observed data to compare a simulation of the whole country with. Every part is optional.
+#[derive(Debug, Clone, Default)]
+pub struct Observations {
+    pub prevalence: Option<ObservedData>,
+    pub reproduction_number: Option<ObservedData>,
+    pub sewage: Option<DailySeries<f32>>, // RNA per ml, interpolated between the days plants measured.
+    pub sewage_samples: Option<DailySeries<f32>>, // RNA per ml on the days plants measured only.
+    pub sewage_data: Option<SewageData> // Representative measurements of every plant, for the safety regions.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, month, day).unwrap()
+    }
+
+    #[test]
+    fn loads_the_rivm_estimates() {
+        let reproduction_number = ObservedData::load_reproduction_number("dataset/COVID-19_reproductiegetal.json").unwrap();
+        assert_eq!(reproduction_number.estimates.start, date(2, 17));
+        assert_eq!(reproduction_number.estimates.get(date(2, 17)), Some(Estimate { low: 0.5, average: Some(1.93), high: 4.0 }));
+        assert_eq!(reproduction_number.sources.get(date(2, 17)), Some(EstimateSource::HospitalAdmissions));
+
+        // Early prevalence estimates only have an interval.
+        let prevalence = ObservedData::load_prevalence("dataset/COVID-19_prevalentie.json").unwrap();
+        let first = prevalence.estimates.get(date(2, 17)).unwrap();
+        assert_eq!((first.average, first.central()), (None, 1499.5));
+        assert!(first.contains(2000.0) && !first.contains(3000.0));
+
+        assert!(ObservedData::load_prevalence("dataset/missing.json").unwrap_err().starts_with("Could not load observed data dataset/missing.json"));
+    }
+
+    #[test]
+    fn reads_unknown_sources_and_records_without_an_interval() {
+        let record: EstimateRecord = serde_json::from_str(r#"{ "Date": "2020-10-01", "Rt_avg": 1.2, "population": "sewage" }"#).unwrap();
+        assert_eq!((record.low, record.average, record.high, record.source), (None, Some(1.2), None, Some(EstimateSource::Unknown)));
+    }
+
+    #[test]
+    fn compares_observed_days_within_the_simulated_time() {
+        let estimate = |central: f32| Estimate { low: central - 1.0, average: Some(central), high: central + 1.0 };
+        let observed = ObservedData {
+            estimates: DailySeries { start: date(3, 1), values: vec![Some(estimate(10.0)), None, Some(estimate(12.0)), Some(estimate(14.0))] },
+            sources: DailySeries { start: date(3, 1), values: vec![] }
+        };
+        // The simulation starts on the 2nd and lasts two days.
+        let comparisons = observed.compare(date(3, 2), &[0.0, 1.0, 2.0], &[100.0, 200.0, 300.0]);
+        let paired: Vec<(NaiveDate, f32, f32)> = comparisons.iter().map(|c| (c.date, c.model, c.observed.central())).collect();
+        assert_eq!(paired, vec![(date(3, 3), 200.0, 12.0), (date(3, 4), 300.0, 14.0)]);
+    }
+}
@@ -1,43 +1,12 @@
+use crate::{MeasureParameters, VaccineSchedule};
 
-pub type MeasureFn = dyn Fn(&SimulationParameters, &Vec<f32>, &[Vec<f32>], f32, f32) -> f32;
-
-
-/// Hand washing measure triggers at infected > 1% of population
-pub fn hand_washing(parameters: &SimulationParameters, previous: &Vec<f32>, previous_data: &[Vec<f32>], time: f32, h: f32) -> f32 {
-
-    let delayed_population = previous_data[previous_data.len() - ((parameters.incubation_period_in_days as f32 / h) as usize)][5];
-    let delayed_infected = previous_data[previous_data.len() - ((parameters.incubation_period_in_days as f32 / h) as usize)][2];
-
-    return if delayed_infected > 1000.0 {
-        0.15
-    } else { 0.0 };
-}
-
-/// Social distancing reduces transmission by having more distance between people and limits visits etc.
-pub fn social_distancing(parameters: &SimulationParameters, previous: &Vec<f32>, previous_data: &[Vec<f32>], time: f32, h: f32) -> f32 {
-    let delayed_hospitalizations = previous_data[previous_data.len() - ((parameters.incubation_period_in_days as f32 / h) as usize)][6];
-    return if delayed_hospitalizations >= 0.1 * parameters.max_hospital_capacity as f32 {
-        return 0.2
-    } else { 0.0 }
-}
-
-/// Soft lock down is triggered based on hospital capacity. It reduces transmissions of disease quite a bit
-pub fn soft_lock_down(parameters: &SimulationParameters, previous: &Vec<f32>, previous_data: &[Vec<f32>], time: f32, h: f32) -> f32 {
-    let delayed_hospitalizations = previous_data[previous_data.len() - ((parameters.incubation_period_in_days as f32 / h) as usize)][6];
-
-    return if delayed_hospitalizations >= 0.3 * parameters.max_hospital_capacity as f32{
-        0.3
-    } else { 0.0 }
-}
-
-/// Hard lock down is triggered based on hospital capacity. It reduces transmissions of disease significantly.
-pub fn hard_lock_down(parameters: &SimulationParameters, previous: &Vec<f32>, previous_data: &[Vec<f32>], time: f32, h: f32) -> f32 {
-
-    let delayed_hospitalizations = previous_data[previous_data.len() - ((parameters.incubation_period_in_days as f32 / h) as usize)][6];
-
-    return if delayed_hospitalizations >= 0.5 * parameters.max_hospital_capacity as f32 {
-        0.15
-    } else { 0.0 }
+/// Parameters of a single age group within a province.
+#[derive(Debug, Clone)]
+pub struct AgeGroupParameters {
+    pub name: String,
+    pub population_fraction: f32,
+    pub mortality_rate: f32,
+    pub hospitalization_rate: f32
 }
 
 /// Represents all configurable parameters that were set for a particular simulation
@@ -54,6 +23,22 @@ pub struct SimulationParameters {
     pub r_naught: f32,
     pub hospitalization_rate: f32,
     pub max_hospital_capacity: usize,
+    pub measure_effectiveness: f32, // Scales the reduction of all measures together.
     pub traffic_rate: f32,
-    pub measures: Vec<Box<MeasureFn>>
+    pub age_groups: Vec<AgeGroupParameters>,
+    /// Contacts between age groups, `contact_matrix[a][b]` being the contacts of a person in group a with group b.
+    /// Normalized to a spectral radius of 1, such that R0 keeps its meaning.
+    pub contact_matrix: Vec<Vec<f32>>,
+    pub vaccine: Option<VaccineSchedule>, // No vaccine means nobody is vaccinated.
+    pub measures: Vec<MeasureParameters>
+}
+
+impl SimulationParameters {
+    /// Delays at which the measures look back into the history of the province.
+    pub fn delays(&self) -> Vec<f32> {
+        self.measures.iter()
+            .filter(|m| m.trigger.is_some() && m.delay_in_days > 0.0)
+            .map(|m| m.delay_in_days)
+            .collect()
+    }
 }
\ No newline at end of file
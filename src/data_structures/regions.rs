@@ -0,0 +1,476 @@
+use crate::{AgeGroupResults, Centroid, Compartments, ConnectionData, ProvinceData, ProvinceResults, SimulationResults};
+use serde::{Serialize, Deserialize};
+use std::convert::TryFrom;
+use std::ops::Index;
+
+/*
+  {
+    "name": "Midden- en West-Brabant", "code": "VR20", "level": "safety_region", "parent": "Noord-Brabant", "population": 1118463,
+    "centroid": { "latitude": 51.58, "longitude": 4.80 }, "connected_regions": ["VR08", "VR17", "VR18", "VR19", "VR21", "VR22"]
+  }
+*/
+
+/// Administrative levels of the Netherlands, from smallest to largest.
+/// Datasets and results are of provinces, unless stated otherwise.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionLevel {
+    Municipality,
+    SafetyRegion, // Veiligheidsregio, the level at which many measures are taken.
+    #[default]
+    Province,
+    Country
+}
+
+impl RegionLevel {
+    /// All levels, from smallest to largest.
+    pub const ALL: [RegionLevel; 4] = [RegionLevel::Municipality, RegionLevel::SafetyRegion, RegionLevel::Province, RegionLevel::Country];
+
+    /// Name used in the dataset and on the command line.
+    pub fn key(&self) -> &'static str {
+        match self {
+            RegionLevel::Municipality => "municipality",
+            RegionLevel::SafetyRegion => "safety_region",
+            RegionLevel::Province => "province",
+            RegionLevel::Country => "country"
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<RegionLevel> {
+        RegionLevel::ALL.iter().copied().find(|l| l.key() == key)
+    }
+}
+
+impl std::fmt::Display for RegionLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.key().replace('_', " "))
+    }
+}
+
+/// Region in the hierarchy dataset. The parent is the name or code of a region at a higher level.
+/// The population may be omitted for regions with sub-regions, in which case it is their sum.
+/// Density, centroid and neighbours are only needed to simulate at the level of the region.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegionData {
+    pub name: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    pub level: RegionLevel,
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub population: Option<u32>,
+    #[serde(default)]
+    pub density_per_square_km: Option<u16>,
+    #[serde(default)]
+    pub centroid: Option<Centroid>,
+    /// Neighbouring regions at the same level, by name or code.
+    #[serde(default)]
+    pub connected_regions: Vec<String>
+}
+
+/// Represents a region and its place in the hierarchy.
+#[derive(Debug)]
+pub struct Region {
+    pub name: String,
+    pub code: Option<String>,
+    pub level: RegionLevel,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub population: u32,
+    pub density_per_square_km: Option<u16>,
+    pub centroid: Option<Centroid>,
+    pub connections: Vec<usize>
+}
+
+/// Tree of regions, where every region lies within its parent at a higher level.
+/// Names are only unique within a level, as e.g. Groningen is a municipality, safety region and province.
+/// Converts results between levels, and derives a dataset at another level to simulate on.
+#[derive(Debug)]
+pub struct RegionHierarchy {
+    regions: Vec<Region>
+}
+
+impl RegionHierarchy {
+    /// Returns the amount of regions
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Returns whether the hierarchy has no regions
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Finds a region at the given level by name or code.
+    pub fn find(&self, level: RegionLevel, name: &str) -> Option<usize> {
+        self.regions.iter().position(|r| r.level == level && (r.name == name || r.code.as_deref() == Some(name)))
+    }
+
+    /// Returns all regions at the given level.
+    pub fn at_level(&self, level: RegionLevel) -> Vec<usize> {
+        (0..self.regions.len()).filter(|idx| self.regions[*idx].level == level).collect()
+    }
+
+    /// Returns the region at the given level containing a region, which is the region itself at its own level.
+    pub fn ancestor_at(&self, region: usize, level: RegionLevel) -> Option<usize> {
+        let mut current = Some(region);
+        while let Some(idx) = current {
+            if self.regions[idx].level == level {
+                return Some(idx);
+            }
+            current = self.regions[idx].parent;
+        }
+        None
+    }
+
+    /// Returns all regions at the given level within a region.
+    pub fn descendants_at(&self, region: usize, level: RegionLevel) -> Vec<usize> {
+        self.at_level(level).into_iter().filter(|idx| self.ancestor_at(*idx, self.regions[region].level) == Some(region)).collect()
+    }
+
+    /// Fraction of the values of `source` which belongs to `target`. The complete values belong to every region containing
+    /// the source, and they are divided by population over the regions within it. Regions which do not overlap get none.
+    pub fn share(&self, source: usize, target: usize) -> Option<f32> {
+        let (source_level, target_level) = (self.regions[source].level, self.regions[target].level);
+        if target_level >= source_level {
+            return if self.ancestor_at(source, target_level) == Some(target) { Some(1.0) } else { None };
+        }
+        if self.ancestor_at(target, source_level) != Some(source) {
+            return None;
+        }
+        let total: u32 = self.descendants_at(source, target_level).iter().map(|idx| self.regions[*idx].population).sum();
+        Some(self.regions[target].population as f32 / total as f32)
+    }
+
+    /// Finds the regions of a dataset or results at the given level.
+    fn find_all<'a, I: Iterator<Item = &'a String>>(&self, level: RegionLevel, names: I) -> Result<Vec<usize>, String> {
+        names.map(|name| self.find(level, name).ok_or(format!("{} is not a known {} in the region hierarchy", name, level))).collect()
+    }
+
+    /// Derives a dataset of all regions at level `to` which overlap the regions of a dataset at level `from`, to simulate on.
+    /// Populations are divided and summed like results. Regions without a density or centroid of their own get those of
+    /// the dataset regions they overlap, averaged by the population each contributes, and so do their age distributions.
+    /// Neighbours are taken from the hierarchy. When aggregating, regions without neighbours in the hierarchy are connected
+    /// to the regions containing the neighbours in the dataset. Daily flows are not converted.
+    pub fn dataset(&self, provinces: &[ProvinceData], from: RegionLevel, to: RegionLevel) -> Result<Vec<ProvinceData>, String> {
+        let sources = self.find_all(from, provinces.iter().map(|p| &p.name))?;
+        let contributions = |target: usize| -> Vec<(&ProvinceData, f32)> {
+            sources.iter().zip(provinces)
+                .filter_map(|(source, p)| self.share(*source, target).map(|share| (p, share * p.population as f32)))
+                .collect()
+        };
+        let targets: Vec<usize> = self.at_level(to).into_iter().filter(|target| !contributions(*target).is_empty()).collect();
+        if targets.is_empty() {
+            return Err(format!("Region hierarchy has no {} overlapping the dataset", to));
+        }
+
+        let dataset = targets.iter().map(|target| {
+            let region = &self.regions[*target];
+            let contributions = contributions(*target);
+            let population: f32 = contributions.iter().map(|(_, weight)| weight).sum();
+            let average = |value: &dyn Fn(&ProvinceData) -> f32| -> f32 {
+                contributions.iter().map(|(p, weight)| value(p) * weight).sum::<f32>() / population
+            };
+
+            let area_in_square_km: f32 = contributions.iter().map(|(p, weight)| weight / p.density_per_square_km as f32).sum();
+            let density_per_square_km = region.density_per_square_km.unwrap_or((population / area_in_square_km).round() as u16);
+            let first = contributions[0].0;
+            let same_groups = contributions.iter().all(|(p, _)| p.age_distribution.len() == first.age_distribution.len());
+            let age_distribution = if same_groups {
+                (0..first.age_distribution.len()).map(|group_idx| average(&|p| p.age_distribution[group_idx])).collect()
+            } else { vec![] };
+            let centroid = region.centroid.or_else(|| {
+                contributions.iter().all(|(p, _)| p.centroid.is_some()).then(|| Centroid {
+                    latitude: average(&|p| p.centroid.unwrap().latitude),
+                    longitude: average(&|p| p.centroid.unwrap().longitude)
+                })
+            });
+
+            let mut connections: Vec<usize> = region.connections.iter().copied().filter(|c| targets.contains(c)).collect();
+            if region.connections.is_empty() && to > from {
+                for (_, province) in sources.iter().zip(provinces).filter(|(source, _)| self.ancestor_at(**source, to) == Some(*target)) {
+                    for connected in &province.connected_provinces {
+                        let neighbour = self.find(from, connected.name())
+                            .and_then(|idx| self.ancestor_at(idx, to))
+                            .filter(|idx| idx != target && targets.contains(idx));
+                        if let Some(neighbour) = neighbour.filter(|n| !connections.contains(n)) {
+                            connections.push(neighbour);
+                        }
+                    }
+                }
+            }
+
+            ProvinceData {
+                name: region.name.clone(),
+                population: population.round() as u32,
+                density_per_square_km,
+                age_distribution,
+                centroid,
+                connected_provinces: connections.iter().map(|c| ConnectionData::Name(self.regions[*c].name.clone())).collect()
+            }
+        }).collect::<Vec<ProvinceData>>();
+
+        if dataset.len() > 1 && dataset.iter().all(|p| p.connected_provinces.is_empty()) {
+            return Err(format!("Region hierarchy has no neighbours of any {} to simulate on", to));
+        }
+        Ok(dataset)
+    }
+
+    /// Converts results to all regions at level `to` which overlap any of their regions.
+    /// Values are summed when aggregating and divided by population when disaggregating.
+    /// Parameters are averaged, weighted by the population each region contributes.
+    /// Measure events are kept for regions within a single source region, as measures act on a region as a whole.
+    pub fn convert(&self, results: &SimulationResults, to: RegionLevel) -> Result<SimulationResults, String> {
+        let sources = self.find_all(results.level, results.provinces.iter().map(|p| &p.name))?;
+
+        let provinces = self.at_level(to).into_iter().filter_map(|target| {
+            let contributions: Vec<(&ProvinceResults, f32, f32)> = sources.iter().zip(&results.provinces)
+                .filter_map(|(source, p)| self.share(*source, target).map(|share| (p, share, share * self.regions[*source].population as f32)))
+                .collect();
+            if contributions.is_empty() {
+                return None;
+            }
+
+            let combine = |values: &dyn Fn(&ProvinceResults) -> &Vec<Compartments>| -> Vec<Compartments> {
+                (0..results.times.len())
+                    .map(|step| contributions.iter().map(|(p, share, _)| values(p)[step] * *share).sum())
+                    .collect()
+            };
+            let total_weight: f32 = contributions.iter().map(|(_, _, weight)| weight).sum();
+            let average = |parameter: &dyn Fn(&ProvinceResults) -> f32| -> f32 {
+                contributions.iter().map(|(p, _, weight)| parameter(p) * weight).sum::<f32>() / total_weight
+            };
+
+            // Age groups are only kept when every contributing region has the same ones.
+            let first = contributions[0].0;
+            let same_groups = contributions.iter().all(|(p, _, _)| p.age_groups.iter().map(|g| &g.name).eq(first.age_groups.iter().map(|g| &g.name)));
+            let age_groups = if same_groups {
+                first.age_groups.iter().enumerate().map(|(group_idx, group)| AgeGroupResults {
+                    name: group.name.clone(),
+                    values: combine(&|p| &p.age_groups[group_idx].values)
+                }).collect()
+            } else { vec![] };
+
+            Some(ProvinceResults {
+                name: self.regions[target].name.clone(),
+                r_naught: average(&|p| p.r_naught),
+                sickness_period_in_days: average(&|p| p.sickness_period_in_days as f32).round() as usize,
+                incubation_period_in_days: average(&|p| p.incubation_period_in_days as f32).round() as usize,
+                mortality_rate: average(&|p| p.mortality_rate),
+                values: combine(&|p| &p.values),
+                age_groups,
+                measure_events: if contributions.len() == 1 { first.measure_events.clone() } else { vec![] }
+            })
+        }).collect::<Vec<ProvinceResults>>();
+        if provinces.is_empty() {
+            return Err(format!("Region hierarchy has no {} overlapping the results", to));
+        }
+
+        Ok(SimulationResults {
+            level: to,
+            time_span_in_days: results.time_span_in_days,
+            start_date: results.start_date,
+            times: results.times.clone(),
+            statistics: results.statistics,
+            provinces,
+            sewage: results.sewage
+        })
+    }
+}
+
+/// Trait for indexing into the hierarchy
+impl Index<usize> for RegionHierarchy {
+    type Output = Region;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.regions[index]
+    }
+}
+
+/// Builds the hierarchy from the regions in the dataset, in any order.
+/// A parent is looked up at the nearest level above the region having a region with that name or code.
+impl TryFrom<Vec<RegionData>> for RegionHierarchy {
+    type Error = String;
+
+    fn try_from(data: Vec<RegionData>) -> Result<Self, Self::Error> {
+        let mut regions: Vec<Region> = vec![];
+        for region in &data {
+            if region.name.is_empty() {
+                return Err(String::from("Region without a name"));
+            }
+            if regions.iter().any(|r| r.level == region.level && r.name == region.name) {
+                return Err(format!("{} occurs more than once as {}", region.name, region.level));
+            }
+            if let Some(code) = &region.code {
+                if regions.iter().any(|r| r.code.as_ref() == Some(code)) {
+                    return Err(format!("Region code {} occurs more than once", code));
+                }
+            }
+            regions.push(Region {
+                name: region.name.clone(),
+                code: region.code.clone(),
+                level: region.level,
+                parent: None,
+                children: vec![],
+                population: region.population.unwrap_or(0),
+                density_per_square_km: region.density_per_square_km,
+                centroid: region.centroid,
+                connections: vec![]
+            });
+        }
+
+        for (idx, region) in data.iter().enumerate() {
+            let parent = match &region.parent {
+                Some(parent) => parent,
+                None => continue
+            };
+            let parent_idx = RegionLevel::ALL.iter()
+                .filter(|level| **level > region.level)
+                .find_map(|level| regions.iter().position(|r| r.level == *level && (r.name == *parent || r.code.as_ref() == Some(parent))))
+                .ok_or(format!("{}: parent region {} does not exist at a higher level", region.name, parent))?;
+            regions[idx].parent = Some(parent_idx);
+            regions[parent_idx].children.push(idx);
+        }
+
+        for (idx, region) in data.iter().enumerate() {
+            for connected in &region.connected_regions {
+                let connected_idx = regions.iter().position(|r| r.level == region.level && (r.name == *connected || r.code.as_ref() == Some(connected)))
+                    .ok_or(format!("{}: connected region {} does not exist as {}", region.name, connected, region.level))?;
+                regions[idx].connections.push(connected_idx);
+            }
+        }
+
+        // Sum the populations of regions without one, from the smallest level up.
+        for level in RegionLevel::ALL.iter() {
+            for (idx, region) in data.iter().enumerate().filter(|(_, r)| r.level == *level && r.population.is_none()) {
+                if regions[idx].children.is_empty() {
+                    return Err(format!("{} has neither a population nor sub-regions", region.name));
+                }
+                regions[idx].population = regions[idx].children.iter().map(|c| regions[*c].population).sum();
+            }
+        }
+        if let Some(region) = regions.iter().find(|r| r.population == 0) {
+            return Err(format!("{} has no population", region.name));
+        }
+
+        Ok(RegionHierarchy { regions })
+    }
+}
+
+/// Trait for for loop and iterations
+impl<'a> IntoIterator for &'a RegionHierarchy {
+    type Item = &'a Region;
+    type IntoIter = std::slice::Iter<'a, Region>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.regions.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_file, AsymmetricConnections, ProvinceGraphBuilder, Scenario, Simulation};
+
+    fn region(name: &str, level: RegionLevel, parent: Option<&str>, population: Option<u32>, connected: &[&str]) -> RegionData {
+        RegionData {
+            name: String::from(name),
+            code: None,
+            level,
+            parent: parent.map(String::from),
+            population,
+            density_per_square_km: None,
+            centroid: None,
+            connected_regions: connected.iter().map(|c| String::from(*c)).collect()
+        }
+    }
+
+    fn province(name: &str, population: u32, density_per_square_km: u16, connected: &str) -> ProvinceData {
+        ProvinceData {
+            name: String::from(name),
+            population,
+            density_per_square_km,
+            age_distribution: vec![0.4, 0.6],
+            centroid: Some(Centroid { latitude: 52.0, longitude: 5.0 }),
+            connected_provinces: vec![ConnectionData::Name(String::from(connected))]
+        }
+    }
+
+    fn hierarchy(connected: bool) -> RegionHierarchy {
+        let neighbour = |name| if connected { vec![name] } else { vec![] };
+        RegionHierarchy::try_from(vec![
+            region("Land", RegionLevel::Country, None, None, &[]),
+            region("West", RegionLevel::Province, Some("Land"), None, &[]),
+            region("East", RegionLevel::Province, Some("Land"), None, &[]),
+            region("Haven", RegionLevel::Municipality, Some("West"), Some(100_000), &neighbour("Dorp")),
+            region("Dorp", RegionLevel::Municipality, Some("West"), Some(300_000), &neighbour("Haven")),
+            region("Stad", RegionLevel::Municipality, Some("East"), Some(50_000), &[])
+        ]).unwrap()
+    }
+
+    #[test]
+    fn derives_municipalities_and_the_country_from_provinces() {
+        let provinces = vec![province("West", 200_000, 400, "East"), province("East", 60_000, 100, "West")];
+
+        let municipalities = hierarchy(true).dataset(&provinces, RegionLevel::Province, RegionLevel::Municipality).unwrap();
+        let summary: Vec<(&str, u32, u16, usize)> = municipalities.iter()
+            .map(|m| (m.name.as_str(), m.population, m.density_per_square_km, m.connected_provinces.len()))
+            .collect();
+        assert_eq!(summary, vec![("Haven", 50_000, 400, 1), ("Dorp", 150_000, 400, 1), ("Stad", 60_000, 100, 0)]);
+        assert_eq!(municipalities[0].age_distribution, vec![0.4, 0.6]);
+
+        let country = hierarchy(true).dataset(&provinces, RegionLevel::Province, RegionLevel::Country).unwrap();
+        assert_eq!((country.len(), country[0].population), (1, 260_000));
+        // 200000 people on 500 km2 and 60000 on 600 km2.
+        assert_eq!(country[0].density_per_square_km, 236);
+
+        let unconnected = hierarchy(false).dataset(&provinces, RegionLevel::Province, RegionLevel::Municipality);
+        assert_eq!(unconnected.unwrap_err(), "Region hierarchy has no neighbours of any municipality to simulate on");
+    }
+
+    #[test]
+    fn derives_a_graph_of_safety_regions_from_the_provinces() {
+        let provinces: Vec<ProvinceData> = load_file("dataset/provinces.json").unwrap();
+        let hierarchy = RegionHierarchy::try_from(load_file::<Vec<RegionData>>("dataset/regions.json").unwrap()).unwrap();
+        let safety_regions = hierarchy.dataset(&provinces, RegionLevel::Province, RegionLevel::SafetyRegion).unwrap();
+
+        let total = |data: &[ProvinceData]| data.iter().map(|p| p.population as i64).sum::<i64>();
+        assert_eq!(safety_regions.len(), 25);
+        assert!((total(&safety_regions) - total(&provinces)).abs() <= 25);
+        assert!(safety_regions.iter().all(|r| r.centroid.is_some() && !r.connected_provinces.is_empty()));
+
+        // The hierarchy connects safety regions both ways, so no asymmetric connections are accepted.
+        let (graph, warnings) = ProvinceGraphBuilder::new(safety_regions)
+            .asymmetric_connections(AsymmetricConnections::Reject)
+            .level(RegionLevel::SafetyRegion)
+            .build()
+            .unwrap();
+        assert_eq!((graph.len(), warnings.len(), graph.level()), (25, 0, RegionLevel::SafetyRegion));
+    }
+
+    #[test]
+    fn converting_results_preserves_their_totals() {
+        let provinces: Vec<ProvinceData> = load_file("dataset/provinces.json").unwrap();
+        let hierarchy = RegionHierarchy::try_from(load_file::<Vec<RegionData>>("dataset/regions.json").unwrap()).unwrap();
+        let graph = ProvinceGraphBuilder::new(provinces).build().unwrap().0;
+        let results = Simulation::new(&graph, &Scenario { time_span_in_days: 20, ..Scenario::default() }).run();
+        let totals = |results: &SimulationResults| -> Vec<(f64, f64)> {
+            (0..results.times.len()).map(|step| results.provinces.iter()
+                .fold((0.0, 0.0), |(population, infected), p| (population + p.values[step].population as f64, infected + p.values[step].infected as f64)))
+                .collect()
+        };
+        let close = |a: &[(f64, f64)], b: &[(f64, f64)]| a.iter().zip(b).all(|(a, b)| (a.0 - b.0).abs() < 1e-5 * a.0 && (a.1 - b.1).abs() <= 1e-5 * a.1.max(1.0));
+
+        let safety_regions = hierarchy.convert(&results, RegionLevel::SafetyRegion).unwrap();
+        let country = hierarchy.convert(&safety_regions, RegionLevel::Country).unwrap();
+        assert_eq!((safety_regions.provinces.len(), safety_regions.level, country.provinces.len()), (25, RegionLevel::SafetyRegion, 1));
+        assert!(close(&totals(&results), &totals(&safety_regions)));
+        assert!(close(&totals(&results), &totals(&country)));
+
+        // Dividing a province and summing it again gives the province back.
+        let back = hierarchy.convert(&safety_regions, RegionLevel::Province).unwrap();
+        let utrecht = |results: &SimulationResults| results.provinces.iter().find(|p| p.name == "Utrecht").unwrap().values.last().unwrap().population;
+        assert!((utrecht(&back) - utrecht(&results)).abs() < 1.0);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{AssimilationMethod, CalibratedParameter, Compartment, Compartments, MeasureEvent, Objective, ObservationKind, RegionLevel, SewageModel};
+use crate::integrators::SolverStatistics;
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+
+/// Results of a single age group within a province.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgeGroupResults {
+    pub name: String,
+    pub values: Vec<Compartments>
+}
+
+/// Results of a single province, containing the state at every step of the simulation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvinceResults {
+    pub name: String,
+    pub r_naught: f32,
+    pub sickness_period_in_days: usize,
+    #[serde(default)]
+    pub incubation_period_in_days: usize,
+    pub mortality_rate: f32,
+    /// Summed over all age groups.
+    pub values: Vec<Compartments>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub age_groups: Vec<AgeGroupResults>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub measure_events: Vec<MeasureEvent>
+}
+
+/// Formats values as CSV, with one row per step. `times` contains the time of every step.
+pub fn to_csv(times: &[f32], values: &[Compartments]) -> String {
+    let mut csv = String::from("time");
+    for compartment in Compartment::ALL.iter() {
+        csv.push(',');
+        csv.push_str(compartment.key());
+    }
+    csv.push('\n');
+
+    for (time, step) in times.iter().zip(values) {
+        csv.push_str(&format!("{}", time));
+        for compartment in Compartment::ALL.iter() {
+            csv.push_str(&format!(",{}", step[*compartment]));
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Formats the activations and releases of measures as CSV, with one row per event.
+pub fn measure_events_to_csv(events: &[MeasureEvent]) -> String {
+    let mut csv = String::from("time,measure,event\n");
+    for event in events {
+        csv.push_str(&format!("{},{},{}\n", event.time, event.measure, if event.activated { "activated" } else { "released" }));
+    }
+    csv
+}
+
+/// Effective reproduction number at every step, derived from the exposed and infected people.
+/// New infections are the change of exposed plus those becoming infectious, and each infected person
+/// infects `new infections / infected` people per day during the sickness period.
+/// Steps with less than one infected person have no reproduction number.
+pub fn reproduction_number(times: &[f32], values: &[Compartments], incubation_period_in_days: usize, sickness_period_in_days: usize) -> Vec<Option<f32>> {
+    (0..values.len()).map(|step| {
+        if values[step].infected < 1.0 || incubation_period_in_days == 0 {
+            return None;
+        }
+        let new_infections = new_infections(times, values, step, incubation_period_in_days)?;
+        Some((new_infections * sickness_period_in_days as f32 / values[step].infected).max(0.0))
+    }).collect()
+}
+
+/// New infections per day at a step, from the change of exposed around it. None at a step without neighbours.
+fn new_infections(times: &[f32], values: &[Compartments], step: usize, incubation_period_in_days: usize) -> Option<f32> {
+    let (before, after) = (step.saturating_sub(1), (step + 1).min(values.len().saturating_sub(1)));
+    if before == after {
+        return None;
+    }
+    let change_exposed = (values[after].exposed - values[before].exposed) / (times[after] - times[before]);
+    Some(change_exposed + values[step].exposed / incubation_period_in_days as f32)
+}
+
+/// Results of a complete simulation run. Can be written to disk and loaded again for plotting or exporting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimulationResults {
+    #[serde(default)]
+    pub level: RegionLevel, // Of the regions which were simulated.
+    pub time_span_in_days: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<NaiveDate>,
+    pub times: Vec<f32>,
+    pub statistics: SolverStatistics,
+    pub provinces: Vec<ProvinceResults>,
+    #[serde(default)]
+    pub sewage: SewageModel // Of the scenario, to plot the results against sewage measurements later on.
+}
+
+impl SimulationResults {
+    /// Effective reproduction number of the whole country at every step. Every province contributes its new infections
+    /// over its own sickness period, so provinces with other incubation and sickness periods are weighed correctly.
+    pub fn national_reproduction_number(&self) -> Vec<Option<f32>> {
+        (0..self.times.len()).map(|step| {
+            let infected: f32 = self.provinces.iter().map(|p| p.values[step].infected).sum();
+            if infected < 1.0 {
+                return None;
+            }
+            let infections: Option<f32> = self.provinces.iter()
+                .filter(|p| p.incubation_period_in_days > 0)
+                .map(|p| new_infections(&self.times, &p.values, step, p.incubation_period_in_days).map(|n| n * p.sickness_period_in_days as f32))
+                .sum();
+            infections.map(|infections| (infections / infected).max(0.0))
+        }).collect()
+    }
+}
+
+/// Values of a province at a quantile of all realizations, taken separately for every compartment and time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuantileResults {
+    pub quantile: f32,
+    pub values: Vec<Compartments>
+}
+
+/// Results of a single province over all realizations of a stochastic run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvinceEnsembleResults {
+    pub name: String,
+    pub outbreak_probability: f32, // Fraction of realizations with an outbreak in this province.
+    pub quantiles: Vec<QuantileResults>
+}
+
+/// Results of a stochastic run, summarizing all realizations.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnsembleResults {
+    pub realizations: usize,
+    pub seed: u64,
+    pub outbreak_probability: f32, // Fraction of realizations with an outbreak in the whole country.
+    pub times: Vec<f32>,
+    pub provinces: Vec<ProvinceEnsembleResults>
+}
+
+/// Fitted value of a calibrated parameter.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct FittedValue {
+    pub parameter: CalibratedParameter,
+    pub value: f32
+}
+
+/// Outcome of a calibration, with statistics of the fit of simulated infected people to the observed prevalence.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CalibrationResults {
+    pub parameters: Vec<FittedValue>,
+    pub start_date: NaiveDate, // Start date of the fitted scenario, including the fitted seed offset.
+    pub objective: Objective,
+    pub objective_value: f32,
+    pub observations: usize, // Observed days used in the fit.
+    pub rmse: f32, // Root mean squared error in people.
+    pub r_squared: f32,
+    pub interval_coverage: f32, // Fraction of observed days where the model lies within the uncertainty interval.
+    #[serde(default)]
+    pub sewage_observations: usize, // Sewage measurements used in the fit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sewage_log_rmse: Option<f32>, // Root mean squared error of the logarithm of RNA per ml plus one.
+    pub evaluations: usize,
+    pub converged: bool
+}
+
+/// Value of a parameter at a quantile of its posterior.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct ParameterQuantile {
+    pub quantile: f32,
+    pub value: f32
+}
+
+/// Summary of the posterior of a single parameter over all chains, with convergence diagnostics.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PosteriorSummary {
+    pub parameter: CalibratedParameter,
+    pub mean: f32,
+    pub standard_deviation: f32,
+    pub quantiles: Vec<ParameterQuantile>,
+    pub r_hat: f32, // Split potential scale reduction. Close to 1 when the chains agree.
+    pub effective_sample_size: f32
+}
+
+/// National values at a quantile of the posterior-predictive distribution, for every day from the start date.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PredictiveBand {
+    pub quantile: f32,
+    pub infected: Vec<f32>,
+    pub reproduction_number: Vec<Option<f32>> // Missing while less than one person is infected in every draw.
+}
+
+/// Outcome of a Bayesian inference run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InferenceResults {
+    pub chains: usize,
+    pub samples_per_chain: usize,
+    pub seed: u64,
+    pub acceptance_rates: Vec<f32>, // Fraction of accepted proposals after the burn-in, per chain.
+    pub parameters: Vec<PosteriorSummary>,
+    pub start_date: NaiveDate, // Date of the first day of the predictive bands.
+    pub predictive: Vec<PredictiveBand>
+}
+
+/// Values of an observed quantity at a quantile of the ensemble, for every stored day.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObservableQuantile {
+    pub quantile: f32,
+    pub values: Vec<f32>
+}
+
+/// Ensemble of an observed quantity, as the observation operator computes it from the model states.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObservableResults {
+    pub observation: ObservationKind,
+    pub quantiles: Vec<ObservableQuantile>
+}
+
+/// Update of the ensemble with a single observation.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct AssimilationUpdate {
+    pub date: NaiveDate,
+    pub observation: ObservationKind,
+    pub observed: f32,
+    pub forecast: f32, // Ensemble mean of the observed quantity before the update.
+    pub analysis: f32, // Ensemble mean of the observed quantity after the update.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_sample_size: Option<f32> // Of the particle weights before resampling. Only for the particle filter.
+}
+
+/// Quantiles of the ensemble in a single province.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvinceAssimilationResults {
+    pub name: String,
+    pub quantiles: Vec<QuantileResults>
+}
+
+/// Outcome of data assimilation: the ensemble after the updates of every day, and the updates themselves.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssimilationResults {
+    pub method: AssimilationMethod,
+    pub members: usize,
+    pub seed: u64,
+    pub start_date: NaiveDate, // Date of time 0.
+    pub times: Vec<f32>, // Days since the start date. A resumed run only contains the days after it resumed.
+    pub national: Vec<QuantileResults>,
+    pub provinces: Vec<ProvinceAssimilationResults>,
+    pub observables: Vec<ObservableResults>,
+    pub updates: Vec<AssimilationUpdate>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Province with growing exposed people and a constant amount of infected people.
+    fn province(name: &str, incubation_period_in_days: usize, sickness_period_in_days: usize, exposed: f32, infected: f32) -> ProvinceResults {
+        ProvinceResults {
+            name: String::from(name),
+            r_naught: 2.0,
+            sickness_period_in_days,
+            incubation_period_in_days,
+            mortality_rate: 0.01,
+            values: (0..3).map(|step| Compartments { exposed: exposed * (1.0 + step as f32), infected, ..Compartments::default() }).collect(),
+            age_groups: vec![],
+            measure_events: vec![]
+        }
+    }
+
+    #[test]
+    fn national_reproduction_number_uses_the_periods_of_every_province() {
+        let times = vec![0.0, 1.0, 2.0];
+        let provinces = vec![province("short", 4, 5, 100.0, 1000.0), province("long", 2, 10, 50.0, 500.0)];
+        let results = SimulationResults { level: RegionLevel::Province, time_span_in_days: 2, start_date: None, times: times.clone(), statistics: SolverStatistics::default(), provinces, sewage: SewageModel::default() };
+
+        // At step 1, the first province gets 100 + 200 / 4 and the second 50 + 100 / 2 new infections per day.
+        let expected = ((100.0 + 200.0 / 4.0) * 5.0 + (50.0 + 100.0 / 2.0) * 10.0) / 1500.0;
+        let national = results.national_reproduction_number();
+        assert!((national[1].unwrap() - expected).abs() < 1e-4, "{:?}", national);
+
+        // A single province gets its own reproduction number.
+        let single = SimulationResults { provinces: vec![results.provinces[1].clone()], ..results };
+        assert_eq!(single.national_reproduction_number(), reproduction_number(&times, &single.provinces[0].values, 2, 10));
+    }
+}
@@ -0,0 +1,735 @@
+use crate::params::*;
+use crate::integrators::IntegratorKind;
+use crate::{MeasureDefinition, MobilityModel, OriginDestinationMatrix, Province, ProvinceGraph, RegionLevel, SewageModel, ThresholdComparison, VaccinationPlan, load_file, spectral_radius};
+use chrono::NaiveDate;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+/// Models of traffic between provinces.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficModel {
+    /// Travelling exposed people turn susceptible people of connected provinces into exposed after every step.
+    #[default]
+    Seeding,
+    /// People of every compartment travel along the connections in both directions, as part of the differential equations.
+    /// Conserves the total population.
+    Migration
+}
+
+/// Province specific values which take precedence over the scenario wide values.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ProvinceOverrides {
+    pub initial_population: Option<usize>,
+    pub initial_spreaders: Option<usize>,
+    pub natural_birth_rate: Option<f32>,
+    pub natural_death_rate: Option<f32>,
+    pub sickness_period_in_days: Option<usize>,
+    pub incubation_period_in_days: Option<usize>,
+    pub immunity_waning_period_in_days: Option<usize>,
+    pub mortality_rate: Option<f32>,
+    pub r_naught: Option<f32>,
+    pub hospitalization_rate: Option<f32>,
+    pub max_hospital_capacity: Option<usize>,
+    pub traffic_rate: Option<f32>,
+    pub measures: Option<Vec<MeasureDefinition>>
+}
+
+/// Age group of the population. Rates which are not given fall back to those of the province.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgeGroup {
+    pub name: String,
+    #[serde(default)]
+    pub mortality_rate: Option<f32>,
+    #[serde(default)]
+    pub hospitalization_rate: Option<f32>
+}
+
+/// Settings of the stochastic engine, which runs many realizations of a scenario using tau-leaping.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StochasticSettings {
+    pub realizations: usize,
+    pub seed: u64, // Realizations are reproducible for the same seed, regardless of the number of threads.
+    pub step_size: f32, // Fixed leap of the tau-leaping method.
+    pub outbreak_threshold: f32, // Cumulative infections after which a realization counts as an outbreak.
+    pub quantiles: Vec<f32>
+}
+
+impl Default for StochasticSettings {
+    fn default() -> Self {
+        Self {
+            realizations: 100,
+            seed: 0,
+            step_size: 0.1,
+            outbreak_threshold: 100.0,
+            quantiles: vec![0.05, 0.25, 0.5, 0.75, 0.95]
+        }
+    }
+}
+
+/// Scenario values which can be fitted to observed data.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibratedParameter {
+    RNaught,
+    SeedOffsetInDays, // Days the seed province is infected before the start date.
+    InitialSpreaders, // Rounded to whole people.
+    MeasureEffectiveness
+}
+
+/// Range within which a parameter is fitted.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct ParameterBounds {
+    pub parameter: CalibratedParameter,
+    pub lower: f32,
+    pub upper: f32
+}
+
+/// Measure of the difference between simulated infected people and the observed prevalence.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+    /// Sum of squared differences of the logarithms, such that growth and decline count as much as the peak.
+    #[default]
+    LogLeastSquares,
+    /// Negative log-likelihood of a normal distribution around the model, with the standard deviation following from the 95% interval.
+    GaussianLikelihood
+}
+
+/// Settings of the calibration, which fits scenario values to the observed prevalence with Nelder-Mead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CalibrationSettings {
+    pub parameters: Vec<ParameterBounds>,
+    pub objective: Objective,
+    pub fit_from: Option<NaiveDate>, // Only observations from this date on are fitted.
+    pub fit_until: Option<NaiveDate>, // Only observations up to this date are fitted.
+    pub max_evaluations: usize,
+    pub tolerance: f32, // Stops when the objective values of the simplex differ less than this, relative to the best value.
+    pub sewage_weight: f32 // Weight of the fit to the national sewage measurements. Sewage is not fitted at 0.
+}
+
+impl Default for CalibrationSettings {
+    fn default() -> Self {
+        Self {
+            parameters: vec![
+                ParameterBounds { parameter: CalibratedParameter::RNaught, lower: 1.0, upper: 5.0 },
+                ParameterBounds { parameter: CalibratedParameter::SeedOffsetInDays, lower: 0.0, upper: 30.0 }
+            ],
+            objective: Objective::LogLeastSquares,
+            fit_from: None,
+            fit_until: None,
+            max_evaluations: 300,
+            tolerance: 1e-4,
+            sewage_weight: 0.0
+        }
+    }
+}
+
+/// Prior distribution of an inferred parameter.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum Prior {
+    Uniform { lower: f32, upper: f32 },
+    Normal { mean: f32, standard_deviation: f32 },
+    /// Distribution of a value whose logarithm is normally distributed with the given mean and standard deviation.
+    LogNormal { log_mean: f32, log_standard_deviation: f32 }
+}
+
+/// Parameter to infer, with its prior.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct InferredParameter {
+    pub parameter: CalibratedParameter,
+    #[serde(flatten)]
+    pub prior: Prior
+}
+
+/// Settings of the Bayesian inference, which samples the posterior of scenario values given the observed prevalence
+/// and reproduction number, using adaptive random-walk Metropolis.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InferenceSettings {
+    pub parameters: Vec<InferredParameter>,
+    pub chains: usize,
+    pub burn_in: usize, // Iterations per chain during which the proposal adapts. Not part of the posterior.
+    pub samples: usize, // Iterations per chain after the burn-in.
+    pub seed: u64, // Chain `i` uses seed `seed + i`.
+    pub fit_from: Option<NaiveDate>,
+    pub fit_until: Option<NaiveDate>,
+    pub use_reproduction_number: bool, // Whether the observed reproduction number is part of the likelihood, next to the prevalence.
+    /// Days between the observations in the likelihood. The published estimates are smoothed over several days,
+    /// such that the estimates of consecutive days are far from independent.
+    pub observation_interval_in_days: usize,
+    /// Factor on the observed standard deviations, for the error of the model itself. Widens the posterior above 1.
+    pub overdispersion: f32,
+    pub predictive_draws: usize, // Posterior samples simulated for the posterior-predictive bands.
+    pub quantiles: Vec<f32>
+}
+
+impl Default for InferenceSettings {
+    fn default() -> Self {
+        Self {
+            parameters: vec![
+                InferredParameter { parameter: CalibratedParameter::RNaught, prior: Prior::Uniform { lower: 1.0, upper: 5.0 } },
+                InferredParameter { parameter: CalibratedParameter::SeedOffsetInDays, prior: Prior::Uniform { lower: 0.0, upper: 30.0 } }
+            ],
+            chains: 4,
+            burn_in: 250,
+            samples: 500,
+            seed: 0,
+            fit_from: None,
+            fit_until: None,
+            use_reproduction_number: true,
+            observation_interval_in_days: 7,
+            overdispersion: 1.0,
+            predictive_draws: 100,
+            quantiles: vec![0.05, 0.25, 0.5, 0.75, 0.95]
+        }
+    }
+}
+
+/// Method used to update an ensemble of model states with observations.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AssimilationMethod {
+    /// Weighs members by the likelihood of the observations, then resamples them by their weights.
+    ParticleFilter,
+    /// Moves members towards perturbed observations, by the covariance of their state with the observed value.
+    #[default]
+    EnsembleKalmanFilter
+}
+
+/// Observed quantity of the whole country which data assimilation updates the ensemble with.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ObservationKind {
+    Prevalence,
+    Sewage
+}
+
+/// Settings of data assimilation, which keeps an ensemble of model states and updates it at every observed date.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AssimilationSettings {
+    pub method: AssimilationMethod,
+    pub members: usize,
+    pub seed: u64, // The noise of day `d` uses seed `seed + d`, such that resumed runs draw the same noise.
+    pub observations: Vec<ObservationKind>,
+    pub process_noise: f32, // Standard deviation of the daily relative change of exposed and infected people, per member and province.
+    /// Standard deviation of the daily relative change of the infection rate, per member. Lets the observations correct
+    /// the infection rate of the scenario, rather than only the state which the model would grow back.
+    pub transmission_noise: f32,
+    pub assimilate_until: Option<NaiveDate>, // Later observations are not used, the ensemble forecasts the rest of the time span.
+    pub quantiles: Vec<f32>
+}
+
+impl Default for AssimilationSettings {
+    fn default() -> Self {
+        Self {
+            method: AssimilationMethod::EnsembleKalmanFilter,
+            members: 50,
+            seed: 0,
+            observations: vec![ObservationKind::Prevalence, ObservationKind::Sewage],
+            process_noise: 0.1,
+            transmission_noise: 0.05,
+            assimilate_until: None,
+            quantiles: vec![0.05, 0.25, 0.5, 0.75, 0.95]
+        }
+    }
+}
+
+/// Settings of the agent-based engine. Individuals live in households, and work or go to school within their province.
+/// Transmission probabilities are per day, per infectious contact.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AgentSettings {
+    pub agents_per_person: f32, // Agents are scaled back to the population in the results.
+    pub seed: u64,
+    pub mean_household_size: f32,
+    pub mean_workplace_size: f32,
+    pub mean_school_size: f32,
+    pub employment_rate: f32, // Fraction of working age people who go to a workplace.
+    pub working_age_groups: Vec<String>, // Without age groups, the single group is called "all".
+    pub school_age_groups: Vec<String>,
+    pub household_transmission: f32,
+    pub workplace_transmission: f32,
+    pub school_transmission: f32,
+    pub community_contacts: f32, // Daily random contacts within the province, divided over age groups by the contact matrix.
+    pub community_transmission: f32,
+    pub infectiousness_dispersion: f32 // Shape of the gamma distributed infectiousness. Lower values mean more super-spreading.
+}
+
+impl Default for AgentSettings {
+    fn default() -> Self {
+        Self {
+            agents_per_person: 0.01,
+            seed: 0,
+            mean_household_size: 2.2,
+            mean_workplace_size: 10.0,
+            mean_school_size: 25.0,
+            employment_rate: 0.7,
+            working_age_groups: vec![String::from("20-39"), String::from("40-59"), String::from("all")],
+            school_age_groups: vec![String::from("0-19")],
+            household_transmission: 0.08,
+            workplace_transmission: 0.01,
+            school_transmission: 0.01,
+            community_contacts: 8.0,
+            community_transmission: 0.02,
+            infectiousness_dispersion: 0.4
+        }
+    }
+}
+
+/// Engines which can simulate a scenario.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EngineKind {
+    /// Compartments integrated as a system of differential equations.
+    #[default]
+    Compartmental,
+    /// Individuals with daily contacts in households, workplaces, schools and the community.
+    AgentBased(AgentSettings)
+}
+
+/// Describes a complete simulation run. Loaded from a JSON file, missing fields fall back to the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Scenario {
+    pub time_span_in_days: usize,
+    pub start_date: Option<NaiveDate>, // Calendar date of day 0, to compare results with observed data.
+    pub level: Option<RegionLevel>, // Regions to simulate, derived from the dataset with the region hierarchy. The dataset itself if not given.
+    pub step_size: f32, // Fixed step size, or initial step size for adaptive integrators.
+    pub engine: EngineKind,
+    pub integrator: IntegratorKind,
+    pub output_interval_in_days: f32, // Interval at which results are stored, independent of the step size.
+    pub seed_province: String,
+    pub initial_spreaders: usize,
+    pub natural_birth_rate: f32,
+    pub natural_death_rate: f32,
+    pub sickness_period_in_days: usize, // Time it takes for infected people to recover or die.
+    pub incubation_period_in_days: usize, // Time it takes for exposed people to become sick + infectious.
+    pub immunity_waning_period_in_days: usize,
+    pub mortality_rate: f32, // Percentage of infected people who die.
+    pub r_naught: f32, // R0 = beta / gamma, before adjusting for population density.
+    pub adjust_r_naught_to_density: bool,
+    pub hospitalization_rate: f32, // Amount of recovering people ending up in hospital, thus counting towards max hospital cap.
+    pub max_hospital_capacity: usize, // Absolute amount of hospital capacity
+    pub measure_effectiveness: f32, // Scales the reduction of the infection rate by all measures.
+    pub enable_traffic: bool,
+    pub traffic_rate: f32, // Percentage of E which travels to other places. With migration, percentage of everyone, unless connections have flows.
+    pub traffic_model: TrafficModel,
+    pub origin_destination: Option<OriginDestinationMatrix>, // Daily flows between provinces, replacing the connections of the dataset.
+    pub origin_destination_file: Option<String>, // JSON file to load the origin-destination matrix from, instead of giving it inline.
+    pub mobility_model: Option<MobilityModel>, // Generates the flows between provinces, when no origin-destination matrix is given.
+    pub age_groups: Vec<AgeGroup>, // No age groups means a single homogeneous population.
+    pub contact_matrix: Vec<Vec<f32>>, // Daily contacts between age groups. Uniform mixing if empty.
+    pub contact_matrix_file: Option<String>, // JSON file to load the contact matrix from, instead of giving it inline.
+    pub vaccination: Option<VaccinationPlan>,
+    pub stochastic: Option<StochasticSettings>, // Runs a stochastic ensemble next to the deterministic run.
+    pub calibration: Option<CalibrationSettings>, // Used by the calibrate command.
+    pub inference: Option<InferenceSettings>, // Used by the infer command.
+    pub assimilation: Option<AssimilationSettings>, // Used by the assimilate command.
+    pub sewage: SewageModel, // Relates simulated infections to measured RNA in sewage.
+    pub measures: Vec<MeasureDefinition>, // Predefined measures by name, or complete definitions.
+    pub province_overrides: HashMap<String, ProvinceOverrides>
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            time_span_in_days: 365,
+            start_date: None,
+            level: None,
+            step_size: 0.1,
+            engine: EngineKind::Compartmental,
+            integrator: IntegratorKind::Rk4,
+            output_interval_in_days: 1.0,
+            seed_province: String::from("Noord-Brabant"),
+            initial_spreaders: 1,
+            natural_birth_rate: 0.011 / 365.0,
+            natural_death_rate: 0.005 / 365.0,
+            sickness_period_in_days: 7,
+            incubation_period_in_days: 7,
+            immunity_waning_period_in_days: 30 * 4, // Immunity wanes after 4 months
+            mortality_rate: 0.03,
+            r_naught: 2.5,
+            adjust_r_naught_to_density: true,
+            hospitalization_rate: 0.1,
+            max_hospital_capacity: 1250,
+            measure_effectiveness: 1.0,
+            enable_traffic: true,
+            traffic_rate: 0.05,
+            traffic_model: TrafficModel::Seeding,
+            origin_destination: None,
+            origin_destination_file: None,
+            mobility_model: None,
+            age_groups: vec![],
+            contact_matrix: vec![],
+            contact_matrix_file: None,
+            vaccination: None,
+            stochastic: None,
+            calibration: None,
+            inference: None,
+            assimilation: None,
+            sewage: SewageModel::default(),
+            measures: vec![],
+            province_overrides: HashMap::new()
+        }
+    }
+}
+
+/// Ranges of rates which are a fraction of the people in a compartment, and of rates which only can not be negative.
+const FRACTION: (f32, f32) = (0.0, 1.0);
+const NON_NEGATIVE: (f32, f32) = (0.0, f32::INFINITY);
+
+/// Checks that every given rate is a finite number within its range. Rates which are not given are skipped.
+/// `scope` follows the name of the rate in the error, e.g. " of Utrecht".
+fn check_rates(scope: &str, rates: &[(&str, Option<f32>, (f32, f32))]) -> Result<(), String> {
+    for (name, value, (lower, upper)) in rates {
+        if let Some(value) = value {
+            if !value.is_finite() || *value < *lower || *value > *upper {
+                return Err(if upper.is_finite() {
+                    format!("{}{} needs to be between {} and {}, not {}", name, scope, lower, upper, value)
+                } else {
+                    format!("{}{} needs to be a finite number of at least {}, not {}", name, scope, lower, value)
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Scenario {
+    /// Loads a scenario file, including the contact matrix and origin-destination files it refers to.
+    pub fn load(path: &str) -> Result<Scenario, String> {
+        let mut scenario = load_file::<Scenario>(path).map_err(|e| format!("Could not load scenario {}: {}", path, e))?;
+        if let Some(matrix_path) = &scenario.contact_matrix_file {
+            scenario.contact_matrix = load_file::<Vec<Vec<f32>>>(matrix_path).map_err(|e| format!("Could not load contact matrix {}: {}", matrix_path, e))?;
+        }
+        if let Some(matrix_path) = &scenario.origin_destination_file {
+            scenario.origin_destination = Some(load_file::<OriginDestinationMatrix>(matrix_path).map_err(|e| format!("Could not load origin-destination matrix {}: {}", matrix_path, e))?);
+        }
+        Ok(scenario)
+    }
+
+    /// Checks whether the scenario can be simulated on the graph.
+    pub fn validate(&self, graph: &ProvinceGraph) -> Result<(), String> {
+        if self.time_span_in_days == 0 || self.step_size.is_nan() || self.step_size <= 0.0 || self.output_interval_in_days.is_nan() || self.output_interval_in_days <= 0.0 {
+            return Err(String::from("Time span, step size and output interval need to be positive"));
+        }
+        if self.sickness_period_in_days == 0 || self.incubation_period_in_days == 0 || self.immunity_waning_period_in_days == 0 {
+            return Err(String::from("Sickness, incubation and immunity waning periods need to be positive"));
+        }
+        check_rates("", &[
+            ("Natural birth rate", Some(self.natural_birth_rate), FRACTION),
+            ("Natural death rate", Some(self.natural_death_rate), FRACTION),
+            ("Mortality rate", Some(self.mortality_rate), FRACTION),
+            ("R0", Some(self.r_naught), NON_NEGATIVE),
+            ("Hospitalization rate", Some(self.hospitalization_rate), FRACTION),
+            ("Measure effectiveness", Some(self.measure_effectiveness), FRACTION),
+            ("Traffic rate", Some(self.traffic_rate), FRACTION)
+        ])?;
+        for group in &self.age_groups {
+            check_rates(&format!(" of age group {}", group.name), &[
+                ("Mortality rate", group.mortality_rate, FRACTION),
+                ("Hospitalization rate", group.hospitalization_rate, FRACTION)
+            ])?;
+        }
+        for (name, overrides) in &self.province_overrides {
+            if !graph.into_iter().any(|p| &p.name == name) {
+                return Err(format!("Overrides are given for {}, which is not a province of the dataset", name));
+            }
+            let periods = [overrides.sickness_period_in_days, overrides.incubation_period_in_days, overrides.immunity_waning_period_in_days];
+            if periods.contains(&Some(0)) {
+                return Err(format!("Periods of {} need to be positive", name));
+            }
+            check_rates(&format!(" of {}", name), &[
+                ("Natural birth rate", overrides.natural_birth_rate, FRACTION),
+                ("Natural death rate", overrides.natural_death_rate, FRACTION),
+                ("Mortality rate", overrides.mortality_rate, FRACTION),
+                ("R0", overrides.r_naught, NON_NEGATIVE),
+                ("Hospitalization rate", overrides.hospitalization_rate, FRACTION),
+                ("Traffic rate", overrides.traffic_rate, FRACTION)
+            ])?;
+        }
+        let groups = self.age_groups.len().max(1);
+        if !self.contact_matrix.is_empty() {
+            if self.contact_matrix.len() != groups || self.contact_matrix.iter().any(|row| row.len() != groups) {
+                return Err(format!("Contact matrix needs to be {0}x{0} for {0} age group(s)", groups));
+            }
+            if self.contact_matrix.iter().flatten().any(|c| *c < 0.0) || spectral_radius(&self.contact_matrix) <= 0.0 {
+                return Err(String::from("Contact matrix needs to be non-negative with at least one contact"));
+            }
+        }
+        if self.level.is_some_and(|level| level != graph.level()) {
+            return Err(format!("Scenario is simulated at {} level, but the graph is at {} level", self.level.unwrap(), graph.level()));
+        }
+        if !graph.into_iter().any(|p| p.name == self.seed_province) {
+            return Err(format!("Seed province {} does not exist", self.seed_province));
+        }
+        if groups > 1 {
+            for province in graph {
+                if province.age_distribution.len() != groups {
+                    return Err(format!("{} has an age distribution of {} groups, expected {}", province.name, province.age_distribution.len(), groups));
+                }
+            }
+        }
+        if let Some(stochastic) = &self.stochastic {
+            if stochastic.realizations == 0 || stochastic.step_size <= 0.0 {
+                return Err(String::from("Stochastic runs need at least one realization and a positive step size"));
+            }
+            if stochastic.quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                return Err(String::from("Quantiles need to be between 0 and 1"));
+            }
+        }
+        if let EngineKind::AgentBased(agents) = &self.engine {
+            if self.vaccination.is_some() {
+                return Err(String::from("Vaccination is not supported by the agent-based engine"));
+            }
+            if self.traffic_model == TrafficModel::Migration {
+                return Err(String::from("Migration traffic is not supported by the agent-based engine"));
+            }
+            // Agents move in whole days, any output in between would repeat the state at the end of the day.
+            if self.output_interval_in_days < 1.0 || self.output_interval_in_days.fract() != 0.0 {
+                return Err(String::from("The agent-based engine needs an output interval of a whole number of days"));
+            }
+            if agents.agents_per_person <= 0.0 || agents.agents_per_person > 1.0 {
+                return Err(String::from("Agents per person needs to be larger than 0 and at most 1"));
+            }
+            let probabilities = [agents.household_transmission, agents.workplace_transmission, agents.school_transmission, agents.community_transmission];
+            if probabilities.iter().any(|p| !(0.0..1.0).contains(p)) || !(0.0..=1.0).contains(&agents.employment_rate) {
+                return Err(String::from("Transmission probabilities need to be at least 0 and below 1, employment rate between 0 and 1"));
+            }
+            if agents.infectiousness_dispersion <= 0.0 {
+                return Err(String::from("Infectiousness dispersion needs to be larger than 0"));
+            }
+        }
+        if let Some(calibration) = &self.calibration {
+            if calibration.parameters.is_empty() || calibration.max_evaluations == 0 {
+                return Err(String::from("Calibration needs at least one parameter and one evaluation"));
+            }
+            if calibration.parameters.iter().any(|b| b.lower >= b.upper || b.lower.is_nan() || b.upper.is_nan()) {
+                return Err(String::from("Calibration bounds need a lower bound below the upper bound"));
+            }
+            if calibration.parameters.iter().enumerate().any(|(idx, b)| calibration.parameters[..idx].iter().any(|other| other.parameter == b.parameter)) {
+                return Err(String::from("Calibration contains a parameter more than once"));
+            }
+            if calibration.sewage_weight.is_nan() || calibration.sewage_weight < 0.0 {
+                return Err(String::from("Sewage weight can not be negative"));
+            }
+        }
+        if let Some(inference) = &self.inference {
+            if inference.parameters.is_empty() || inference.chains == 0 || inference.samples == 0 {
+                return Err(String::from("Inference needs at least one parameter, chain and sample"));
+            }
+            let invalid_prior = |prior: &Prior| match *prior {
+                Prior::Uniform { lower, upper } => lower >= upper || lower.is_nan() || upper.is_nan(),
+                Prior::Normal { standard_deviation, .. } => standard_deviation.is_nan() || standard_deviation <= 0.0,
+                Prior::LogNormal { log_standard_deviation, .. } => log_standard_deviation.is_nan() || log_standard_deviation <= 0.0
+            };
+            if inference.parameters.iter().any(|p| invalid_prior(&p.prior)) {
+                return Err(String::from("Priors need a lower bound below the upper bound, or a positive standard deviation"));
+            }
+            if inference.parameters.iter().enumerate().any(|(idx, p)| inference.parameters[..idx].iter().any(|other| other.parameter == p.parameter)) {
+                return Err(String::from("Inference contains a parameter more than once"));
+            }
+            if inference.observation_interval_in_days == 0 || inference.overdispersion.is_nan() || inference.overdispersion <= 0.0 {
+                return Err(String::from("Inference needs a positive observation interval and overdispersion"));
+            }
+            if inference.quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                return Err(String::from("Quantiles need to be between 0 and 1"));
+            }
+        }
+        let overridden_measures = self.province_overrides.values().flat_map(|o| o.measures.iter().flatten());
+        for measure in self.measures.iter().chain(overridden_measures).map(|m| m.measure()) {
+            if !(0.0..=1.0).contains(&measure.effect) {
+                return Err(format!("Effect of measure {} needs to be between 0 and 1", measure.name));
+            }
+            if let Some(trigger) = &measure.trigger {
+                if trigger.threshold.is_nan() || trigger.delay_in_days.is_some_and(|d| d.is_nan() || d < 0.0) {
+                    return Err(format!("Trigger of measure {} needs a threshold and can not look into the future", measure.name));
+                }
+                // Releasing needs to be harder than activating, otherwise the measure switches at every step.
+                let release = trigger.release_threshold.unwrap_or(trigger.threshold);
+                let beyond = match trigger.comparison {
+                    ThresholdComparison::Above | ThresholdComparison::AtLeast => release <= trigger.threshold,
+                    ThresholdComparison::Below | ThresholdComparison::AtMost => release >= trigger.threshold
+                };
+                if !beyond {
+                    return Err(format!("Release threshold of measure {} needs to lie on the other side of its threshold", measure.name));
+                }
+            }
+            if !(measure.minimum_duration_in_days >= 0.0 && measure.ramp_in_days >= 0.0 && measure.ramp_out_days >= 0.0) {
+                return Err(format!("Minimum duration and ramps of measure {} can not be negative", measure.name));
+            }
+            if (measure.start_date.is_some() || measure.end_date.is_some()) && self.start_date.is_none() {
+                return Err(format!("Measure {} has a date, which needs a start date in the scenario", measure.name));
+            }
+            if let (Some(start), Some(end)) = (measure.start_date, measure.end_date) {
+                if start >= end {
+                    return Err(format!("Measure {} needs to start before it ends", measure.name));
+                }
+            }
+        }
+        let shedding = &self.sewage.shedding;
+        if !(shedding.mean_in_days > 0.0 && shedding.shape > 0.0 && shedding.duration_in_days > 0) {
+            return Err(String::from("Shedding profile needs a positive mean, shape and duration"));
+        }
+        if !(self.sewage.scale > 0.0 && self.sewage.noise > 0.0) {
+            return Err(String::from("Sewage scale and noise need to be positive"));
+        }
+        if let Some(assimilation) = &self.assimilation {
+            if assimilation.members < 2 || assimilation.observations.is_empty() {
+                return Err(String::from("Data assimilation needs at least two members and one kind of observation"));
+            }
+            if assimilation.process_noise.is_nan() || assimilation.process_noise < 0.0 || assimilation.transmission_noise.is_nan() || assimilation.transmission_noise < 0.0 {
+                return Err(String::from("Process and transmission noise can not be negative"));
+            }
+            if assimilation.quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                return Err(String::from("Quantiles need to be between 0 and 1"));
+            }
+        }
+        if let Some(vaccination) = &self.vaccination {
+            let province_names: Vec<&str> = graph.into_iter().map(|p| p.name.as_str()).collect();
+            vaccination.validate(&province_names, &self.age_group_names())?;
+        }
+        Ok(())
+    }
+
+    /// Names of the age groups, a single group named "all" without age groups.
+    pub fn age_group_names(&self) -> Vec<&str> {
+        if self.age_groups.is_empty() {
+            vec!["all"]
+        } else {
+            self.age_groups.iter().map(|g| g.name.as_str()).collect()
+        }
+    }
+
+    /// Builds the simulation parameters of a single province.
+    /// `mean_density` is the mean population density over all provinces, used to adjust R0.
+    pub fn parameters_for(&self, province: &Province, mean_density: f32) -> SimulationParameters {
+        let default_overrides = ProvinceOverrides::default();
+        let overrides = self.province_overrides.get(&province.name).unwrap_or(&default_overrides);
+
+        let default_spreaders = if province.name == self.seed_province { self.initial_spreaders } else { 0 };
+
+        // Compute relative change compared to mean density. Used to adjust infection rate.
+        let relative_change = if self.adjust_r_naught_to_density {
+            (province.density_per_square_km as f32 - mean_density) / mean_density
+        } else { 0.0 };
+
+        let mortality_rate = overrides.mortality_rate.unwrap_or(self.mortality_rate);
+        let incubation_period_in_days = overrides.incubation_period_in_days.unwrap_or(self.incubation_period_in_days);
+        let max_hospital_capacity = overrides.max_hospital_capacity.unwrap_or(self.max_hospital_capacity);
+        let hospitalization_rate = overrides.hospitalization_rate.unwrap_or(self.hospitalization_rate);
+
+        // Without age groups the province is a single group containing everyone.
+        let age_groups = if self.age_groups.is_empty() {
+            vec![AgeGroupParameters { name: String::from("all"), population_fraction: 1.0, mortality_rate, hospitalization_rate }]
+        } else {
+            let uniform = vec![1.0; self.age_groups.len()];
+            let distribution = if province.age_distribution.len() == self.age_groups.len() { &province.age_distribution } else { &uniform };
+            let total: f32 = distribution.iter().sum();
+            self.age_groups.iter().zip(distribution).map(|(group, fraction)| AgeGroupParameters {
+                name: group.name.clone(),
+                population_fraction: fraction / total,
+                mortality_rate: group.mortality_rate.unwrap_or(mortality_rate),
+                hospitalization_rate: group.hospitalization_rate.unwrap_or(hospitalization_rate)
+            }).collect()
+        };
+
+        let mut contact_matrix = if self.contact_matrix.is_empty() {
+            vec![vec![1.0; age_groups.len()]; age_groups.len()]
+        } else {
+            self.contact_matrix.clone()
+        };
+        let radius = spectral_radius(&contact_matrix);
+        for c in contact_matrix.iter_mut().flatten() {
+            *c /= radius;
+        }
+
+        SimulationParameters {
+            time_span_in_days: self.time_span_in_days,
+            initial_population: overrides.initial_population.unwrap_or(province.population as usize),
+            initial_spreaders: overrides.initial_spreaders.unwrap_or(default_spreaders),
+            natural_birth_rate: overrides.natural_birth_rate.unwrap_or(self.natural_birth_rate),
+            natural_death_rate: overrides.natural_death_rate.unwrap_or(self.natural_death_rate),
+            sickness_period_in_days: overrides.sickness_period_in_days.unwrap_or(self.sickness_period_in_days),
+            incubation_period_in_days,
+            immunity_waning_period_in_days: overrides.immunity_waning_period_in_days.unwrap_or(self.immunity_waning_period_in_days),
+            mortality_rate,
+            r_naught: overrides.r_naught.unwrap_or(self.r_naught) * (1.0 + relative_change),
+            hospitalization_rate,
+            max_hospital_capacity,
+            measure_effectiveness: self.measure_effectiveness,
+            traffic_rate: overrides.traffic_rate.unwrap_or(self.traffic_rate),
+            age_groups,
+            contact_matrix,
+            vaccine: self.vaccination.as_ref().map(|v| v.schedule.clone()),
+            measures: overrides.measures.as_ref().unwrap_or(&self.measures).iter()
+                .map(|m| m.measure().parameters(self.start_date, incubation_period_in_days, max_hospital_capacity))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_file, ProvinceData, ProvinceGraphBuilder};
+
+    fn graph() -> ProvinceGraph {
+        let provinces = load_file::<Vec<ProvinceData>>("dataset/provinces.json").unwrap();
+        ProvinceGraphBuilder::new(provinces).build().unwrap().0
+    }
+
+    #[test]
+    fn rejects_rates_outside_their_range() {
+        let graph = graph();
+        assert!(Scenario::default().validate(&graph).is_ok());
+        for scenario in [
+            Scenario { r_naught: f32::NAN, ..Scenario::default() },
+            Scenario { r_naught: f32::INFINITY, ..Scenario::default() },
+            Scenario { mortality_rate: -0.1, ..Scenario::default() },
+            Scenario { measure_effectiveness: 1.5, ..Scenario::default() },
+            Scenario { natural_death_rate: f32::NAN, ..Scenario::default() },
+            Scenario { age_groups: vec![AgeGroup { name: String::from("all"), mortality_rate: Some(2.0), hospitalization_rate: None }], ..Scenario::default() }
+        ] {
+            assert!(scenario.validate(&graph).is_err(), "{:?}", scenario);
+        }
+    }
+
+    #[test]
+    fn rejects_overridden_rates_outside_their_range() {
+        let graph = graph();
+        for overrides in [
+            ProvinceOverrides { traffic_rate: Some(f32::NAN), ..ProvinceOverrides::default() },
+            ProvinceOverrides { hospitalization_rate: Some(-1.0), ..ProvinceOverrides::default() },
+            ProvinceOverrides { natural_birth_rate: Some(f32::NEG_INFINITY), ..ProvinceOverrides::default() }
+        ] {
+            let scenario = Scenario { province_overrides: HashMap::from([(String::from("Utrecht"), overrides)]), ..Scenario::default() };
+            let error = scenario.validate(&graph).unwrap_err();
+            assert!(error.contains("of Utrecht"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn agent_based_engine_needs_whole_day_outputs() {
+        let graph = graph();
+        let agent_based = |output_interval_in_days| Scenario { engine: EngineKind::AgentBased(AgentSettings::default()), output_interval_in_days, ..Scenario::default() };
+        assert!(agent_based(1.0).validate(&graph).is_ok());
+        assert!(agent_based(7.0).validate(&graph).is_ok());
+        assert!(agent_based(0.5).validate(&graph).is_err());
+        assert!(agent_based(1.5).validate(&graph).is_err());
+    }
+
+    #[test]
+    fn needs_a_graph_at_its_level() {
+        let graph = graph();
+        assert!(Scenario { level: Some(RegionLevel::Province), ..Scenario::default() }.validate(&graph).is_ok());
+        let error = Scenario { level: Some(RegionLevel::SafetyRegion), ..Scenario::default() }.validate(&graph).unwrap_err();
+        assert_eq!(error, "Scenario is simulated at safety region level, but the graph is at province level");
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::{Duration, NaiveDate};
+use serde::{Serialize, Deserialize};
+
+/// Values for consecutive days, starting at `start`. Days without a value are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DailySeries<T> {
+    pub start: NaiveDate,
+    pub values: Vec<Option<T>>
+}
+
+impl<T: Copy> DailySeries<T> {
+    /// Builds a series from dated values in any order. A later value for the same date replaces an earlier one.
+    /// Returns `None` without any values.
+    pub fn from_dated<I: IntoIterator<Item = (NaiveDate, T)>>(dated: I) -> Option<Self> {
+        let dated: Vec<(NaiveDate, T)> = dated.into_iter().collect();
+        let start = dated.iter().map(|(date, _)| *date).min()?;
+        let end = dated.iter().map(|(date, _)| *date).max()?;
+        let mut values = vec![None; (end - start).num_days() as usize + 1];
+        for (date, value) in dated {
+            values[(date - start).num_days() as usize] = Some(value);
+        }
+        Some(DailySeries { start, values })
+    }
+
+    /// Returns the amount of days covered
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether the series covers no days
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Last day covered by the series.
+    pub fn end(&self) -> NaiveDate {
+        self.start + Duration::days(self.values.len() as i64 - 1)
+    }
+
+    /// Value at a date, if the series has one.
+    pub fn get(&self, date: NaiveDate) -> Option<T> {
+        let day = (date - self.start).num_days();
+        if day < 0 { return None; }
+        self.values.get(day as usize).copied().flatten()
+    }
+
+    /// Days which have a value, together with that value.
+    pub fn dated(&self) -> impl Iterator<Item = (NaiveDate, T)> + '_ {
+        self.values.iter().enumerate().filter_map(move |(day, v)| v.map(|v| (self.start + Duration::days(day as i64), v)))
+    }
+
+    /// Applies `f` to every value.
+    pub fn map<U, F: Fn(T) -> U>(&self, f: F) -> DailySeries<U> {
+        DailySeries { start: self.start, values: self.values.iter().map(|v| v.map(&f)).collect() }
+    }
+}
+
+impl DailySeries<f32> {
+    /// Fills missing days by interpolating linearly between the surrounding values.
+    pub fn interpolated(&self) -> DailySeries<f32> {
+        let mut values = self.values.clone();
+        let known: Vec<usize> = (0..values.len()).filter(|day| values[*day].is_some()).collect();
+        for pair in known.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let (a, b) = (values[from].unwrap(), values[to].unwrap());
+            for (offset, value) in values[from + 1..to].iter_mut().enumerate() {
+                *value = Some(a + (b - a) * (offset + 1) as f32 / (to - from) as f32);
+            }
+        }
+        DailySeries { start: self.start, values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 9, day).unwrap()
+    }
+
+    #[test]
+    fn builds_a_series_from_dated_values_in_any_order() {
+        let series = DailySeries::from_dated(vec![(date(5), 3.0), (date(2), 1.0), (date(5), 4.0)]).unwrap();
+        assert_eq!((series.start, series.end(), series.len()), (date(2), date(5), 4));
+        assert_eq!(series.values, vec![Some(1.0), None, None, Some(4.0)]);
+        assert_eq!((series.get(date(1)), series.get(date(3)), series.get(date(6))), (None, None, None));
+        assert!(DailySeries::<f32>::from_dated(vec![]).is_none());
+    }
+
+    #[test]
+    fn interpolates_missing_days_between_known_values() {
+        let series = DailySeries { start: date(1), values: vec![None, Some(1.0), None, None, Some(4.0), None] };
+        assert_eq!(series.interpolated().values, vec![None, Some(1.0), Some(2.0), Some(3.0), Some(4.0), None]);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::{interpolate, load_file, Compartments, DailySeries, SimulationResults};
+use chrono::{Duration, NaiveDate};
+use serde::{Serialize, Deserialize, Deserializer};
+use std::collections::HashMap;
+
+/*
+  {
+    "Date_measurement": "2020-04-01",
+    "RWZI_AWZI_code": 9016,
+    "RWZI_AWZI_name": "Arnhem",
+    "X_coordinate": 187878,
+    "Y_coordinate": 442241,
+    "Postal_code": "6841HJ",
+    "Security_region_code": "VR07",
+    "Security_region_name": "Gelderland-Midden",
+    "Percentage_in_security_region": "0,864490095",
+    "RNA_per_ml": 984,
+    "Representative_measurement": true
+  }
+*/
+
+/// Parses a fraction written with a decimal comma, as RIVM does. Plain numbers are accepted as well.
+fn decimal_comma<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Fraction { Number(f32), Text(String) }
+
+    match Fraction::deserialize(deserializer)? {
+        Fraction::Number(n) => Ok(n),
+        Fraction::Text(s) => s.replace(',', ".").parse::<f32>().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Measurement of SARS-CoV-2 RNA in the sewage entering a treatment plant (RWZI).
+/// Plants serving several safety regions occur once for every region, with the fraction of the plant's area in that region.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SewageMeasurement {
+    #[serde(rename = "Date_measurement")]
+    pub date: NaiveDate,
+    #[serde(rename = "RWZI_AWZI_code")]
+    pub plant_code: u32,
+    #[serde(rename = "RWZI_AWZI_name")]
+    pub plant_name: String,
+    #[serde(rename = "X_coordinate", default)]
+    pub x_coordinate: Option<f32>, // Rijksdriehoek coordinates in meters.
+    #[serde(rename = "Y_coordinate", default)]
+    pub y_coordinate: Option<f32>,
+    #[serde(rename = "Postal_code", default)]
+    pub postal_code: Option<String>,
+    #[serde(rename = "Security_region_code")]
+    pub safety_region_code: String,
+    #[serde(rename = "Security_region_name")]
+    pub safety_region_name: String,
+    #[serde(rename = "Percentage_in_security_region", deserialize_with = "decimal_comma")]
+    pub fraction_in_safety_region: f32,
+    #[serde(rename = "RNA_per_ml", default)]
+    pub rna_per_ml: Option<f32>,
+    #[serde(rename = "Representative_measurement", default)]
+    pub representative: bool
+}
+
+/// People whose sewage flows to a treatment plant. Not part of the RIVM dataset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SewagePlant {
+    pub code: u32,
+    pub population_served: u32
+}
+
+/// All measurements of the sewage dataset.
+#[derive(Debug, Clone)]
+pub struct SewageData {
+    pub measurements: Vec<SewageMeasurement>,
+    pub population_served: HashMap<u32, u32> // By plant code. Plants without it count as serving the mean of the known plants.
+}
+
+impl SewageData {
+    /// Loads the RIVM sewage dataset.
+    pub fn load(path: &str) -> Result<SewageData, String> {
+        let measurements = load_file::<Vec<SewageMeasurement>>(path).map_err(|e| format!("Could not load sewage data {}: {}", path, e))?;
+        Ok(SewageData { measurements, population_served: HashMap::new() })
+    }
+
+    /// Sets the population served by the given plants, used to weigh plants when combining them.
+    pub fn with_population_served(mut self, plants: &[SewagePlant]) -> SewageData {
+        self.population_served.extend(plants.iter().map(|p| (p.code, p.population_served)));
+        self
+    }
+
+    /// Only the measurements RIVM considers representative for the plant, e.g. not taken after heavy rainfall.
+    pub fn representative_only(&self) -> SewageData {
+        SewageData {
+            measurements: self.measurements.iter().filter(|m| m.representative).cloned().collect(),
+            population_served: self.population_served.clone()
+        }
+    }
+
+    /// Dates with at least one measurement, in order.
+    pub fn dates(&self) -> Vec<NaiveDate> {
+        let mut dates: Vec<NaiveDate> = self.measurements.iter().map(|m| m.date).collect();
+        dates.sort();
+        dates.dedup();
+        dates
+    }
+
+    /// Code and name of every plant, in order of first appearance.
+    pub fn plants(&self) -> Vec<(u32, String)> {
+        let mut plants: Vec<(u32, String)> = vec![];
+        for m in &self.measurements {
+            if !plants.iter().any(|(code, _)| *code == m.plant_code) {
+                plants.push((m.plant_code, m.plant_name.clone()));
+            }
+        }
+        plants
+    }
+
+    /// Code and name of every safety region, sorted by code.
+    pub fn safety_regions(&self) -> Vec<(String, String)> {
+        let mut regions: Vec<(String, String)> = vec![];
+        for m in &self.measurements {
+            if !regions.iter().any(|(code, _)| *code == m.safety_region_code) {
+                regions.push((m.safety_region_code.clone(), m.safety_region_name.clone()));
+            }
+        }
+        regions.sort();
+        regions
+    }
+
+    /// RNA per ml measured at a plant. Days without a measurement are missing.
+    pub fn plant_series(&self, plant_code: u32) -> Option<DailySeries<f32>> {
+        DailySeries::from_dated(self.measurements.iter()
+            .filter(|m| m.plant_code == plant_code)
+            .filter_map(|m| m.rna_per_ml.map(|rna| (m.date, rna))))
+    }
+
+    /// RNA per ml of a safety region, by code or name. Plants are sampled on different days, so every plant's series is
+    /// interpolated first. Each day is the mean over the plants measuring by then, weighted by the people they serve in the region.
+    pub fn safety_region_series(&self, region: &str) -> Option<DailySeries<f32>> {
+        self.combined_series(|m| m.safety_region_code == region || m.safety_region_name == region)
+    }
+
+    /// RNA per ml of the whole country, the mean over all plants measuring by then as for a safety region.
+    /// A plant serving several safety regions counts once, as its fractions add up to one.
+    pub fn national_series(&self) -> Option<DailySeries<f32>> {
+        self.combined_series(|_| true)
+    }
+
+    /// RNA per ml of the whole country on the days plants actually measured, without interpolation: the mean of that day's
+    /// measurements, weighted by the people the plants serve. Days without a measurement are missing.
+    pub fn national_samples(&self) -> Option<DailySeries<f32>> {
+        DailySeries::from_dated(self.dates().into_iter().filter_map(|date| {
+            let (sum, weight) = self.measurements.iter()
+                .filter(|m| m.date == date)
+                .filter_map(|m| m.rna_per_ml.map(|rna| (rna, m.fraction_in_safety_region * self.population_served(m.plant_code))))
+                .fold((0.0, 0.0), |(sum, weight), (rna, w)| (sum + rna * w, weight + w));
+            if weight > 0.0 { Some((date, sum / weight)) } else { None }
+        }))
+    }
+
+    /// People served by a plant. Without any known plant, every plant serves one person.
+    fn population_served(&self, plant_code: u32) -> f32 {
+        match self.population_served.get(&plant_code) {
+            Some(population) => *population as f32,
+            None if self.population_served.is_empty() => 1.0,
+            None => self.population_served.values().map(|p| *p as f32).sum::<f32>() / self.population_served.len() as f32
+        }
+    }
+
+    /// Weighted mean of the interpolated series of every plant in the selected measurements.
+    fn combined_series<F: Fn(&SewageMeasurement) -> bool>(&self, select: F) -> Option<DailySeries<f32>> {
+        // Every plant in every safety region, with the fraction of its area there.
+        let mut plants: Vec<(u32, &str, f32)> = vec![];
+        for m in self.measurements.iter().filter(|m| select(m)) {
+            if !plants.iter().any(|(code, region, _)| *code == m.plant_code && *region == m.safety_region_code) {
+                plants.push((m.plant_code, &m.safety_region_code, m.fraction_in_safety_region));
+            }
+        }
+
+        let series: Vec<(DailySeries<f32>, f32)> = plants.iter()
+            .filter_map(|(code, _, fraction)| self.plant_series(*code).map(|s| (s.interpolated(), fraction * self.population_served(*code))))
+            .collect();
+        let start = series.iter().map(|(s, _)| s.start).min()?;
+        let end = series.iter().map(|(s, _)| s.end()).max()?;
+
+        let days = (end - start).num_days();
+        DailySeries::from_dated((0..=days).map(|day| start + Duration::days(day)).filter_map(|date| {
+            let (sum, weight) = series.iter()
+                .filter_map(|(s, fraction)| s.get(date).map(|rna| (rna * fraction, *fraction)))
+                .fold((0.0, 0.0), |(sum, weight), (rna, fraction)| (sum + rna, weight + fraction));
+            if weight > 0.0 { Some((date, sum / weight)) } else { None }
+        }))
+    }
+}
+
+/// Gamma distributed shedding of RNA over the days since infection, cut off after `duration_in_days`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct SheddingProfile {
+    pub mean_in_days: f32,
+    pub shape: f32, // Lower values shed more at the start and have a longer tail.
+    pub duration_in_days: usize
+}
+
+impl Default for SheddingProfile {
+    fn default() -> Self {
+        Self { mean_in_days: 7.0, shape: 2.0, duration_in_days: 21 }
+    }
+}
+
+/// Observation model of sewage: RNA per ml shed by the people infected on every earlier day, per person connected to the sewer.
+/// Measurements are log-normally distributed around it.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct SewageModel {
+    pub shedding: SheddingProfile,
+    pub scale: f32, // RNA per ml while one in 100 000 inhabitants gets infected every day.
+    pub noise: f32 // Standard deviation of the logarithm of measurements plus one.
+}
+
+impl Default for SewageModel {
+    fn default() -> Self {
+        Self { shedding: SheddingProfile::default(), scale: 7.0, noise: 0.7 }
+    }
+}
+
+/// New infections between two states `days` apart: the change of exposed people plus those becoming infectious.
+pub fn new_infections(before: &Compartments, after: &Compartments, incubation_period_in_days: usize, days: f32) -> f32 {
+    let becoming_infectious = (before.exposed + after.exposed) / 2.0 * days / incubation_period_in_days.max(1) as f32;
+    (after.exposed - before.exposed + becoming_infectious).max(0.0)
+}
+
+impl SewageModel {
+    /// Fraction of all RNA shed on every day since infection, summing to one.
+    pub fn shedding_weights(&self) -> Vec<f32> {
+        const SAMPLES_PER_DAY: usize = 10;
+        let SheddingProfile { mean_in_days, shape, duration_in_days } = self.shedding;
+        let rate = shape / mean_in_days;
+        // Unnormalized density of the gamma distribution, sampled within every day.
+        let density = |t: f32| t.powf(shape - 1.0) * (-rate * t).exp();
+        let weights: Vec<f32> = (0..duration_in_days)
+            .map(|day| (0..SAMPLES_PER_DAY).map(|s| density(day as f32 + (s as f32 + 0.5) / SAMPLES_PER_DAY as f32)).sum())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        weights.iter().map(|w| if total > 0.0 { w / total } else { 0.0 }).collect()
+    }
+
+    /// Expected RNA per ml from the daily new infections per 100 000 inhabitants, the most recent day last.
+    pub fn expected(&self, daily_infections_per_100_000: &[f32]) -> f32 {
+        self.shedding_weights().iter()
+            .zip(daily_infections_per_100_000.iter().rev())
+            .map(|(weight, infections)| weight * infections)
+            .sum::<f32>() * self.scale
+    }
+
+    /// Expected RNA per ml at every step of the values of a region, with `times` in days.
+    /// New infections are taken per whole day, between those days the expectation is interpolated.
+    pub fn expected_rna(&self, times: &[f32], values: &[Compartments], incubation_period_in_days: usize) -> Vec<f32> {
+        let exposed: Vec<f32> = values.iter().map(|v| v.exposed).collect();
+        let population: Vec<f32> = values.iter().map(|v| v.population).collect();
+        let at = |day: usize| Compartments {
+            exposed: interpolate(times, &exposed, day as f32).unwrap_or(0.0),
+            population: interpolate(times, &population, day as f32).unwrap_or(0.0),
+            ..Compartments::default()
+        };
+
+        let last_day = times.last().map(|t| t.floor().max(0.0) as usize).unwrap_or(0);
+        let daily: Vec<f32> = (0..=last_day).map(|day| {
+            if day == 0 { return 0.0; }
+            let (before, after) = (at(day - 1), at(day));
+            let infections = new_infections(&before, &after, incubation_period_in_days, 1.0);
+            if after.population > 0.0 { infections / after.population * 100_000.0 } else { 0.0 }
+        }).collect();
+
+        let expected: Vec<f32> = (0..=last_day).map(|day| self.expected(&daily[..=day])).collect();
+        let days: Vec<f32> = (0..=last_day).map(|day| day as f32).collect();
+        times.iter().map(|t| interpolate(&days, &expected, *t).or(expected.last().copied()).unwrap_or(0.0)).collect()
+    }
+
+    /// Expected RNA per ml of the whole country at every step: the expectation of every province weighted by its population.
+    pub fn national_rna(&self, results: &SimulationResults) -> Vec<f32> {
+        let mut weighted = vec![0.0; results.times.len()];
+        let mut population = vec![0.0; results.times.len()];
+        for province in &results.provinces {
+            let expected = self.expected_rna(&results.times, &province.values, province.incubation_period_in_days);
+            for (step, values) in province.values.iter().enumerate() {
+                weighted[step] += expected[step] * values.population;
+                population[step] += values.population;
+            }
+        }
+        weighted.iter().zip(&population).map(|(w, p)| if *p > 0.0 { w / p } else { 0.0 }).collect()
+    }
+
+    /// Interval within which 95% of the measurements lie, around an expected value.
+    pub fn interval(&self, expected: f32) -> (f32, f32) {
+        let center = (expected.max(0.0) + 1.0).ln();
+        ((center - 1.96 * self.noise).exp() - 1.0, (center + 1.96 * self.noise).exp() - 1.0)
+    }
+
+    /// Logarithm of the likelihood of a measurement, up to a constant.
+    pub fn log_likelihood(&self, expected: f32, measured: f32) -> f32 {
+        -0.5 * (((measured.max(0.0) + 1.0).ln() - (expected.max(0.0) + 1.0).ln()) / self.noise).powi(2) - self.noise.ln()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(date: &str, plant_code: u32, region: &str, fraction: &str, rna: f32) -> SewageMeasurement {
+        serde_json::from_str(&format!(r#"{{
+            "Date_measurement": "{}", "RWZI_AWZI_code": {}, "RWZI_AWZI_name": "Plant {}",
+            "Security_region_code": "{}", "Security_region_name": "Region {}",
+            "Percentage_in_security_region": {}, "RNA_per_ml": {}, "Representative_measurement": true
+        }}"#, date, plant_code, plant_code, region, region, fraction, rna)).unwrap()
+    }
+
+    #[test]
+    fn reads_fractions_with_a_decimal_comma() {
+        assert_eq!(measurement("2020-09-01", 1, "VR07", r#""0,8645""#, 10.0).fraction_in_safety_region, 0.8645);
+        assert_eq!(measurement("2020-09-01", 1, "VR07", "0.5", 10.0).fraction_in_safety_region, 0.5);
+        assert_eq!(measurement("2020-09-01", 1, "VR07", r#""1""#, 10.0).fraction_in_safety_region, 1.0);
+    }
+
+    #[test]
+    fn weighs_plants_by_the_people_they_serve_in_a_region() {
+        let data = SewageData {
+            measurements: vec![
+                measurement("2020-09-01", 1, "VR07", "1", 100.0),
+                measurement("2020-09-03", 1, "VR07", "1", 300.0),
+                measurement("2020-09-02", 2, "VR07", r#""0,5""#, 600.0),
+                measurement("2020-09-02", 2, "VR08", r#""0,5""#, 600.0)
+            ],
+            population_served: HashMap::new()
+        }.with_population_served(&[SewagePlant { code: 1, population_served: 1000 }, SewagePlant { code: 2, population_served: 2000 }]);
+
+        // Plant 1 is interpolated to 200 on the 2nd, and weighs as much as the half of plant 2 in the region.
+        let region = data.safety_region_series("VR07").unwrap();
+        assert_eq!(region.values, vec![Some(100.0), Some(400.0), Some(300.0)]);
+        assert_eq!(data.safety_region_series("Region VR08").unwrap().values, vec![Some(600.0)]);
+        assert!(data.safety_region_series("VR09").is_none());
+    }
+
+    #[test]
+    fn shedding_weights_sum_to_one_and_peak_at_the_mode() {
+        let model = SewageModel::default();
+        let weights = model.shedding_weights();
+        assert_eq!(weights.len(), model.shedding.duration_in_days);
+        assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        // The mode of the gamma distribution, (shape - 1) / rate, lies at 3.5 days.
+        let peak = (0..weights.len()).max_by(|a, b| weights[*a].total_cmp(&weights[*b])).unwrap();
+        assert_eq!(peak, 3);
+    }
+
+    #[test]
+    fn steady_infections_give_the_scale() {
+        let model = SewageModel::default();
+        let expected = model.expected(&[1.0; 30]);
+        assert!((expected - model.scale).abs() < 1e-4);
+
+        let (lower, upper) = model.interval(expected);
+        assert!(lower < expected && expected < upper);
+        assert!(model.log_likelihood(expected, expected) > model.log_likelihood(expected, upper));
+    }
+}
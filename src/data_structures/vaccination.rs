@@ -0,0 +1,240 @@
+use crate::Compartments;
+use serde::{Serialize, Deserialize};
+
+/// Protection given by a single dose of a vaccine.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub struct Dose {
+    pub efficacy_against_infection: f32,
+    pub efficacy_against_severe_disease: f32
+}
+
+/// Dosing schedule of a vaccine. Supports one or two doses, as the model has a compartment for partially and one for fully vaccinated people.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaccineSchedule {
+    pub doses: Vec<Dose>,
+    pub dose_interval_in_days: f32, // Mean time between the first and second dose.
+    pub immunity_waning_period_in_days: f32 // Vaccinated people become susceptible again after this period.
+}
+
+impl VaccineSchedule {
+    /// Protection of people who received the first dose of a two dose schedule.
+    pub fn partial(&self) -> Dose {
+        self.doses[0]
+    }
+
+    /// Protection of people who received all doses.
+    pub fn full(&self) -> Dose {
+        *self.doses.last().unwrap()
+    }
+
+    /// Whether a second dose is needed for full protection.
+    pub fn is_two_dose(&self) -> bool {
+        self.doses.len() > 1
+    }
+}
+
+/// Protection of the vaccinated compartments. Without a vaccine nobody is protected and nobody gets vaccinated.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Protection {
+    pub partial: Dose,
+    pub full: Dose,
+    pub two_dose: bool,
+    pub waning_rate: f32 // Change of vaccinated to s
+}
+
+impl Protection {
+    pub fn of(vaccine: Option<&VaccineSchedule>) -> Self {
+        match vaccine {
+            Some(vaccine) => Protection {
+                partial: vaccine.partial(),
+                full: vaccine.full(),
+                two_dose: vaccine.is_two_dose(),
+                waning_rate: 1.0 / vaccine.immunity_waning_period_in_days
+            },
+            None => Protection::default()
+        }
+    }
+}
+
+/// Period during which a daily amount of doses is delivered.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaccinationCampaign {
+    pub start_day: f32,
+    #[serde(default)]
+    pub end_day: Option<f32>,
+    pub daily_doses: f32, // Doses per day for all provinces together.
+    /// Provinces which receive doses first. Without priority, doses are divided by population.
+    #[serde(default)]
+    pub province_priority: Vec<String>,
+    /// Age groups which receive doses first within a province. Without priority, doses are divided by the people willing to be vaccinated.
+    #[serde(default)]
+    pub age_priority: Vec<String>
+}
+
+/// Vaccine and the campaigns rolling it out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaccinationPlan {
+    pub schedule: VaccineSchedule,
+    pub acceptance_rate: f32, // Fraction of the population willing to be vaccinated.
+    pub campaigns: Vec<VaccinationCampaign>
+}
+
+/// Doses per day given to an age group.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DoseRates {
+    pub first: f32,
+    pub second: f32
+}
+
+/// Orders indices by the names in `priority` first, followed by the remaining indices in their original order.
+fn priority_order(names: &[&str], priority: &[String]) -> Vec<usize> {
+    let prioritized: Vec<usize> = priority.iter().filter_map(|p| names.iter().position(|n| n == p)).collect();
+    let remaining = (0..names.len()).filter(|idx| !prioritized.contains(idx));
+    prioritized.iter().copied().chain(remaining).collect()
+}
+
+/// Gives at most `budget` doses, dividing it over the demands either in order of priority or proportionally.
+/// Returns the doses given to every demand.
+fn distribute(budget: f32, demands: &[f32], order: Option<&[usize]>) -> Vec<f32> {
+    let mut given = vec![0.0; demands.len()];
+    match order {
+        Some(order) => {
+            let mut remaining = budget;
+            for idx in order {
+                given[*idx] = demands[*idx].min(remaining);
+                remaining -= given[*idx];
+            }
+        },
+        None => {
+            let total: f32 = demands.iter().sum();
+            if total > 0.0 {
+                let fraction = (budget / total).min(1.0);
+                for (g, d) in given.iter_mut().zip(demands) {
+                    *g = d * fraction;
+                }
+            }
+        }
+    }
+    given
+}
+
+impl VaccinationPlan {
+    /// Checks the plan against the provinces and age groups of a scenario.
+    pub fn validate(&self, province_names: &[&str], group_names: &[&str]) -> Result<(), String> {
+        if self.schedule.doses.is_empty() || self.schedule.doses.len() > 2 {
+            return Err(format!("Vaccine schedule has {} doses, only schedules of one or two doses are supported", self.schedule.doses.len()));
+        }
+        let periods = [self.schedule.dose_interval_in_days, self.schedule.immunity_waning_period_in_days];
+        if periods.iter().any(|p| p.is_nan() || *p <= 0.0) {
+            return Err(String::from("Dose interval and immunity waning period of the vaccine need to be positive"));
+        }
+        let efficacies = self.schedule.doses.iter().flat_map(|d| vec![d.efficacy_against_infection, d.efficacy_against_severe_disease]);
+        if efficacies.chain(std::iter::once(self.acceptance_rate)).any(|e| !(0.0..=1.0).contains(&e)) {
+            return Err(String::from("Vaccine efficacies and acceptance rate need to be between 0 and 1"));
+        }
+        for campaign in &self.campaigns {
+            if !campaign.daily_doses.is_finite() || campaign.daily_doses < 0.0 {
+                return Err(format!("Vaccination campaign starting on day {} needs a non-negative amount of daily doses", campaign.start_day));
+            }
+            if !campaign.start_day.is_finite() || campaign.end_day.is_some_and(|end| end.is_nan() || end <= campaign.start_day) {
+                return Err(format!("Vaccination campaign starting on day {} needs to start before it ends", campaign.start_day));
+            }
+            if let Some(p) = campaign.province_priority.iter().find(|p| !province_names.contains(&p.as_str())) {
+                return Err(format!("Vaccination priority contains unknown province {}", p));
+            }
+            if let Some(g) = campaign.age_priority.iter().find(|g| !group_names.contains(&g.as_str())) {
+                return Err(format!("Vaccination priority contains unknown age group {}", g));
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the doses per day given to every age group of every province at time `t`.
+    /// Second doses are given before first doses. First doses are limited to the susceptible people willing to be vaccinated.
+    /// Nobody is vaccinated when there is no population left.
+    pub fn doses(&self, t: f32, state: &[Vec<Compartments>], province_names: &[&str], group_names: &[&str]) -> Vec<Vec<DoseRates>> {
+        let mut rates: Vec<Vec<DoseRates>> = state.iter().map(|p| vec![DoseRates::default(); p.len()]).collect();
+
+        // Doses which could be given per day, if enough doses were available.
+        let mut second_demand: Vec<Vec<f32>> = state.iter().map(|p| p.iter().map(|g| {
+            if self.schedule.is_two_dose() { g.vaccinated_partially / self.schedule.dose_interval_in_days } else { 0.0 }
+        }).collect()).collect();
+        let mut first_demand: Vec<Vec<f32>> = state.iter().map(|p| p.iter().map(|g| {
+            (g.susceptible - (1.0 - self.acceptance_rate) * g.population).max(0.0)
+        }).collect()).collect();
+
+        let populations: Vec<f32> = state.iter().map(|p| p.iter().map(|g| g.population).sum()).collect();
+        let total_population: f32 = populations.iter().sum();
+        if total_population <= 0.0 {
+            return rates;
+        }
+
+        let active = self.campaigns.iter().filter(|c| c.start_day <= t && c.end_day.is_none_or(|end| t < end));
+        for campaign in active {
+            // Divide the budget over provinces.
+            let province_demands: Vec<f32> = second_demand.iter().zip(&first_demand)
+                .map(|(s, f)| s.iter().sum::<f32>() + f.iter().sum::<f32>())
+                .collect();
+            let province_budgets = if campaign.province_priority.is_empty() {
+                populations.iter().zip(&province_demands)
+                    .map(|(p, d)| (campaign.daily_doses * p / total_population).min(*d))
+                    .collect()
+            } else {
+                distribute(campaign.daily_doses, &province_demands, Some(&priority_order(province_names, &campaign.province_priority)))
+            };
+
+            // Within a province, give second doses first and first doses with the remaining budget.
+            let age_order = priority_order(group_names, &campaign.age_priority);
+            for (province_idx, budget) in province_budgets.into_iter().enumerate() {
+                let second_total: f32 = second_demand[province_idx].iter().sum();
+                let second = distribute(budget.min(second_total), &second_demand[province_idx], None);
+                let remaining = budget - second.iter().sum::<f32>();
+                let order = if campaign.age_priority.is_empty() { None } else { Some(age_order.as_slice()) };
+                let first = distribute(remaining, &first_demand[province_idx], order);
+
+                for group_idx in 0..rates[province_idx].len() {
+                    rates[province_idx][group_idx].second += second[group_idx];
+                    rates[province_idx][group_idx].first += first[group_idx];
+                    second_demand[province_idx][group_idx] -= second[group_idx];
+                    first_demand[province_idx][group_idx] -= first[group_idx];
+                }
+            }
+        }
+        rates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(campaign: VaccinationCampaign) -> VaccinationPlan {
+        let dose = Dose { efficacy_against_infection: 0.9, efficacy_against_severe_disease: 0.95 };
+        VaccinationPlan {
+            schedule: VaccineSchedule { doses: vec![dose, dose], dose_interval_in_days: 21.0, immunity_waning_period_in_days: 180.0 },
+            acceptance_rate: 0.8,
+            campaigns: vec![campaign]
+        }
+    }
+
+    fn campaign(start_day: f32, end_day: Option<f32>, daily_doses: f32) -> VaccinationCampaign {
+        VaccinationCampaign { start_day, end_day, daily_doses, province_priority: vec![], age_priority: vec![] }
+    }
+
+    #[test]
+    fn rejects_negative_doses_and_empty_campaigns() {
+        let validate = |c| plan(c).validate(&["Utrecht"], &["all"]);
+        assert!(validate(campaign(0.0, Some(10.0), 1000.0)).is_ok());
+        assert!(validate(campaign(0.0, None, -1.0)).is_err());
+        assert!(validate(campaign(0.0, None, f32::NAN)).is_err());
+        assert!(validate(campaign(10.0, Some(10.0), 1000.0)).is_err());
+        assert!(validate(campaign(10.0, Some(5.0), 1000.0)).is_err());
+    }
+
+    #[test]
+    fn gives_no_doses_without_population() {
+        let state = vec![vec![Compartments::default()]];
+        let rates = plan(campaign(0.0, None, 1000.0)).doses(1.0, &state, &["Utrecht"], &["all"]);
+        assert_eq!((rates[0][0].first, rates[0][0].second), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::Compartments;
+use crate::integrators::OdeState;
+use std::sync::Arc;
+
+/// Values of the system before the start of its history.
+#[derive(Clone)]
+pub enum PreHistory<S> {
+    /// The same value for all time before the start.
+    Constant(S),
+    /// Value as a function of time, for all time before the start.
+    Function(Arc<dyn Fn(f32) -> S + Send + Sync>)
+}
+
+/// History of a delay differential equation system. Stores every accepted step,
+/// and answers the state at any earlier time by linear interpolation between steps.
+#[derive(Clone)]
+pub struct History<S> {
+    start_time: f32,
+    pre_history: PreHistory<S>,
+    times: Vec<f32>,
+    values: Vec<S>
+}
+
+impl<S: OdeState> History<S> {
+    /// Creates a history starting at `start_time` with the initial value of the system.
+    pub fn new(start_time: f32, initial: S, pre_history: PreHistory<S>) -> Self {
+        Self {
+            start_time,
+            pre_history,
+            times: vec![start_time],
+            values: vec![initial]
+        }
+    }
+
+    /// Stores the value of the system at time `t`, which needs to be later than all stored times.
+    pub fn push(&mut self, t: f32, value: S) {
+        debug_assert!(t > *self.times.last().unwrap(), "History needs to be stored in order");
+        self.times.push(t);
+        self.values.push(value);
+    }
+
+    /// Continues a history from stored times and values, as returned by `stored`. Needs at least one value.
+    pub fn from_stored(start_time: f32, pre_history: PreHistory<S>, times: Vec<f32>, values: Vec<S>) -> Self {
+        assert!(!times.is_empty() && times.len() == values.len(), "History needs a value for every time");
+        Self { start_time, pre_history, times, values }
+    }
+
+    /// Stored times and values, oldest first.
+    pub fn stored(&self) -> (&[f32], &[S]) {
+        (&self.times, &self.values)
+    }
+
+    /// Replaces the most recent stored value, e.g. after correcting the state with observations.
+    pub fn replace_last(&mut self, value: S) {
+        *self.values.last_mut().unwrap() = value;
+    }
+
+    /// Time of the most recent stored value.
+    pub fn last_time(&self) -> f32 {
+        *self.times.last().unwrap()
+    }
+
+    /// Returns the state of the system at time `t`.
+    pub fn state_at(&self, t: f32) -> S {
+        self.map_at(t, |s| s.clone())
+    }
+
+    /// Returns part of the state of the system at time `t`. `f` selects the part, and needs to be linear.
+    /// Interpolates after selecting, which avoids cloning the complete state.
+    /// Times after the last stored value return the last stored value.
+    pub fn map_at<T: OdeState, F: Fn(&S) -> T>(&self, t: f32, f: F) -> T {
+        if t < self.start_time {
+            return match &self.pre_history {
+                PreHistory::Constant(value) => f(value),
+                PreHistory::Function(function) => f(&function(t))
+            };
+        }
+
+        let idx = self.times.partition_point(|time| *time <= t);
+        if idx == 0 {
+            return f(&self.values[0]);
+        }
+        if idx == self.times.len() {
+            return f(self.values.last().unwrap());
+        }
+
+        let (t0, t1) = (self.times[idx - 1], self.times[idx]);
+        let theta = (t - t0) / (t1 - t0);
+        f(&self.values[idx - 1]).scale(1.0 - theta).scaled_add(theta, &f(&self.values[idx]))
+    }
+
+    /// Discards stored values which are no longer needed to answer queries at or after `t`.
+    /// Used to bound memory usage to the largest delay of the system. Later queries before `t` return the oldest kept value.
+    pub fn discard_before(&mut self, t: f32) {
+        let idx = self.times.partition_point(|time| *time <= t).saturating_sub(1);
+        if idx > 0 {
+            self.times.drain(..idx);
+            self.values.drain(..idx);
+        }
+    }
+}
+
+/// View on the history of a single province, within the history of a system of provinces.
+pub struct ProvinceHistory<'a> {
+    history: &'a History<Vec<Vec<Compartments>>>,
+    province: usize
+}
+
+impl<'a> ProvinceHistory<'a> {
+    pub fn new(history: &'a History<Vec<Vec<Compartments>>>, province: usize) -> Self {
+        Self { history, province }
+    }
+
+    /// Returns the state of the province at time `t`, summed over all age groups.
+    pub fn state_at(&self, t: f32) -> Compartments {
+        self.history.map_at(t, |s| s[self.province].iter().copied().sum())
+    }
+
+    /// Returns the state of every age group of the province at time `t`.
+    pub fn age_groups_at(&self, t: f32) -> Vec<Compartments> {
+        self.history.map_at(t, |s| s[self.province].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History<f32> {
+        let mut history = History::new(0.0, 1.0, PreHistory::Function(Arc::new(|t| 1.0 + t)));
+        history.push(1.0, 3.0);
+        history.push(3.0, 7.0);
+        history
+    }
+
+    #[test]
+    fn interpolates_between_stored_steps() {
+        let history = history();
+        let states: Vec<f32> = [-0.5, 0.0, 0.5, 1.0, 2.5, 3.0, 4.0].iter().map(|t| history.state_at(*t)).collect();
+        assert_eq!(states, vec![0.5, 1.0, 2.0, 3.0, 6.0, 7.0, 7.0]);
+        assert_eq!(History::new(0.0, 1.0, PreHistory::Constant(0.0)).state_at(-10.0), 0.0);
+    }
+
+    #[test]
+    fn keeps_answering_after_discarding_old_steps() {
+        let mut history = history();
+        history.discard_before(2.0);
+        assert_eq!(history.stored(), (&[1.0, 3.0][..], &[3.0, 7.0][..]));
+        assert_eq!(history.state_at(2.0), 5.0);
+        // Before the oldest kept value, the pre-history no longer applies.
+        assert_eq!(history.state_at(0.5), 3.0);
+    }
+}
@@ -4,7 +4,7 @@ use std::cmp::Ordering;
 // However, since we're guaranteed to not have NaN numbers, we do have a total order in our specific case.
 // Therefore I implemented variants of f32 and f64 which are guaranteed to be non-NaN.
 
-#[derive(PartialEq,PartialOrd, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone)]
 pub struct NonNanF64(pub f64);
 
 impl NonNanF64 {
@@ -19,13 +19,19 @@ impl NonNanF64 {
 
 impl Eq for NonNanF64 {}
 
+impl PartialOrd for NonNanF64 {
+    fn partial_cmp(&self, other: &NonNanF64) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for NonNanF64 {
     fn cmp(&self, other: &NonNanF64) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.0.partial_cmp(&other.0).unwrap()
     }
 }
 
-#[derive(PartialEq,PartialOrd, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone)]
 pub struct NonNanF32(pub f32);
 
 impl NonNanF32 {
@@ -40,8 +46,14 @@ impl NonNanF32 {
 
 impl Eq for NonNanF32 {}
 
+impl PartialOrd for NonNanF32 {
+    fn partial_cmp(&self, other: &NonNanF32) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for NonNanF32 {
     fn cmp(&self, other: &NonNanF32) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.0.partial_cmp(&other.0).unwrap()
     }
 }
\ No newline at end of file
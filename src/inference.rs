@@ -0,0 +1,437 @@
+use crate::*;
+use crate::calibration::scenario_with;
+use chrono::NaiveDate;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, LogNormal, Normal, StandardNormal, Uniform};
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+
+/// Burn-in iterations between adaptations of the proposal.
+const ADAPTATION_INTERVAL: usize = 50;
+
+/// Evaluations used to find a mode of the posterior, around which the chains start.
+const MODE_EVALUATIONS: usize = 200;
+
+/// Spread of the starting points of the chains around the mode, in prior scales. Chains which start apart
+/// and still agree show that they explore the posterior, rather than the neighbourhood of their start.
+const START_DISPERSION: f32 = 0.25;
+
+/// Acceptance rate the proposal adapts to, optimal for random-walk Metropolis in several dimensions.
+const TARGET_ACCEPTANCE_RATE: f32 = 0.234;
+
+/// Observed standard deviations smaller than these are raised to them, so days with a tiny interval do not dominate.
+const MIN_LOG_PREVALENCE_DEVIATION: f32 = 0.05;
+const MIN_REPRODUCTION_NUMBER_DEVIATION: f32 = 0.05;
+
+impl Prior {
+    /// Logarithm of the density, up to a constant. Minus infinity outside of the support.
+    pub fn ln_density(&self, x: f32) -> f32 {
+        match *self {
+            Prior::Uniform { lower, upper } => if (lower..=upper).contains(&x) { -(upper - lower).ln() } else { f32::NEG_INFINITY },
+            Prior::Normal { mean, standard_deviation } => -0.5 * ((x - mean) / standard_deviation).powi(2) - standard_deviation.ln(),
+            Prior::LogNormal { log_mean, log_standard_deviation } => {
+                if x <= 0.0 { return f32::NEG_INFINITY; }
+                -0.5 * ((x.ln() - log_mean) / log_standard_deviation).powi(2) - log_standard_deviation.ln() - x.ln()
+            }
+        }
+    }
+
+    /// Typical spread of the distribution, used to size the initial proposal.
+    pub fn scale(&self) -> f32 {
+        match *self {
+            Prior::Uniform { lower, upper } => (upper - lower) / 12f32.sqrt(),
+            Prior::Normal { standard_deviation, .. } => standard_deviation,
+            Prior::LogNormal { log_mean, log_standard_deviation } => log_mean.exp() * log_standard_deviation
+        }
+    }
+
+    /// Draws a value, used as the starting point of a chain.
+    pub fn sample(&self, rng: &mut Pcg64) -> f32 {
+        match *self {
+            Prior::Uniform { lower, upper } => Uniform::new_inclusive(lower, upper).sample(rng),
+            Prior::Normal { mean, standard_deviation } => Normal::new(mean, standard_deviation).unwrap().sample(rng),
+            Prior::LogNormal { log_mean, log_standard_deviation } => LogNormal::new(log_mean, log_standard_deviation).unwrap().sample(rng)
+        }
+    }
+}
+
+impl CalibratedParameter {
+    /// Name used in scenario files and trace files.
+    pub fn key(&self) -> &'static str {
+        match self {
+            CalibratedParameter::RNaught => "r_naught",
+            CalibratedParameter::SeedOffsetInDays => "seed_offset_in_days",
+            CalibratedParameter::InitialSpreaders => "initial_spreaders",
+            CalibratedParameter::MeasureEffectiveness => "measure_effectiveness"
+        }
+    }
+}
+
+/// Samples of a single Markov chain after the burn-in.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    pub samples: Vec<Vec<f32>>, // Value of every parameter, per iteration.
+    pub log_posterior: Vec<f32>,
+    pub accepted: usize
+}
+
+impl Chain {
+    /// Formats the chain as CSV, with one row per iteration.
+    pub fn to_csv(&self, parameters: &[CalibratedParameter]) -> String {
+        let mut csv = String::from("iteration,log_posterior");
+        for parameter in parameters {
+            csv.push(',');
+            csv.push_str(parameter.key());
+        }
+        csv.push('\n');
+
+        for (iteration, (sample, log_posterior)) in self.samples.iter().zip(&self.log_posterior).enumerate() {
+            csv.push_str(&format!("{},{}", iteration, log_posterior));
+            for value in sample {
+                csv.push_str(&format!(",{}", value));
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Split R-hat of Gelman et al. Every chain is split in halves, such that chains which drift are detected as well.
+fn split_r_hat(chains: &[Vec<f32>]) -> f32 {
+    let halves: Vec<&[f32]> = chains.iter().flat_map(|c| { let (a, b) = c.split_at(c.len() / 2); vec![a, &b[b.len() - a.len()..]] }).collect();
+    let (m, n) = (halves.len() as f32, halves[0].len() as f32);
+    if n < 2.0 {
+        return f32::NAN;
+    }
+    let means: Vec<f32> = halves.iter().map(|h| h.iter().sum::<f32>() / n).collect();
+    let mean = means.iter().sum::<f32>() / m;
+    let between = n / (m - 1.0) * means.iter().map(|x| (x - mean).powi(2)).sum::<f32>();
+    let within = halves.iter().zip(&means).map(|(h, hm)| h.iter().map(|x| (x - hm).powi(2)).sum::<f32>() / (n - 1.0)).sum::<f32>() / m;
+    if within <= 0.0 {
+        return if between <= 0.0 { 1.0 } else { f32::INFINITY };
+    }
+    (((n - 1.0) / n * within + between / n) / within).sqrt()
+}
+
+/// Effective sample size over all chains, from the autocorrelations summed with Geyer's initial positive sequence.
+fn effective_sample_size(chains: &[Vec<f32>]) -> f32 {
+    let (m, n) = (chains.len(), chains[0].len());
+    let total = (m * n) as f32;
+    let means: Vec<f32> = chains.iter().map(|c| c.iter().sum::<f32>() / n as f32).collect();
+    let autocovariance = |lag: usize| -> f32 {
+        chains.iter().zip(&means)
+            .map(|(c, mean)| (0..n - lag).map(|i| (c[i] - mean) * (c[i + lag] - mean)).sum::<f32>() / n as f32)
+            .sum::<f32>() / m as f32
+    };
+    let mean = means.iter().sum::<f32>() / m as f32;
+    let within = autocovariance(0) * n as f32 / (n as f32 - 1.0).max(1.0);
+    let between = if m > 1 { means.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / (m - 1) as f32 } else { 0.0 };
+    let variance = (n as f32 - 1.0) / n as f32 * within + between;
+    if variance <= 0.0 {
+        return total;
+    }
+
+    let rho = |lag: usize| 1.0 - (within - autocovariance(lag)) / variance;
+    let mut tau = -1.0;
+    let mut lag = 0;
+    while lag + 1 < n {
+        let pair = rho(lag) + rho(lag + 1);
+        if pair <= 0.0 { break; }
+        tau += 2.0 * pair;
+        lag += 2;
+    }
+    total / tau.max(1.0 / total.log10().max(1.0))
+}
+
+/// Sample covariance of points.
+fn covariance(points: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let (n, dimensions) = (points.len() as f32, points[0].len());
+    let means: Vec<f32> = (0..dimensions).map(|i| points.iter().map(|p| p[i]).sum::<f32>() / n).collect();
+    (0..dimensions).map(|i| (0..dimensions).map(|j| {
+        points.iter().map(|p| (p[i] - means[i]) * (p[j] - means[j])).sum::<f32>() / n
+    }).collect()).collect()
+}
+
+/// Lower triangular Cholesky factor of a symmetric matrix. `None` if the matrix is not positive definite.
+fn cholesky(matrix: &[Vec<f32>]) -> Option<Vec<Vec<f32>>> {
+    let n = matrix.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f32 = matrix[i][j] - (0..j).map(|k| l[i][k] * l[j][k]).sum::<f32>();
+            if i == j {
+                if sum <= 0.0 { return None; }
+                l[i][j] = sum.sqrt();
+            } else {
+                l[i][j] = sum / l[j][j];
+            }
+        }
+    }
+    Some(l)
+}
+
+/// Samples the posterior of scenario values given the observed prevalence and reproduction number of the whole country.
+/// Every evaluation of the likelihood simulates the scenario with the compartmental model.
+pub struct Inference<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    settings: &'a InferenceSettings,
+    prevalence: &'a ObservedData,
+    reproduction_number: Option<&'a ObservedData>
+}
+
+impl<'a> Inference<'a> {
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario, settings: &'a InferenceSettings, prevalence: &'a ObservedData, reproduction_number: Option<&'a ObservedData>) -> Self {
+        Self { graph, scenario, settings, prevalence, reproduction_number }
+    }
+
+    fn parameters(&self) -> Vec<CalibratedParameter> {
+        self.settings.parameters.iter().map(|p| p.parameter).collect()
+    }
+
+    /// Observed days within the fitted period, as days since the start date, one per observation interval.
+    fn observed(&self, data: &ObservedData, start_date: NaiveDate) -> Vec<(usize, Estimate)> {
+        let observed: Vec<(usize, Estimate)> = data.estimates.dated()
+            .filter(|(date, _)| self.settings.fit_from.is_none_or(|from| *date >= from) && self.settings.fit_until.is_none_or(|until| *date <= until))
+            .filter(|(date, _)| *date >= start_date)
+            .map(|(date, estimate)| ((date - start_date).num_days() as usize, estimate))
+            .collect();
+        let first = observed.first().map(|(day, _)| *day).unwrap_or(0);
+        observed.into_iter().filter(|(day, _)| (day - first).is_multiple_of(self.settings.observation_interval_in_days)).collect()
+    }
+
+    /// National infected people and reproduction number for every day from the start date up to `days`.
+    /// Days before the seeding have no infected people, days with less than one infected person no reproduction number.
+    fn daily_national(&self, values: &[f32], days: usize) -> (Vec<f32>, Vec<f32>) {
+        let (mut scenario, offset) = scenario_with(self.scenario, &self.parameters(), values);
+        scenario.time_span_in_days = (days as f32 + offset).ceil().max(1.0) as usize + 1;
+        scenario.stochastic = None;
+
+        let results = Simulation::new(self.graph, &scenario).run();
+        let national: Vec<Compartments> = (0..results.times.len())
+            .map(|step| results.provinces.iter().map(|p| p.values[step]).sum())
+            .collect();
+        let infected: Vec<f32> = national.iter().map(|v| v.infected).collect();
+        let r: Vec<f32> = results.national_reproduction_number().into_iter().map(|r| r.unwrap_or(f32::NAN)).collect();
+
+        (0..=days).map(|day| {
+            let t = day as f32 + offset;
+            (interpolate(&results.times, &infected, t).unwrap_or(0.0), interpolate(&results.times, &r, t).unwrap_or(f32::NAN))
+        }).unzip()
+    }
+
+    /// Logarithm of the posterior density, up to a constant. Prevalence is compared on a logarithmic scale,
+    /// with the standard deviations following from the 95% intervals, times the overdispersion.
+    fn log_posterior(&self, values: &[f32], prevalence: &[(usize, Estimate)], reproduction_number: &[(usize, Estimate)]) -> f32 {
+        let prior: f32 = self.settings.parameters.iter().zip(values).map(|(p, x)| p.prior.ln_density(*x)).sum();
+        if !prior.is_finite() {
+            return f32::NEG_INFINITY;
+        }
+
+        let last = prevalence.iter().chain(reproduction_number).map(|(day, _)| *day).max().unwrap_or(0);
+        let (infected, r) = self.daily_national(values, last);
+        let overdispersion = self.settings.overdispersion;
+        let normal = |x: f32, mean: f32, deviation: f32| -0.5 * ((x - mean) / (deviation * overdispersion)).powi(2) - (deviation * overdispersion).ln();
+
+        let prevalence_likelihood: f32 = prevalence.iter().map(|(day, observed)| {
+            let deviation = (((observed.high + 1.0).ln() - (observed.low + 1.0).ln()) / (2.0 * 1.96)).max(MIN_LOG_PREVALENCE_DEVIATION);
+            normal((infected[*day].max(0.0) + 1.0).ln(), (observed.central() + 1.0).ln(), deviation)
+        }).sum();
+        let reproduction_likelihood: f32 = reproduction_number.iter()
+            .filter(|(day, _)| r[*day].is_finite())
+            .map(|(day, observed)| normal(r[*day], observed.central(), ((observed.high - observed.low) / (2.0 * 1.96)).max(MIN_REPRODUCTION_NUMBER_DEVIATION)))
+            .sum();
+        prior + prevalence_likelihood + reproduction_likelihood
+    }
+
+    /// Mode of the posterior near a draw of the priors, as the posterior is usually too narrow for the burn-in
+    /// to find it from anywhere in the priors. Steps of the minimization are in prior scales.
+    fn mode(&self, prevalence: &[(usize, Estimate)], reproduction_number: &[(usize, Estimate)]) -> Vec<f32> {
+        let mut rng = Pcg64::seed_from_u64(self.settings.seed);
+        let start: Vec<f32> = self.settings.parameters.iter().map(|p| p.prior.sample(&mut rng)).collect();
+        let at = |point: &[f32]| -> Vec<f32> {
+            start.iter().zip(point).zip(&self.settings.parameters).map(|((x, u), p)| x + u * p.prior.scale()).collect()
+        };
+        let mode = nelder_mead(|point| -self.log_posterior(&at(point), prevalence, reproduction_number), &vec![0.0; start.len()], &vec![0.5; start.len()], MODE_EVALUATIONS, 1e-4);
+        at(&mode.point)
+    }
+
+    /// Runs a single chain, starting at a random distance from the mode. During the burn-in the proposal adapts to the
+    /// recent covariance of the chain times 2.38² / d, scaled further to reach the target acceptance rate.
+    fn chain(&self, seed: u64, mode: &[f32], prevalence: &[(usize, Estimate)], reproduction_number: &[(usize, Estimate)]) -> Chain {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        let dimensions = self.settings.parameters.len();
+        let scales: Vec<f32> = self.settings.parameters.iter().map(|p| p.prior.scale()).collect();
+
+        // Starting points outside of the priors are drawn again.
+        let (mut current, mut current_log_posterior) = (mode.to_vec(), f32::NEG_INFINITY);
+        for _ in 0..100 {
+            let start: Vec<f32> = mode.iter().zip(&scales).map(|(x, scale)| x + START_DISPERSION * scale * rng.sample::<f32, _>(StandardNormal)).collect();
+            let log_posterior = self.log_posterior(&start, prevalence, reproduction_number);
+            if log_posterior.is_finite() {
+                current = start;
+                current_log_posterior = log_posterior;
+                break;
+            }
+        }
+
+        // Cholesky factor of the proposal covariance, initially independent steps of a tenth of every prior.
+        let mut proposal_factor: Vec<Vec<f32>> = (0..dimensions)
+            .map(|i| (0..dimensions).map(|j| if i == j { 0.1 * scales[i] } else { 0.0 }).collect())
+            .collect();
+        let mut history: Vec<Vec<f32>> = vec![];
+        let (mut factor, mut recently_accepted) = (1.0, 0);
+        let mut chain = Chain { samples: vec![], log_posterior: vec![], accepted: 0 };
+        for iteration in 0..self.settings.burn_in + self.settings.samples {
+            let z: Vec<f32> = (0..dimensions).map(|_| rng.sample(StandardNormal)).collect();
+            let proposal: Vec<f32> = current.iter().zip(&proposal_factor)
+                .map(|(x, row)| x + row.iter().zip(&z).map(|(l, z)| l * z).sum::<f32>())
+                .collect();
+            let proposal_log_posterior = self.log_posterior(&proposal, prevalence, reproduction_number);
+            let accept = proposal_log_posterior.is_finite()
+                && (!current_log_posterior.is_finite() || rng.gen::<f32>().ln() < proposal_log_posterior - current_log_posterior);
+            if accept {
+                current = proposal;
+                current_log_posterior = proposal_log_posterior;
+            }
+
+            if iteration < self.settings.burn_in {
+                history.push(current.clone());
+                recently_accepted += accept as usize;
+                if history.len().is_multiple_of(ADAPTATION_INTERVAL) {
+                    // Steer the acceptance rate towards the target, and forget the start of the chain, which is rarely representative.
+                    let change = (recently_accepted as f32 / ADAPTATION_INTERVAL as f32 / TARGET_ACCEPTANCE_RATE).clamp(0.25, 2.0);
+                    factor *= change;
+                    recently_accepted = 0;
+                    let scale = factor * 2.38 / (dimensions as f32).sqrt();
+                    match cholesky(&covariance(&history[history.len() / 2..])) {
+                        Some(l) => proposal_factor = l.into_iter().map(|row| row.into_iter().map(|v| v * scale).collect()).collect(),
+                        // A chain which did not move recently keeps its proposal, only scaled.
+                        None => proposal_factor.iter_mut().flatten().for_each(|v| *v *= change.min(1.0))
+                    }
+                }
+            } else {
+                chain.samples.push(current.clone());
+                chain.log_posterior.push(current_log_posterior);
+                chain.accepted += accept as usize;
+            }
+        }
+        chain
+    }
+
+    /// Runs all chains in parallel, then summarizes them and simulates draws of the posterior for the predictive bands.
+    /// Returns the summary together with the chains, for trace files.
+    pub fn run(&self) -> Result<(InferenceResults, Vec<Chain>), String> {
+        let start_date = self.scenario.start_date.ok_or("Inference needs a start date in the scenario")?;
+        if self.scenario.engine != EngineKind::Compartmental {
+            return Err(String::from("Inference needs the compartmental engine"));
+        }
+        let prevalence = self.observed(self.prevalence, start_date);
+        let reproduction_number = match self.reproduction_number {
+            Some(data) if self.settings.use_reproduction_number => self.observed(data, start_date),
+            _ => vec![]
+        };
+        if prevalence.is_empty() {
+            return Err(String::from("No observed prevalence within the fitted period"));
+        }
+
+        let mode = self.mode(&prevalence, &reproduction_number);
+        let chains: Vec<Chain> = (0..self.settings.chains)
+            .into_par_iter()
+            .map(|idx| self.chain(self.settings.seed.wrapping_add(idx as u64), &mode, &prevalence, &reproduction_number))
+            .collect();
+
+        let summaries = self.settings.parameters.iter().enumerate().map(|(idx, p)| {
+            let traces: Vec<Vec<f32>> = chains.iter().map(|c| c.samples.iter().map(|s| s[idx]).collect()).collect();
+            let mut pooled: Vec<f32> = traces.iter().flatten().copied().collect();
+            pooled.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let mean = pooled.iter().sum::<f32>() / pooled.len() as f32;
+            PosteriorSummary {
+                parameter: p.parameter,
+                mean,
+                standard_deviation: (pooled.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / pooled.len() as f32).sqrt(),
+                quantiles: self.settings.quantiles.iter().map(|q| ParameterQuantile { quantile: *q, value: quantile(&pooled, *q) }).collect(),
+                r_hat: split_r_hat(&traces),
+                effective_sample_size: effective_sample_size(&traces)
+            }
+        }).collect();
+
+        // Posterior-predictive bands over the whole time span, from samples spread evenly over all chains.
+        let pooled: Vec<&Vec<f32>> = chains.iter().flat_map(|c| c.samples.iter()).collect();
+        let draws = self.settings.predictive_draws.min(pooled.len());
+        let (infected, r): (Vec<Vec<f32>>, Vec<Vec<f32>>) = (0..draws)
+            .into_par_iter()
+            .map(|draw| self.daily_national(pooled[draw * pooled.len() / draws.max(1)], self.scenario.time_span_in_days))
+            .unzip();
+        let band = |simulated: &[Vec<f32>], q: f32| -> Vec<Option<f32>> {
+            (0..=self.scenario.time_span_in_days).map(|day| {
+                let mut at_day: Vec<f32> = simulated.iter().map(|s| s[day]).filter(|v| v.is_finite()).collect();
+                at_day.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                if at_day.is_empty() { None } else { Some(quantile(&at_day, q)) }
+            }).collect()
+        };
+        let predictive = self.settings.quantiles.iter().map(|q| PredictiveBand {
+            quantile: *q,
+            infected: band(&infected, *q).into_iter().map(|v| v.unwrap_or(0.0)).collect(),
+            reproduction_number: band(&r, *q)
+        }).collect();
+
+        let results = InferenceResults {
+            chains: chains.len(),
+            samples_per_chain: self.settings.samples,
+            seed: self.settings.seed,
+            acceptance_rates: chains.iter().map(|c| c.accepted as f32 / self.settings.samples as f32).collect(),
+            parameters: summaries,
+            start_date,
+            predictive
+        };
+        Ok((results, chains))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normal_chains(chains: usize, samples: usize) -> Vec<Vec<f32>> {
+        let mut rng = Pcg64::seed_from_u64(0);
+        (0..chains).map(|_| (0..samples).map(|_| rng.sample(StandardNormal)).collect()).collect()
+    }
+
+    #[test]
+    fn independent_chains_have_converged() {
+        let chains = normal_chains(4, 1000);
+        assert!((split_r_hat(&chains) - 1.0).abs() < 0.01);
+        let ess = effective_sample_size(&chains);
+        assert!(ess > 3000.0 && ess < 5000.0, "{}", ess);
+    }
+
+    #[test]
+    fn constant_chains() {
+        let same = vec![vec![1.5; 100]; 4];
+        assert_eq!(split_r_hat(&same), 1.0);
+        assert_eq!(effective_sample_size(&same), 400.0);
+
+        let different: Vec<Vec<f32>> = (0..4).map(|idx| vec![idx as f32; 100]).collect();
+        assert_eq!(split_r_hat(&different), f32::INFINITY);
+    }
+
+    #[test]
+    fn drifting_chains_have_not_converged() {
+        let chains: Vec<Vec<f32>> = normal_chains(4, 1000).into_iter()
+            .map(|c| c.into_iter().enumerate().map(|(i, x)| x + i as f32 / 100.0).collect())
+            .collect();
+        assert!(split_r_hat(&chains) > 1.1);
+    }
+
+    #[test]
+    fn autocorrelation_reduces_effective_sample_size() {
+        // AR(1) with coefficient 0.9 has an effective sample size of (1 - 0.9) / (1 + 0.9) of its length.
+        let chains: Vec<Vec<f32>> = normal_chains(4, 5000).into_iter()
+            .map(|c| c.into_iter().scan(0.0, |x, z: f32| { *x = 0.9 * *x + z; Some(*x) }).collect())
+            .collect();
+        let ess = effective_sample_size(&chains);
+        let expected = 20000.0 * 0.1 / 1.9;
+        assert!(ess > 0.7 * expected && ess < 1.3 * expected, "{}", ess);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::integrators::*;
+
+/// Adaptive Dormand-Prince RK45 method. Fifth order solution with an embedded fourth order error estimate.
+/// Uses the first same as last property, but recomputes the first stage to keep the integrator stateless.
+/// Provides a fourth order continuous extension for dense output.
+#[derive(Debug, Copy, Clone)]
+pub struct DormandPrince {
+    pub absolute_tolerance: f32,
+    pub relative_tolerance: f32,
+    pub max_step_size: f32
+}
+
+// Butcher tableau of the method.
+const C: [f32; 7] = [0.0, 1.0 / 5.0, 3.0 / 10.0, 4.0 / 5.0, 8.0 / 9.0, 1.0, 1.0];
+const A: [[f32; 6]; 7] = [
+    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [1.0 / 5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [3.0 / 40.0, 9.0 / 40.0, 0.0, 0.0, 0.0, 0.0],
+    [44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0, 0.0, 0.0, 0.0],
+    [19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0, 0.0, 0.0],
+    [9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0, 0.0],
+    [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0],
+];
+// Coefficients of the continuous extension. The weight of stage i at theta is the sum of P[i][j] * theta^(j + 1).
+const P: [[f32; 4]; 7] = [
+    [1.0, -8048581381.0 / 2820520608.0, 8663915743.0 / 2820520608.0, -12715105075.0 / 11282082432.0],
+    [0.0, 0.0, 0.0, 0.0],
+    [0.0, 131558114200.0 / 32700410799.0, -68118460800.0 / 10900136933.0, 87487479700.0 / 32700410799.0],
+    [0.0, -1754552775.0 / 470086768.0, 14199869525.0 / 1410260304.0, -10690763975.0 / 1880347072.0],
+    [0.0, 127303824393.0 / 49829197408.0, -318862633887.0 / 49829197408.0, 701980252875.0 / 199316789632.0],
+    [0.0, -282668133.0 / 205662961.0, 2019193451.0 / 616988883.0, -1453857185.0 / 822651844.0],
+    [0.0, 40617522.0 / 29380423.0, -110615467.0 / 29380423.0, 69997945.0 / 29380423.0],
+];
+// Difference between the fifth and fourth order weights.
+const E: [f32; 7] = [71.0 / 57600.0, 0.0, -71.0 / 16695.0, 71.0 / 1920.0, -17253.0 / 339200.0, 22.0 / 525.0, -1.0 / 40.0];
+
+impl Integrator for DormandPrince {
+    fn step<S: OdeState, F: Fn(f32, &S) -> S>(&self, f: &F, t: f32, y: &S, h: f32) -> Step<S> {
+        let mut k: Vec<S> = Vec::with_capacity(7);
+        k.push(f(t, y));
+        for stage in 1..7 {
+            let mut y_stage = y.clone();
+            for (j, kj) in k.iter().enumerate() {
+                if A[stage][j] != 0.0 {
+                    y_stage = y_stage.scaled_add(h * A[stage][j], kj);
+                }
+            }
+            k.push(f(t + C[stage] * h, &y_stage));
+        }
+
+        // The last stage was evaluated at the fifth order solution.
+        let mut value = y.clone();
+        for (j, kj) in k.iter().take(6).enumerate() {
+            if A[6][j] != 0.0 {
+                value = value.scaled_add(h * A[6][j], kj);
+            }
+        }
+
+        let mut error_estimate = k[0].scale(h * E[0]);
+        for (j, kj) in k.iter().enumerate().skip(1) {
+            if E[j] != 0.0 {
+                error_estimate = error_estimate.scaled_add(h * E[j], kj);
+            }
+        }
+
+        // Root mean square of the error, relative to the tolerance of each component.
+        let (sum, count) = value.zip_fold(&error_estimate, (0.0f32, 0usize), |(sum, count), v, e| {
+            let scale = self.absolute_tolerance + self.relative_tolerance * v.abs();
+            (sum + (e / scale) * (e / scale), count + 1)
+        });
+        let error = if count > 0 { (sum / count as f32).sqrt() } else { 0.0 };
+
+        Step { value, error: Some(error), stages: k }
+    }
+
+    fn interpolate<S: OdeState>(&self, y: &S, step: &Step<S>, h: f32, theta: f32) -> S {
+        let mut value = y.clone();
+        for (row, k) in P.iter().zip(&step.stages) {
+            let weight = row.iter().rev().fold(0.0, |acc, p| (acc + p) * theta);
+            if weight != 0.0 {
+                value = value.scaled_add(h * weight, k);
+            }
+        }
+        value
+    }
+
+    fn max_step_size(&self) -> f32 {
+        self.max_step_size
+    }
+}
@@ -0,0 +1,15 @@
+use crate::integrators::*;
+
+/// Forward Euler method. First order, a single evaluation per step.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Euler;
+
+impl Integrator for Euler {
+    fn step<S: OdeState, F: Fn(f32, &S) -> S>(&self, f: &F, t: f32, y: &S, h: f32) -> Step<S> {
+        Step {
+            value: y.scaled_add(h, &f(t, y)),
+            error: None,
+            stages: vec![]
+        }
+    }
+}
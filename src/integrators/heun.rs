@@ -0,0 +1,17 @@
+use crate::integrators::*;
+
+/// Heun's method (explicit trapezoidal rule). Second order, two evaluations per step.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Heun;
+
+impl Integrator for Heun {
+    fn step<S: OdeState, F: Fn(f32, &S) -> S>(&self, f: &F, t: f32, y: &S, h: f32) -> Step<S> {
+        let k1 = f(t, y);
+        let k2 = f(t + h, &y.scaled_add(h, &k1));
+        Step {
+            value: y.scaled_add(0.5 * h, &k1).scaled_add(0.5 * h, &k2),
+            error: None,
+            stages: vec![]
+        }
+    }
+}
@@ -0,0 +1,223 @@
+pub mod dormand_prince;
+pub mod euler;
+pub mod heun;
+pub mod rk4;
+
+pub use dormand_prince::*;
+pub use euler::*;
+pub use heun::*;
+pub use rk4::*;
+
+use serde::{Serialize, Deserialize};
+
+/// State of an ODE system which integrators can operate on.
+pub trait OdeState: Clone {
+    /// Returns `self + factor * other`. Both states need to have the same shape.
+    fn scaled_add(&self, factor: f32, other: &Self) -> Self;
+
+    /// Returns `factor * self`.
+    fn scale(&self, factor: f32) -> Self;
+
+    /// Folds over all pairs of matching scalar components of `self` and `other`.
+    fn zip_fold<A, F: FnMut(A, f32, f32) -> A>(&self, other: &Self, init: A, f: F) -> A;
+}
+
+impl OdeState for f32 {
+    fn scaled_add(&self, factor: f32, other: &Self) -> Self {
+        self + factor * other
+    }
+
+    fn scale(&self, factor: f32) -> Self {
+        factor * self
+    }
+
+    fn zip_fold<A, F: FnMut(A, f32, f32) -> A>(&self, other: &Self, init: A, mut f: F) -> A {
+        f(init, *self, *other)
+    }
+}
+
+impl<S: OdeState> OdeState for Vec<S> {
+    fn scaled_add(&self, factor: f32, other: &Self) -> Self {
+        self.iter().zip(other).map(|(a, b)| a.scaled_add(factor, b)).collect()
+    }
+
+    fn scale(&self, factor: f32) -> Self {
+        self.iter().map(|a| a.scale(factor)).collect()
+    }
+
+    fn zip_fold<A, F: FnMut(A, f32, f32) -> A>(&self, other: &Self, init: A, mut f: F) -> A {
+        self.iter().zip(other).fold(init, |acc, (a, b)| a.zip_fold(b, acc, &mut f))
+    }
+}
+
+/// Result of a single attempted step.
+pub struct Step<S> {
+    pub value: S,
+    /// Error estimate scaled by the tolerances. Steps with an error above 1.0 should be rejected.
+    /// Integrators without error control return None.
+    pub error: Option<f32>,
+    /// Stage derivatives of the step, kept by integrators which provide their own dense output.
+    pub stages: Vec<S>
+}
+
+/// Numerical method which advances an ODE system `dy/dt = f(t, y)` by a single step.
+pub trait Integrator {
+    /// Attempts a step of size `h` from `y` at time `t`.
+    fn step<S: OdeState, F: Fn(f32, &S) -> S>(&self, f: &F, t: f32, y: &S, h: f32) -> Step<S>;
+
+    /// Dense output: returns the solution at `t + theta * h` within a step taken from `y` at time `t`, with `theta` in [0, 1].
+    /// Integrators without their own continuous extension interpolate linearly.
+    fn interpolate<S: OdeState>(&self, y: &S, step: &Step<S>, h: f32, theta: f32) -> S {
+        let _ = h;
+        y.scale(1.0 - theta).scaled_add(theta, &step.value)
+    }
+
+    /// Largest step size the integrator may grow to. Only used by integrators with error control.
+    fn max_step_size(&self) -> f32 {
+        f32::INFINITY
+    }
+}
+
+/// Counters describing the cost of an integration.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub struct SolverStatistics {
+    pub accepted_steps: usize,
+    pub rejected_steps: usize,
+    pub evaluations: usize
+}
+
+/// Takes a single accepted step of at most `h`, shrinking it until the error estimate is within tolerance.
+/// Returns the step size which was taken, the accepted step and the step size to try next.
+pub fn advance<I: Integrator, S: OdeState, F: Fn(f32, &S) -> S>(integrator: &I, f: &F, t: f32, y: &S, mut h: f32, statistics: &mut SolverStatistics) -> (f32, Step<S>, f32) {
+    loop {
+        let step = integrator.step(f, t, y, h);
+        let error = match step.error {
+            Some(e) => e,
+            None => {
+                statistics.accepted_steps += 1;
+                return (h, step, h)
+            }
+        };
+
+        // Standard step size controller for a 5th order method, limited to a factor 5 change per step.
+        let factor = if error > 0.0 { (0.9 * error.powf(-0.2)).clamp(0.2, 5.0) } else { 5.0 };
+        let next_h = (h * factor).min(integrator.max_step_size());
+
+        if error <= 1.0 || h <= f32::EPSILON {
+            statistics.accepted_steps += 1;
+            return (h, step, next_h)
+        }
+        statistics.rejected_steps += 1;
+        h = next_h;
+    }
+}
+
+/// Integrators which can be selected from a scenario file.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum IntegratorKind {
+    Euler,
+    Heun,
+    #[default]
+    Rk4,
+    DormandPrince {
+        #[serde(default = "default_absolute_tolerance")]
+        absolute_tolerance: f32,
+        #[serde(default = "default_relative_tolerance")]
+        relative_tolerance: f32,
+        #[serde(default = "default_max_step_size")]
+        max_step_size: f32
+    }
+}
+
+fn default_absolute_tolerance() -> f32 { 1e-2 }
+fn default_relative_tolerance() -> f32 { 1e-4 }
+fn default_max_step_size() -> f32 { 1.0 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Double precision keeps the errors of the higher order methods above rounding errors.
+    impl OdeState for f64 {
+        fn scaled_add(&self, factor: f32, other: &Self) -> Self {
+            self + factor as f64 * other
+        }
+
+        fn scale(&self, factor: f32) -> Self {
+            factor as f64 * self
+        }
+
+        fn zip_fold<A, F: FnMut(A, f32, f32) -> A>(&self, other: &Self, init: A, mut f: F) -> A {
+            f(init, *self as f32, *other as f32)
+        }
+    }
+
+    /// Error at t = 4 of dy/dt = y cos(t) with y(0) = 1, whose solution is exp(sin(t)), taking fixed steps of size h.
+    fn error<I: Integrator>(integrator: &I, h: f32) -> f64 {
+        let f = |t: f32, y: &f64| y * (t as f64).cos();
+        let steps = (4.0 / h).round() as usize;
+        let y = (0..steps).fold(1.0, |y, step| integrator.step(&f, step as f32 * h, &y, h).value);
+        (y - 4f64.sin().exp()).abs()
+    }
+
+    /// Order of convergence between two step sizes, from the errors of both.
+    fn order<I: Integrator>(integrator: &I, h: f32) -> f64 {
+        (error(integrator, h) / error(integrator, h / 2.0)).log2()
+    }
+
+    #[test]
+    fn orders_of_convergence() {
+        let dormand_prince = DormandPrince { absolute_tolerance: 1e-2, relative_tolerance: 1e-4, max_step_size: 1.0 };
+        for (name, order, expected) in [
+            ("Euler", order(&Euler, 0.01), 1.0),
+            ("Heun", order(&Heun, 0.05), 2.0),
+            ("RK4", order(&Rk4, 0.2), 4.0),
+            ("Dormand-Prince", order(&dormand_prince, 0.5), 5.0)
+        ] {
+            assert!((order - expected).abs() < 0.3, "{} converges with order {}, expected {}", name, order, expected);
+        }
+    }
+
+    #[test]
+    fn dormand_prince_stays_within_tolerance() {
+        // Adaptive steps over the whole interval, with an error estimate below 1 for every accepted step.
+        let integrator = DormandPrince { absolute_tolerance: 1e-6, relative_tolerance: 1e-6, max_step_size: 1.0 };
+        let f = |t: f32, y: &f64| y * (t as f64).cos();
+        let (mut t, mut y, mut h) = (0.0f32, 1.0f64, 0.1f32);
+        let mut statistics = SolverStatistics::default();
+        while t < 4.0 {
+            let (taken, step, next_h) = advance(&integrator, &f, t, &y, h.min(4.0 - t), &mut statistics);
+            assert!(step.error.unwrap() <= 1.0);
+            t += taken;
+            y = step.value;
+            h = next_h;
+        }
+        assert!((y - 4f64.sin().exp()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn dormand_prince_interpolates_within_a_step() {
+        // Dense output is of fourth order, far closer to the solution within a large step than linear interpolation.
+        let integrator = DormandPrince { absolute_tolerance: 1e-2, relative_tolerance: 1e-4, max_step_size: 1.0 };
+        let f = |t: f32, y: &f64| y * (t as f64).cos();
+        let step = integrator.step(&f, 0.0, &1.0, 1.0);
+        for theta in [0.25, 0.5, 0.75] {
+            let exact = (theta as f64).sin().exp();
+            let dense = integrator.interpolate(&1.0, &step, 1.0, theta);
+            let linear = 1.0 + theta as f64 * (step.value - 1.0);
+            assert!((dense - exact).abs() < 1e-3 && (dense - exact).abs() < (linear - exact).abs() / 10.0, "theta {}: {} instead of {}", theta, dense, exact);
+        }
+        assert!((integrator.interpolate(&1.0, &step, 1.0, 1.0) - step.value).abs() < 1e-5);
+    }
+
+    #[test]
+    fn advance_rejects_steps_above_tolerance() {
+        let integrator = DormandPrince { absolute_tolerance: 1e-8, relative_tolerance: 1e-8, max_step_size: 10.0 };
+        let f = |t: f32, y: &f64| y * (t as f64).cos();
+        let mut statistics = SolverStatistics::default();
+        let (taken, step, _) = advance(&integrator, &f, 0.0, &1.0, 4.0, &mut statistics);
+        assert!(taken < 4.0 && step.error.unwrap() <= 1.0);
+        assert!(statistics.rejected_steps > 0 && statistics.accepted_steps == 1);
+    }
+}
@@ -0,0 +1,22 @@
+use crate::integrators::*;
+
+/// Classic fixed step Runge-Kutta method. Fourth order, samples and weighs 4 points per step.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Rk4;
+
+impl Integrator for Rk4 {
+    fn step<S: OdeState, F: Fn(f32, &S) -> S>(&self, f: &F, t: f32, y: &S, h: f32) -> Step<S> {
+        let k1 = f(t, y);
+        let k2 = f(t + 0.5 * h, &y.scaled_add(0.5 * h, &k1));
+        let k3 = f(t + 0.5 * h, &y.scaled_add(0.5 * h, &k2));
+        let k4 = f(t + h, &y.scaled_add(h, &k3));
+        Step {
+            value: y.scaled_add(h / 6.0, &k1)
+                .scaled_add(h / 3.0, &k2)
+                .scaled_add(h / 3.0, &k3)
+                .scaled_add(h / 6.0, &k4),
+            error: None,
+            stages: vec![]
+        }
+    }
+}
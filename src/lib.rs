@@ -0,0 +1,23 @@
+mod agent_based;
+mod assimilation;
+mod calibration;
+mod data_structures;
+mod float_helper;
+mod inference;
+mod simulation;
+mod stochastic;
+mod utility;
+
+pub mod dde;
+pub mod integrators;
+pub mod plot;
+
+pub use agent_based::*;
+pub use assimilation::*;
+pub use calibration::*;
+pub use float_helper::*;
+pub use inference::*;
+pub use data_structures::*;
+pub use simulation::*;
+pub use stochastic::*;
+pub use utility::*;
@@ -1,260 +1,298 @@
-mod data_structures;
-mod float_helper;
-mod utility;
+mod cli;
 
-pub use float_helper::*;
-pub use data_structures::*;
-pub use utility::*;
+use covid19_simulator::*;
+use cli::{Command, ExportFormat, Options};
+use std::convert::TryFrom;
 
-use serde::{Serialize, de::DeserializeOwned};
-
-
-use plotters::{*, prelude::*, drawing::*};
-use crate::NonNanF32;
-use crate::params::SimulationParameters;
-use crate::data_structures::params::hand_washing;
-
-const TIMESPAN_IN_DAYS: usize = 1 * 365;
-const INITIAL_SPREADERS: u32 = 1;
-
-const NATURAL_BIRTH_RATE: f32 = 0.011 / 365.0; // 6% growth a year
-const NATURAL_DEATH_RATE: f32 = 0.005 / 365.0;
-
-const DISEASE_PERIOD: usize = 7; // Time it takes for infected people to recover or die.
-const INCUBATION_PERIOD: usize = 7; // Time it takes for exposed people to become sick + infectious.
-const MORTALITY_RATE: f32 = 0.03; // Percentage of infected people who die.
-const IMMUNITY_WANING_TIME_IN_DAYS: usize = 30 * 4; // Immunity wanes after 4 months
-
-// R0 = beta / gamma
-const R_NAUGHT: f32 = 2.5;
-
-const HOSPITALIZATION_RATE: f32 = 0.1; //Amount of recovering people ending up in hospital, thus counting towards max hospital cap.
-const MAX_HOSPITAL_CAPACITY: usize = 1250; // Absolute amount of hospital capacity
+/// Loads a JSON file, printing why it could not be loaded.
+fn load<T: serde::de::DeserializeOwned>(path: &str) -> Result<T, Box<dyn std::error::Error>> {
+    match load_file::<T>(path) {
+        Ok(v) => Ok(v),
+        Err(e) => { println!("Could not load file {}: {}", path, e); Err(e.into()) }
+    }
+}
 
-const ENABLE_TRAFFIC: bool = true;
-const TRAFFIC_RATE: f32 = 0.05; // Percentage of E which travels to other places
+/// Loads the region hierarchy given on the command line.
+fn load_hierarchy(options: &Options) -> Result<RegionHierarchy, Box<dyn std::error::Error>> {
+    let regions = load::<Vec<RegionData>>(&options.regions)?;
+    Ok(RegionHierarchy::try_from(regions)?)
+}
 
-fn rate_of_change_with_time(sp: &SimulationParameters, previous: &Vec<f32>, previous_data: &[Vec<f32>], time: f32, h: f32) -> Vec<f32> {
-    let susceptible = previous[0];
-    let exposed = previous[1];
-    let infected = previous[2];
-    let recovered = previous[3];
-    // let dead = previous[4];
-    let population = previous[5];
-    //let hospitalizations = previous[6];
+/// Loads the province dataset and builds the graph, printing problems which were accepted or fixed.
+/// The dataset is converted to the regions at `level` first, if given and not the level of the dataset.
+fn build_graph(options: &Options, level: Option<RegionLevel>) -> Result<ProvinceGraph, Box<dyn std::error::Error>> {
+    let mut provinces = load::<Vec<ProvinceData>>(&options.dataset)?;
+    let level = level.unwrap_or(options.dataset_level);
+    if level != options.dataset_level {
+        provinces = load_hierarchy(options)?.dataset(&provinces, options.dataset_level, level)?;
+    }
+    match ProvinceGraphBuilder::new(provinces).asymmetric_connections(options.asymmetric_connections).level(level).build() {
+        Ok((graph, warnings)) => {
+            for warning in warnings {
+                println!("Warning: {}", warning);
+            }
+            Ok(graph)
+        },
+        Err(e) => { println!("{}: {}", options.dataset, e); Err(e.into()) }
+    }
+}
 
-    let mut measures_change = 0.0;
-    for measure in &sp.measures {
-        measures_change += measure(&sp, previous, previous_data, time, h);
+/// Converts results to the level requested on the command line, using the region hierarchy.
+/// Results are returned as they are when no other level is requested.
+fn results_at_level(options: &Options, results: SimulationResults) -> Result<SimulationResults, Box<dyn std::error::Error>> {
+    match options.level {
+        Some(level) if level != results.level => Ok(load_hierarchy(options)?.convert(&results, level)?),
+        _ => Ok(results)
     }
+}
 
-    let recovery_rate = 1.0 / (sp.sickness_period_in_days as f32); // Change of i to r
-    let base_infection_rate = sp.r_naught * recovery_rate as f32; // Change s to e
-    let mut infection_rate = base_infection_rate * (1.0 - measures_change); // Change of s to e
-    let incubation_rate = 1.0 / (sp.incubation_period_in_days as f32); // Change of e to i
-    let immunity_waning_rate = 1.0 / (sp.immunity_waning_period_in_days as f32); // Change r to s
-
-
-    // Compute the dy/dx for all differential equations in the system. See the report for the definition and explanation.
-    let mut dydx = vec![
-        /*s*/ sp.natural_birth_rate * population - ((infection_rate) * susceptible * (infected / population as f32)) - (sp.natural_death_rate * susceptible) + (immunity_waning_rate * recovered),
-        /*e*/ (infection_rate * susceptible * (infected / population as f32)) - incubation_rate * exposed - (sp.natural_death_rate * exposed),
-        /*i*/ (incubation_rate * exposed) - (recovery_rate * infected) - (sp.natural_death_rate * infected),
-        /*r*/ (recovery_rate * infected) * (1.0 - sp.mortality_rate) - sp.natural_death_rate * recovered - (immunity_waning_rate * recovered),
-        /*d*/ (recovery_rate * infected) * sp.mortality_rate + sp.natural_death_rate * susceptible + sp.natural_death_rate * exposed + sp.natural_death_rate * infected + sp.natural_death_rate * recovered,
-        /*p*/ (sp.natural_birth_rate * population - sp.natural_death_rate * population) - ((recovery_rate * infected) * sp.mortality_rate),
-        /*h*/ ((incubation_rate * exposed) - (recovery_rate * infected) - (sp.natural_death_rate * infected)) * sp.hospitalization_rate,
-    ];
-    dydx
+/// Loads the RIVM datasets in the observations directory. Datasets which can not be loaded are left out.
+fn load_observations(directory: &str) -> Observations {
+    let load = |name: &str, result: Result<ObservedData, String>| match result {
+        Ok(v) => Some(v),
+        Err(e) => { println!("Warning: {}, {} is not plotted", e, name); None }
+    };
+    // The population served per plant is not part of the RIVM data, it is only used when given.
+    let plants_path = format!("{}/sewage_plants.json", directory);
+    let plants = if std::path::Path::new(&plants_path).exists() {
+        load_file::<Vec<SewagePlant>>(&plants_path).unwrap_or_else(|e| { println!("Warning: {}: {}, the population served is not used", plants_path, e); vec![] })
+    } else { vec![] };
+    let sewage_data = SewageData::load(&format!("{}/COVID-19_rioolwaterdata.json", directory))
+        .map(|data| data.with_population_served(&plants).representative_only())
+        .map_err(|e| println!("Warning: {}, sewage is not plotted", e))
+        .ok();
+    Observations {
+        prevalence: load("prevalence", ObservedData::load_prevalence(&format!("{}/COVID-19_prevalentie.json", directory))),
+        reproduction_number: load("reproduction number", ObservedData::load_reproduction_number(&format!("{}/COVID-19_reproductiegetal.json", directory))),
+        sewage: sewage_data.as_ref().and_then(|data| data.national_series()),
+        sewage_samples: sewage_data.as_ref().and_then(|data| data.national_samples()),
+        sewage_data
+    }
 }
 
-/// Represents initial value and what values it needs to repeat before it.
-#[derive(Debug, Copy, Clone)]
-pub struct InitialValue {
-    value: f32,
-    repeating_before: f32
+/// Draws a graph for every province in the results. Results with a start date are also plotted against the observed data,
+/// with sewage through the sewage model of their scenario.
+fn plot_results(options: &Options, results: &SimulationResults) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(&options.output)?;
+    for province in &results.provinces {
+        plot::draw(&options.output, province, results.time_span_in_days, &results.times)?;
+    }
+    if let Some(start_date) = results.start_date {
+        let observations = load_observations(&options.observations);
+        plot::draw_observed(&options.output, results, start_date, &observations, &results.sewage)?;
+        // Regions which are safety regions are plotted against their own sewage measurements.
+        if let Some(sewage) = &observations.sewage_data {
+            for region in &results.provinces {
+                if let Some(measured) = sewage.safety_region_series(&region.name) {
+                    plot::draw_sewage(&options.output, region, &results.times, start_date, &results.sewage, &measured)?;
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
-/// Actual implementation of sampling  and weighing of the 4 points. Works on vectors. Those need to be identical in length.
-fn rk4_impl(value: &Vec<f32>, previous_data: &[Vec<f32>], t: f32, h: f32, params: &SimulationParameters, f: fn(&SimulationParameters, &Vec<f32>, &[Vec<f32>], f32, f32)->Vec<f32>) -> Vec<f32> {
-    let k1: Vec<f32> = f(params, value, previous_data, t, h).iter().map(|e|e*h).collect();
-    let k2: Vec<f32> = f(params, &value.iter().enumerate().map(|(idx, e)| e + 0.5 * k1[idx]).collect(), previous_data, t + 0.5 * h, h).iter().map(|e|e*h).collect();
-    let k3: Vec<f32> = f(params, &value.iter().enumerate().map(|(idx, e)| e + 0.5 * k2[idx]).collect(), previous_data, t + 0.5 * h, h).iter().map(|e|e*h).collect();
-    let k4: Vec<f32> = f(params, &value.iter().enumerate().map(|(idx, e)| e + k3[idx]).collect(), previous_data, t + h, h).iter().map(|e|e*h).collect();
-    return value.iter().enumerate().map(|(idx,e)| e + {(1.0/6.0) * (k1[idx] + 2.0 * k2[idx] + 2.0 * k3[idx] + k4[idx])}).collect();
+/// Loads a scenario and the graph to simulate it on, applying the command line overrides.
+/// Falls back to the default scenario if no file is given.
+fn load_scenario(options: &Options, scenario_path: Option<&str>) -> Result<(Scenario, ProvinceGraph), Box<dyn std::error::Error>> {
+    let mut scenario = match scenario_path {
+        Some(path) => Scenario::load(path)?,
+        None => Scenario::default()
+    };
+    if let Some(step_size) = options.step_size {
+        scenario.step_size = step_size;
+    }
+    if let Some(days) = options.days {
+        scenario.time_span_in_days = days;
+    }
+    if options.realizations.is_some() || options.seed.is_some() {
+        let stochastic = scenario.stochastic.get_or_insert_with(StochasticSettings::default);
+        stochastic.realizations = options.realizations.unwrap_or(stochastic.realizations);
+        stochastic.seed = options.seed.unwrap_or(stochastic.seed);
+    }
+
+    // Load province data into memory and construct the graph, of the regions the scenario is simulated on.
+    let mut graph = build_graph(options, scenario.level)?;
+    if let Some(matrix) = &scenario.origin_destination {
+        graph.apply_flows(matrix)?;
+    } else if let Some(model) = &scenario.mobility_model {
+        graph.apply_flows(&model.flows(&graph)?)?;
+    }
+    scenario.validate(&graph)?;
+    Ok((scenario, graph))
 }
 
-predefined_color!(ORANGE, 255, 165, 0, "The predefined orange color");
+/// Executes the run command: simulates a scenario, then writes the results and their graphs.
+fn run(options: &Options, scenario_path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let (scenario, graph) = load_scenario(options, scenario_path)?;
 
-/// main function of the program.
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Simulation in progress...");
 
-    // Load province data into memory.
-    let file = match load_file::<Vec<ProvinceData>>("./dataset/provinces.json") {
-        Some(v) => v,
-        None => { println!("Could not load file!"); return Err("Could not load file".into()) }
+    let results = match &scenario.engine {
+        EngineKind::Compartmental => Simulation::new(&graph, &scenario).run(),
+        EngineKind::AgentBased(settings) => AgentSimulation::new(&graph, &scenario, settings).run()
     };
 
-    // Construct graph
-    let graph = ProvinceGraph::from(file);
+    println!("Simulation done in {} steps ({} rejected, {} evaluations). Generating graphs...",
+             results.statistics.accepted_steps, results.statistics.rejected_steps, results.statistics.evaluations);
 
-    let mut province_parameters: Vec<SimulationParameters> = vec![];
-    let mut initial_values: Vec<Vec<InitialValue>> = vec![];
-    let mut results: Vec<Vec<Vec<f32>>> = vec![];
+    std::fs::create_dir_all(&options.output)?;
+    save_file(&format!("{}/results.json", options.output), &results)?;
+    plot_results(options, &results_at_level(options, results.clone())?)?;
 
-    // Step size to use in the simulation.
-    let step_size = 0.1;
+    if let Some(settings) = &scenario.stochastic {
+        println!("Running {} stochastic realizations...", settings.realizations);
+        let ensemble = StochasticSimulation::new(&graph, &scenario, settings).run();
+        println!("Outbreak probability: {:.1}%", ensemble.outbreak_probability * 100.0);
 
-    // Compute mean density over provinces.
-    let mut mean_density = 0.0f32;
-    for p in &graph {
-        mean_density += p.density_per_square_km as f32;
+        save_file(&format!("{}/ensemble.json", options.output), &ensemble)?;
+        for (province, deterministic) in ensemble.provinces.iter().zip(&results.provinces) {
+            plot::draw_ensemble(&options.output, province, deterministic, results.time_span_in_days, &ensemble.times)?;
+        }
     }
-    mean_density /= graph.len() as f32;
-
-    // Set up each province's parameters
-    for province in &graph {
-        // Compute relative change compared to mean density. Used to adjust infection rate.
-        let relative_change: f32 = (province.density_per_square_km as f32 - mean_density) / mean_density;
-
-        // Set up all parameters of the simulation
-        let parameters = SimulationParameters {
-            time_span_in_days: TIMESPAN_IN_DAYS,
-            initial_population: province.population as usize,
-            initial_spreaders:  if province.name == "Noord-Brabant" { INITIAL_SPREADERS as usize } else { 0 },
-            natural_birth_rate: NATURAL_BIRTH_RATE, 
-            natural_death_rate: NATURAL_DEATH_RATE,
-            sickness_period_in_days: DISEASE_PERIOD,
-            incubation_period_in_days: INCUBATION_PERIOD,
-            immunity_waning_period_in_days: IMMUNITY_WANING_TIME_IN_DAYS,
-            mortality_rate: MORTALITY_RATE,
-            r_naught: R_NAUGHT * (1.0 + relative_change),
-            hospitalization_rate: HOSPITALIZATION_RATE,
-            max_hospital_capacity: MAX_HOSPITAL_CAPACITY,
-            traffic_rate: TRAFFIC_RATE,
-            measures: vec![
-                //Box::from(hand_washing),
-                //Box::from(social_distancing),
-                //Box::from(soft_lock_down),
-                //Box::from(hard_lock_down),
-            ]
-        };
+    Ok(())
+}
 
-        // Set up initial values for the system. repating_before are for when DDE's are used.
-        let t0 : Vec<InitialValue> = vec![
-            InitialValue { value: (parameters.initial_population - parameters.initial_spreaders) as f32, repeating_before: 0.0 }, //Susceptible people
-            InitialValue { value: parameters.initial_spreaders as f32, repeating_before: 0.0 }, //Exposed people
-            InitialValue { value: 0.0 as f32, repeating_before: 0.0 }, //Infected people
-            InitialValue { value: 0.0, repeating_before: 0.0 }, //Recovered people
-            InitialValue { value: 0.0, repeating_before: 0.0 }, //Dead people
-            InitialValue { value: parameters.initial_population as f32, repeating_before: parameters.initial_population as f32}, // Population
-            InitialValue {value: 0.0, repeating_before: 0.0}, // Hospitalizations
-        ];
-
-        // Store initial parameters for incubation period days. I.e. delay between what happened and what can be measured.
-        let initial_zero_values = ((parameters.incubation_period_in_days as f32 / step_size) as usize) + 1;
-        let mut province_results = vec![t0.iter().map(|i| i.repeating_before).collect(); initial_zero_values];
-        province_results.push(t0.iter().map(|i| i.value).collect());
-
-        results.push(province_results);
-        province_parameters.push(parameters);
-        initial_values.push(t0);
+/// Executes the calibrate command: fits the scenario to the observed prevalence, then writes the fit,
+/// the fitted scenario and the results of simulating it.
+fn calibrate(options: &Options, scenario_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (scenario, graph) = load_scenario(options, Some(scenario_path))?;
+    let settings = scenario.calibration.clone().unwrap_or_default();
+    let prevalence = ObservedData::load_prevalence(&format!("{}/COVID-19_prevalentie.json", options.observations))?;
+    let observations = if settings.sewage_weight > 0.0 { load_observations(&options.observations) } else { Observations::default() };
+
+    println!("Calibration in progress...");
+    let mut calibration = Calibration::new(&graph, &scenario, &settings, &prevalence);
+    if let Some(sewage) = &observations.sewage {
+        calibration = calibration.with_sewage(sewage);
     }
+    let (calibration, fitted) = calibration.run()?;
 
-    println!("Simulation in progress...");
+    println!("Calibration {} after {} evaluations, objective {:.4}:", if calibration.converged { "converged" } else { "stopped" }, calibration.evaluations, calibration.objective_value);
+    for fitted_value in &calibration.parameters {
+        println!("  {:?}: {:.3}", fitted_value.parameter, fitted_value.value);
+    }
+    println!("  Start date: {}", calibration.start_date);
+    println!("RMSE {:.0}, R2 {:.3}, {:.0}% of {} observed days within the interval",
+             calibration.rmse, calibration.r_squared, calibration.interval_coverage * 100.0, calibration.observations);
+    if let Some(sewage_log_rmse) = calibration.sewage_log_rmse {
+        println!("Sewage log RMSE {:.3} over {} measurements", sewage_log_rmse, calibration.sewage_observations);
+    }
 
-    // Execute iterations
-    let iterations = f32::floor(TIMESPAN_IN_DAYS as f32 / step_size) as usize;
-    for i in 0..iterations-1 {
-        // Simulate all provinces for this iteration.
-        for province_idx in 0..province_parameters.len() {
-            // Determine last and previous data.
-            let (pr, last) = results[province_idx].split_at(results[province_idx].len() - 1);
-            // Compute new step's values for this province
-            let new_step = rk4_impl(last.first().unwrap(), pr,i as f32 * step_size, step_size, &province_parameters[province_idx], rate_of_change_with_time);
-            results[province_idx].push(new_step);
-        }
+    std::fs::create_dir_all(&options.output)?;
+    save_file(&format!("{}/calibration.json", options.output), &calibration)?;
+    save_file(&format!("{}/scenario.json", options.output), &fitted)?;
 
-        // This part is responsible for computing traffic between provinces.
-        if ENABLE_TRAFFIC {
-
-            // Effectively turns a few susceptible people in other provinces into exposed.
-            for province_idx in 0..province_parameters.len() {
-                let connected_count = graph[province_idx].connected_provinces.len();
-                let province_e = results[province_idx].last().unwrap()[1];
-                let delta_e = (province_parameters[province_idx].traffic_rate * province_e * step_size);
-
-                // Spread out infected cases over new provinces. Simulates effect of 'travelling'.
-                for idx in 0..connected_count {
-                    let connected_idx = graph[province_idx].connected_provinces[idx];
-                    if results[connected_idx].last_mut().unwrap()[0] > (delta_e / connected_count as f32) {
-                        results[connected_idx].last_mut().unwrap()[0] -= (delta_e / connected_count as f32);
-                        results[connected_idx].last_mut().unwrap()[1] += (delta_e / connected_count as f32);
-                    }
-                }
-            }
-        }
+    let results = Simulation::new(&graph, &fitted).run();
+    save_file(&format!("{}/results.json", options.output), &results)?;
+    plot_results(options, &results_at_level(options, results)?)
+}
+
+/// Executes the infer command: samples the posterior of the scenario values, then writes its summary,
+/// a trace file per chain and the posterior-predictive bands.
+fn infer(options: &Options, scenario_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (scenario, graph) = load_scenario(options, Some(scenario_path))?;
+    let mut settings = scenario.inference.clone().unwrap_or_default();
+    settings.seed = options.seed.unwrap_or(settings.seed);
+    let observations = load_observations(&options.observations);
+    let prevalence = observations.prevalence.as_ref().ok_or("Inference needs the observed prevalence")?;
+
+    println!("Sampling {} chains of {} samples after {} burn-in iterations...", settings.chains, settings.samples, settings.burn_in);
+    let (inference, chains) = Inference::new(&graph, &scenario, &settings, prevalence, observations.reproduction_number.as_ref()).run()?;
+
+    let rates: Vec<String> = inference.acceptance_rates.iter().map(|r| format!("{:.0}%", r * 100.0)).collect();
+    println!("Acceptance rates: {}", rates.join(", "));
+    for summary in &inference.parameters {
+        let interval = match (summary.quantiles.first(), summary.quantiles.last()) {
+            (Some(lower), Some(upper)) => format!(", {:.0}% - {:.0}%: {:.3} - {:.3}", lower.quantile * 100.0, upper.quantile * 100.0, lower.value, upper.value),
+            _ => String::new()
+        };
+        println!("  {:?}: mean {:.3}, sd {:.3}{}, R-hat {:.3}, ESS {:.0}",
+                 summary.parameter, summary.mean, summary.standard_deviation, interval, summary.r_hat, summary.effective_sample_size);
+    }
+    if inference.parameters.iter().any(|s| s.r_hat > 1.1) {
+        println!("Warning: R-hat above 1.1, the chains have not converged");
+    }
+
+    std::fs::create_dir_all(&options.output)?;
+    save_file(&format!("{}/inference.json", options.output), &inference)?;
+    let parameters: Vec<CalibratedParameter> = settings.parameters.iter().map(|p| p.parameter).collect();
+    for (idx, chain) in chains.iter().enumerate() {
+        std::fs::write(format!("{}/trace_{}.csv", options.output, idx), chain.to_csv(&parameters))?;
     }
+    plot::draw_posterior(&options.output, &inference, &observations)
+}
 
-    println!("Simulation done. Generating graphs...");
+/// Executes the assimilate command: steps an ensemble through the scenario and updates it with every observation,
+/// then writes the ensemble quantiles, the updates and the final ensemble to continue from.
+fn assimilate(options: &Options, scenario_path: &str, resume: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let (scenario, graph) = load_scenario(options, Some(scenario_path))?;
+    let mut settings = scenario.assimilation.clone().unwrap_or_default();
+    settings.seed = options.seed.unwrap_or(settings.seed);
+    let observations = load_observations(&options.observations);
+    let state = match resume {
+        Some(path) => Some(load::<EnsembleState>(path)?),
+        None => None
+    };
 
-    // Show results
-    for province_idx in 0..province_parameters.len() {
-        let initial_zero_values = ((province_parameters[province_idx].sickness_period_in_days as f32 / step_size) as usize) + 1;
-        results[province_idx] = results[province_idx].split_at(initial_zero_values).1.to_vec();
+    println!("Assimilating with {} members...", state.as_ref().map(|s| s.members.len()).unwrap_or(settings.members));
+    let (assimilation, ensemble) = Assimilation::new(&graph, &scenario, &settings, &observations).run(state.as_ref())?;
 
-        draw(&graph[province_idx].name, &initial_values[province_idx], &province_parameters[province_idx], &results[province_idx], step_size)?;
+    let count = |kind: ObservationKind| assimilation.updates.iter().filter(|u| u.observation == kind).count();
+    println!("Updated with {} prevalence and {} sewage observations", count(ObservationKind::Prevalence), count(ObservationKind::Sewage));
+    if let Some(last) = assimilation.updates.last() {
+        println!("Last update on {}: {:?} observed {:.0}, forecast {:.0}, analysis {:.0}", last.date, last.observation, last.observed, last.forecast, last.analysis);
     }
 
-    Ok(())
+    std::fs::create_dir_all(&options.output)?;
+    save_file(&format!("{}/assimilation.json", options.output), &assimilation)?;
+    save_file(&format!("{}/assimilation_state.json", options.output), &ensemble)?;
+    plot::draw_assimilation(&options.output, &assimilation, &observations)
 }
 
-// This function is responsible for plotting the data onto a 2D graph.
-fn draw(output_file_name: &str, t0: &Vec<InitialValue>, parameters: &SimulationParameters, rk4_results: &Vec<Vec<f32>>, step_size: f32) -> Result<(), Box<dyn std::error::Error>> {
-    
-    let max_pop : f32 = rk4_results.iter().map(|v| NonNanF32(v[1])).max().unwrap().0;
-    
-    let var = String::from(String::from("./output/") + output_file_name) + ".png";
-    let mut backend = BitMapBackend::new(&var, (600,600));
-    let mut drawing_area = backend.into_drawing_area();
-
-    drawing_area.fill(&WHITE);
-    drawing_area = drawing_area.margin(50,50,50,50);
-
-    let mut chart = ChartBuilder::on(&drawing_area)
-        .caption(&format!("SEIRDS - R0: {:.1} - Recovery in days: {:.1} - Mortality: {:.2}", parameters.r_naught, parameters.sickness_period_in_days, parameters.mortality_rate), ("sans-serif", 20).into_font())
-        .set_left_and_bottom_label_area_size(20)
-        .right_y_label_area_size(0)
-        .margin(0)
-        .build_cartesian_2d(0f32..parameters.time_span_in_days as f32, 0f32..(max_pop + 0.1 * max_pop))?;
-
-    // Then we can draw a mesh
-    chart
-        .configure_mesh()
-        // We can customize the maximum number of labels allowed for each axis
-        .x_labels(5)
-        .y_labels(5)
-        // We can also change the format of the label text
-        .x_label_formatter(&|x| format!("{:.0}", x))
-        .y_label_formatter(&|x| format!("{:.0}", x))
-        .draw()?;
-
-    let colors = [&ORANGE, &MAGENTA, &RED, &GREEN, &BLACK, &BLUE, &CYAN];
-    let labels = ["Susceptible", "Exposed", "Infected", "Recovered", "Deaths", "Population", "Hospitalizations"];
-    for idx in 0..t0.len() {
-
-        let mut points : Vec<(f32, f32)> = generate_range_from_input(rk4_results.len(), step_size).into_iter().enumerate().map(|(i, c)| (c, rk4_results[i][idx])).collect();
-
-        chart.draw_series(LineSeries::new(points, colors[idx]))?
-            .label(labels[idx])
-            .legend( move |(x, y)|
-                    PathElement::new(vec![(x, y), (x + 20, y)], colors[idx])
-            );
+/// Executes the export command.
+fn export(options: &Options, results_path: &str, format: ExportFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let results = results_at_level(options, load::<SimulationResults>(results_path)?)?;
+    std::fs::create_dir_all(&options.output)?;
+    for province in &results.provinces {
+        match format {
+            ExportFormat::Csv => {
+                std::fs::write(format!("{}/{}.csv", options.output, province.name), to_csv(&results.times, &province.values))?;
+                for group in &province.age_groups {
+                    std::fs::write(format!("{}/{}_{}.csv", options.output, province.name, group.name), to_csv(&results.times, &group.values))?;
+                }
+                if !province.measure_events.is_empty() {
+                    std::fs::write(format!("{}/{}_measures.csv", options.output, province.name), measure_events_to_csv(&province.measure_events))?;
+                }
+            },
+            ExportFormat::Json => save_file(&format!("{}/{}.json", options.output, province.name), province)?
+        }
     }
-    chart
-        .configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
-        .draw()?;
     Ok(())
 }
+
+/// Executes the validate command. Reports the first error in the dataset, and every problem which does not prevent simulating it.
+fn validate(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let graph = build_graph(options, None)?;
+    println!("{}: {} regions at {} level, no errors found", options.dataset, graph.len(), graph.level());
+    Ok(())
+}
+
+/// main function of the program.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(v) => v,
+        Err(e) => { println!("{}\n\n{}", e, cli::USAGE); return Err(e.into()) }
+    };
+
+    match &options.command {
+        Command::Run { scenario } => run(&options, scenario.as_deref()),
+        Command::Plot { results } => plot_results(&options, &results_at_level(&options, load::<SimulationResults>(results)?)?),
+        Command::Calibrate { scenario } => calibrate(&options, scenario),
+        Command::Infer { scenario } => infer(&options, scenario),
+        Command::Assimilate { scenario, resume } => assimilate(&options, scenario, resume.as_deref()),
+        Command::Export { results, format } => export(&options, results, *format),
+        Command::Validate => validate(&options),
+        Command::Help => { println!("{}", cli::USAGE); Ok(()) }
+    }
+}
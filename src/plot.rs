@@ -0,0 +1,450 @@
+use crate::*;
+use chrono::{Duration, NaiveDate};
+use plotters::prelude::*;
+
+// This function is responsible for plotting the data onto a 2D graph.
+pub fn draw(output_directory: &str, province: &ProvinceResults, time_span_in_days: usize, times: &[f32]) -> Result<(), Box<dyn std::error::Error>> {
+    let rk4_results = &province.values;
+
+    let max_pop : f32 = rk4_results.iter().map(|v| NonNanF32(v.exposed)).max().unwrap().0;
+    
+    let var = format!("{}/{}.png", output_directory, province.name);
+    let backend = BitMapBackend::new(&var, (600,600));
+    let mut drawing_area = backend.into_drawing_area();
+
+    drawing_area.fill(&WHITE)?;
+    drawing_area = drawing_area.margin(50,50,50,50);
+
+    let mut chart = ChartBuilder::on(&drawing_area)
+        .caption(format!("SEIRDS - R0: {:.1} - Recovery in days: {:.1} - Mortality: {:.2}", province.r_naught, province.sickness_period_in_days, province.mortality_rate), ("sans-serif", 20).into_font())
+        .set_left_and_bottom_label_area_size(20)
+        .right_y_label_area_size(0)
+        .margin(0)
+        .build_cartesian_2d(0f32..time_span_in_days as f32, 0f32..(max_pop + 0.1 * max_pop))?;
+
+    // Then we can draw a mesh
+    chart
+        .configure_mesh()
+        // We can customize the maximum number of labels allowed for each axis
+        .x_labels(5)
+        .y_labels(5)
+        // We can also change the format of the label text
+        .x_label_formatter(&|x| format!("{:.0}", x))
+        .y_label_formatter(&|x| format!("{:.0}", x))
+        .draw()?;
+
+    for compartment in Compartment::ALL.iter() {
+
+        let points : Vec<(f32, f32)> = times.iter().zip(rk4_results).map(|(t, v)| (*t, v[*compartment])).collect();
+        let color = compartment.color();
+
+        chart.draw_series(LineSeries::new(points, color))?
+            .label(compartment.label())
+            .legend( move |(x, y)|
+                    PathElement::new(vec![(x, y), (x + 20, y)], color)
+            );
+    }
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
+
+// Plots the quantile bands of infected people over all realizations of a stochastic run, next to the deterministic run.
+pub fn draw_ensemble(output_directory: &str, ensemble: &ProvinceEnsembleResults, deterministic: &ProvinceResults, time_span_in_days: usize, times: &[f32]) -> Result<(), Box<dyn std::error::Error>> {
+    let max_infected = ensemble.quantiles.iter()
+        .flat_map(|q| q.values.iter())
+        .chain(deterministic.values.iter())
+        .map(|v| NonNanF32(v.infected))
+        .max()
+        .unwrap().0;
+
+    let var = format!("{}/{}_ensemble.png", output_directory, ensemble.name);
+    let backend = BitMapBackend::new(&var, (600,600));
+    let mut drawing_area = backend.into_drawing_area();
+
+    drawing_area.fill(&WHITE)?;
+    drawing_area = drawing_area.margin(50,50,50,50);
+
+    let mut chart = ChartBuilder::on(&drawing_area)
+        .caption(format!("Infected - Outbreak probability: {:.0}%", ensemble.outbreak_probability * 100.0), ("sans-serif", 20).into_font())
+        .set_left_and_bottom_label_area_size(20)
+        .right_y_label_area_size(0)
+        .margin(0)
+        .build_cartesian_2d(0f32..time_span_in_days as f32, 0f32..(max_infected + 0.1 * max_infected).max(1.0))?;
+
+    chart
+        .configure_mesh()
+        .x_labels(5)
+        .y_labels(5)
+        .x_label_formatter(&|x| format!("{:.0}", x))
+        .y_label_formatter(&|x| format!("{:.0}", x))
+        .draw()?;
+
+    // Bands between matching lower and upper quantiles, the outer bands being the lightest.
+    let count = ensemble.quantiles.len();
+    for idx in 0..count / 2 {
+        let (lower, upper) = (&ensemble.quantiles[idx], &ensemble.quantiles[count - 1 - idx]);
+        let mut points: Vec<(f32, f32)> = times.iter().zip(&upper.values).map(|(t, v)| (*t, v.infected)).collect();
+        points.extend(times.iter().zip(&lower.values).rev().map(|(t, v)| (*t, v.infected)));
+        let style = RED.mix(0.15 * (idx + 1) as f64).filled();
+        chart.draw_series(std::iter::once(Polygon::new(points, style)))?
+            .label(format!("{:.0}% - {:.0}%", lower.quantile * 100.0, upper.quantile * 100.0))
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+    }
+    if count % 2 == 1 {
+        let middle = &ensemble.quantiles[count / 2];
+        chart.draw_series(LineSeries::new(times.iter().zip(&middle.values).map(|(t, v)| (*t, v.infected)), RED))?
+            .label(format!("{:.0}%", middle.quantile * 100.0))
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+    }
+
+    chart.draw_series(LineSeries::new(times.iter().zip(&deterministic.values).map(|(t, v)| (*t, v.infected)), BLACK))?
+        .label("Deterministic")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLACK));
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
+
+// Model values at every whole day, with the date of that day. Steps within a day are skipped.
+fn daily_points(start_date: NaiveDate, times: &[f32], values: impl Iterator<Item = Option<f32>>) -> Vec<(NaiveDate, f32)> {
+    times.iter().zip(values)
+        .filter(|(t, _)| t.fract() == 0.0)
+        .filter_map(|(t, v)| v.map(|v| (start_date + Duration::days(*t as i64), v)))
+        .collect()
+}
+
+// Polygon around the uncertainty intervals of observed estimates.
+fn band(estimates: &DailySeries<Estimate>) -> Vec<(NaiveDate, f32)> {
+    let mut points: Vec<(NaiveDate, f32)> = estimates.dated().map(|(d, e)| (d, e.high)).collect();
+    let mut lower: Vec<(NaiveDate, f32)> = estimates.dated().map(|(d, e)| (d, e.low)).collect();
+    lower.reverse();
+    points.extend(lower);
+    points
+}
+
+// Plots the simulated infected people and reproduction number of the whole country on calendar dates,
+// over the observed prevalence and reproduction number with their uncertainty. Measured sewage RNA and the RNA the
+// sewage model expects from the simulation are on a secondary axis.
+pub fn draw_observed(output_directory: &str, results: &SimulationResults, start_date: NaiveDate, observations: &Observations, sewage_model: &SewageModel) -> Result<(), Box<dyn std::error::Error>> {
+    let values: Vec<Compartments> = (0..results.times.len())
+        .map(|step| results.provinces.iter().map(|p| p.values[step]).sum())
+        .collect();
+    let infected = daily_points(start_date, &results.times, values.iter().map(|v| Some(v.infected)));
+    let r = daily_points(start_date, &results.times, results.national_reproduction_number().into_iter());
+    let rna = daily_points(start_date, &results.times, sewage_model.national_rna(results).into_iter().map(Some));
+    let end_date = start_date + Duration::days(results.time_span_in_days as i64);
+
+    let var = format!("{}/observed.png", output_directory);
+    let backend = BitMapBackend::new(&var, (900, 1000));
+    let drawing_area = backend.into_drawing_area();
+    drawing_area.fill(&WHITE)?;
+    let areas = drawing_area.margin(30, 30, 30, 30).split_evenly((2, 1));
+
+    // Prevalence and sewage.
+    let max_infected = infected.iter().map(|(_, v)| *v)
+        .chain(observations.prevalence.iter().flat_map(|p| p.estimates.dated().map(|(_, e)| e.high)))
+        .fold(1.0, f32::max);
+    let max_rna = observations.sewage.iter().flat_map(|s| s.dated().map(|(_, v)| v))
+        .chain(rna.iter().map(|(_, v)| *v))
+        .fold(1.0, f32::max);
+
+    let mut chart = ChartBuilder::on(&areas[0])
+        .caption("Infected - observed prevalence and sewage", ("sans-serif", 20).into_font())
+        .set_left_and_bottom_label_area_size(50)
+        .right_y_label_area_size(50)
+        .margin(10)
+        .build_cartesian_2d(start_date..end_date, 0f32..max_infected * 1.1)?
+        .set_secondary_coord(start_date..end_date, 0f32..max_rna * 1.1);
+
+    chart
+        .configure_mesh()
+        .x_labels(6)
+        .y_labels(5)
+        .x_label_formatter(&|d| d.format("%d-%m-%Y").to_string())
+        .y_label_formatter(&|y| format!("{:.0}", y))
+        .draw()?;
+    chart
+        .configure_secondary_axes()
+        .y_desc("RNA per ml")
+        .y_label_formatter(&|y| format!("{:.0}", y))
+        .draw()?;
+
+    if let Some(prevalence) = &observations.prevalence {
+        let style = BLUE.mix(0.2).filled();
+        chart.draw_series(std::iter::once(Polygon::new(band(&prevalence.estimates), style)))?
+            .label("Observed prevalence")
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+        chart.draw_series(LineSeries::new(prevalence.estimates.dated().map(|(d, e)| (d, e.central())), BLUE))?;
+    }
+    chart.draw_series(LineSeries::new(infected, RED))?
+        .label("Simulated infected")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+    if let Some(sewage) = &observations.sewage {
+        chart.draw_secondary_series(LineSeries::new(sewage.dated(), GREEN))?
+            .label("Sewage RNA per ml")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], GREEN));
+    }
+    chart.draw_secondary_series(LineSeries::new(rna, MAGENTA))?
+        .label("Expected RNA per ml")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], MAGENTA));
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    // Reproduction number.
+    let max_r = r.iter().map(|(_, v)| *v)
+        .chain(observations.reproduction_number.iter().flat_map(|r| r.estimates.dated().map(|(_, e)| e.high)))
+        .fold(2.0, f32::max)
+        .min(10.0);
+
+    let mut chart = ChartBuilder::on(&areas[1])
+        .caption("Reproduction number", ("sans-serif", 20).into_font())
+        .set_left_and_bottom_label_area_size(50)
+        .right_y_label_area_size(50)
+        .margin(10)
+        .build_cartesian_2d(start_date..end_date, 0f32..max_r * 1.1)?;
+
+    chart
+        .configure_mesh()
+        .x_labels(6)
+        .y_labels(5)
+        .x_label_formatter(&|d| d.format("%d-%m-%Y").to_string())
+        .y_label_formatter(&|y| format!("{:.1}", y))
+        .draw()?;
+
+    if let Some(observed) = &observations.reproduction_number {
+        let style = BLUE.mix(0.2).filled();
+        chart.draw_series(std::iter::once(Polygon::new(band(&observed.estimates), style)))?
+            .label("Observed Rt")
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+        chart.draw_series(LineSeries::new(observed.estimates.dated().map(|(d, e)| (d, e.central())), BLUE))?;
+    }
+    chart.draw_series(LineSeries::new(vec![(start_date, 1.0), (end_date, 1.0)], BLACK.mix(0.5)))?;
+    chart.draw_series(LineSeries::new(r, RED))?
+        .label("Simulated Rt")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
+
+// Plots the RNA per ml the sewage model expects from the simulated region with the interval of the measurement noise,
+// against the sewage measured in it.
+pub fn draw_sewage(output_directory: &str, region: &ProvinceResults, times: &[f32], start_date: NaiveDate, model: &SewageModel, measured: &DailySeries<f32>) -> Result<(), Box<dyn std::error::Error>> {
+    let expected = daily_points(start_date, times, model.expected_rna(times, &region.values, region.incubation_period_in_days).into_iter().map(Some));
+    let end_date = start_date + Duration::days(times.last().copied().unwrap_or(0.0).ceil() as i64);
+    let measured: Vec<(NaiveDate, f32)> = measured.dated().filter(|(d, _)| *d >= start_date && *d <= end_date).collect();
+
+    let mut interval: Vec<(NaiveDate, f32)> = expected.iter().map(|(d, v)| (*d, model.interval(*v).1)).collect();
+    interval.extend(expected.iter().rev().map(|(d, v)| (*d, model.interval(*v).0)));
+    let max_rna = measured.iter().chain(&interval).map(|(_, v)| *v).fold(1.0, f32::max);
+
+    let var = format!("{}/{}_sewage.png", output_directory, region.name);
+    let backend = BitMapBackend::new(&var, (900, 500));
+    let drawing_area = backend.into_drawing_area();
+    drawing_area.fill(&WHITE)?;
+    let drawing_area = drawing_area.margin(30, 30, 30, 30);
+
+    let mut chart = ChartBuilder::on(&drawing_area)
+        .caption(format!("{} - expected and measured RNA per ml", region.name), ("sans-serif", 20).into_font())
+        .set_left_and_bottom_label_area_size(50)
+        .margin(10)
+        .build_cartesian_2d(start_date..end_date, 0f32..max_rna * 1.1)?;
+
+    chart
+        .configure_mesh()
+        .x_labels(6)
+        .y_labels(5)
+        .x_label_formatter(&|d| d.format("%d-%m-%Y").to_string())
+        .y_label_formatter(&|y| format!("{:.0}", y))
+        .draw()?;
+
+    let style = MAGENTA.mix(0.2).filled();
+    chart.draw_series(std::iter::once(Polygon::new(interval, style)))?
+        .label("95% of measurements")
+        .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+    chart.draw_series(LineSeries::new(expected, MAGENTA))?
+        .label("Expected RNA per ml")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], MAGENTA));
+    chart.draw_series(measured.iter().map(|p| Circle::new(*p, 2, GREEN.filled())))?
+        .label("Measured RNA per ml")
+        .legend(|(x, y)| Circle::new((x + 10, y), 2, GREEN.filled()));
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
+
+// Plots the posterior-predictive bands of infected people and the reproduction number of the whole country,
+// over the observed prevalence and reproduction number with their uncertainty.
+pub fn draw_posterior(output_directory: &str, results: &InferenceResults, observations: &Observations) -> Result<(), Box<dyn std::error::Error>> {
+    let start_date = results.start_date;
+    let days = results.predictive.first().map(|b| b.infected.len()).unwrap_or(1).max(2) - 1;
+    let end_date = start_date + Duration::days(days as i64);
+    let date = |day: usize| start_date + Duration::days(day as i64);
+    let infected: Vec<Vec<(NaiveDate, f32)>> = results.predictive.iter()
+        .map(|b| b.infected.iter().enumerate().map(|(day, v)| (date(day), *v)).collect())
+        .collect();
+    let r: Vec<Vec<(NaiveDate, f32)>> = results.predictive.iter()
+        .map(|b| b.reproduction_number.iter().enumerate().filter_map(|(day, v)| v.map(|v| (date(day), v))).collect())
+        .collect();
+
+    let var = format!("{}/posterior.png", output_directory);
+    let backend = BitMapBackend::new(&var, (900, 1000));
+    let drawing_area = backend.into_drawing_area();
+    drawing_area.fill(&WHITE)?;
+    let areas = drawing_area.margin(30, 30, 30, 30).split_evenly((2, 1));
+
+    let panels = [
+        ("Infected - posterior predictive and observed prevalence", &infected, &observations.prevalence),
+        ("Reproduction number - posterior predictive and observed", &r, &observations.reproduction_number)
+    ];
+    for (area, (caption, predictive, observed)) in areas.iter().zip(panels.iter()) {
+        let max_value = predictive.iter().flatten().map(|(_, v)| *v)
+            .chain(observed.iter().flat_map(|o| o.estimates.dated().map(|(_, e)| e.high)))
+            .fold(1.0, f32::max);
+
+        let mut chart = ChartBuilder::on(area)
+            .caption(*caption, ("sans-serif", 20).into_font())
+            .set_left_and_bottom_label_area_size(50)
+            .margin(10)
+            .build_cartesian_2d(start_date..end_date, 0f32..max_value * 1.1)?;
+
+        chart
+            .configure_mesh()
+            .x_labels(6)
+            .y_labels(5)
+            .x_label_formatter(&|d| d.format("%d-%m-%Y").to_string())
+            .y_label_formatter(&|y| if max_value < 10.0 { format!("{:.1}", y) } else { format!("{:.0}", y) })
+            .draw()?;
+
+        if let Some(observed) = observed {
+            let style = BLUE.mix(0.2).filled();
+            chart.draw_series(std::iter::once(Polygon::new(band(&observed.estimates), style)))?
+                .label("Observed")
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+            chart.draw_series(LineSeries::new(observed.estimates.dated().map(|(d, e)| (d, e.central())), BLUE))?;
+        }
+
+        // Bands between matching lower and upper quantiles, the outer bands being the lightest.
+        let count = results.predictive.len();
+        for idx in 0..count / 2 {
+            let (lower, upper) = (&results.predictive[idx], &results.predictive[count - 1 - idx]);
+            let mut points = predictive[count - 1 - idx].clone();
+            points.extend(predictive[idx].iter().rev());
+            let style = RED.mix(0.15 * (idx + 1) as f64).filled();
+            chart.draw_series(std::iter::once(Polygon::new(points, style)))?
+                .label(format!("{:.0}% - {:.0}%", lower.quantile * 100.0, upper.quantile * 100.0))
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+        }
+        if count % 2 == 1 {
+            chart.draw_series(LineSeries::new(predictive[count / 2].clone(), RED))?
+                .label(format!("{:.0}%", results.predictive[count / 2].quantile * 100.0))
+                .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+        }
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+    }
+    Ok(())
+}
+
+// Plots the ensemble of every assimilated quantity of the whole country, over what was observed.
+pub fn draw_assimilation(output_directory: &str, results: &AssimilationResults, observations: &Observations) -> Result<(), Box<dyn std::error::Error>> {
+    let date = |t: f32| results.start_date + Duration::days(t as i64);
+    let (start_date, end_date) = match (results.times.first(), results.times.last()) {
+        (Some(first), Some(last)) => (date(*first), date(last.max(first + 1.0))),
+        _ => return Ok(())
+    };
+
+    let var = format!("{}/assimilation.png", output_directory);
+    let backend = BitMapBackend::new(&var, (900, 500 * results.observables.len().max(1) as u32));
+    let drawing_area = backend.into_drawing_area();
+    drawing_area.fill(&WHITE)?;
+    let areas = drawing_area.margin(30, 30, 30, 30).split_evenly((results.observables.len().max(1), 1));
+
+    for (area, observable) in areas.iter().zip(&results.observables) {
+        let series: Vec<Vec<(NaiveDate, f32)>> = observable.quantiles.iter()
+            .map(|q| results.times.iter().zip(&q.values).map(|(t, v)| (date(*t), *v)).collect())
+            .collect();
+        // Only the observations within the plotted days.
+        let within = |d: &NaiveDate| *d >= start_date && *d <= end_date;
+        let (caption, observed): (&str, Vec<(NaiveDate, f32, f32, f32)>) = match observable.observation {
+            ObservationKind::Prevalence => ("Infected - ensemble and observed prevalence", observations.prevalence.iter()
+                .flat_map(|p| p.estimates.dated().filter(|(d, _)| within(d)).map(|(d, e)| (d, e.low, e.central(), e.high)))
+                .collect()),
+            ObservationKind::Sewage => ("RNA per ml - ensemble and observed sewage", observations.sewage.iter()
+                .flat_map(|s| s.dated().filter(|(d, _)| within(d)).map(|(d, v)| (d, v, v, v)))
+                .collect())
+        };
+        let max_value = series.iter().flatten().map(|(_, v)| *v)
+            .chain(observed.iter().map(|o| o.3))
+            .fold(1.0, f32::max);
+
+        let mut chart = ChartBuilder::on(area)
+            .caption(caption, ("sans-serif", 20).into_font())
+            .set_left_and_bottom_label_area_size(50)
+            .margin(10)
+            .build_cartesian_2d(start_date..end_date, 0f32..max_value * 1.1)?;
+
+        chart
+            .configure_mesh()
+            .x_labels(6)
+            .y_labels(5)
+            .x_label_formatter(&|d| d.format("%d-%m-%Y").to_string())
+            .y_label_formatter(&|y| format!("{:.0}", y))
+            .draw()?;
+
+        if observed.iter().any(|o| o.1 != o.3) {
+            let mut points: Vec<(NaiveDate, f32)> = observed.iter().map(|o| (o.0, o.3)).collect();
+            points.extend(observed.iter().rev().map(|o| (o.0, o.1)));
+            let style = BLUE.mix(0.2).filled();
+            chart.draw_series(std::iter::once(Polygon::new(points, style)))?
+                .label("Observed interval")
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+        }
+        chart.draw_series(LineSeries::new(observed.iter().map(|o| (o.0, o.2)), BLUE))?
+            .label("Observed")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLUE));
+
+        // Bands between matching lower and upper quantiles, the outer bands being the lightest.
+        let count = observable.quantiles.len();
+        for idx in 0..count / 2 {
+            let (lower, upper) = (&observable.quantiles[idx], &observable.quantiles[count - 1 - idx]);
+            let mut points = series[count - 1 - idx].clone();
+            points.extend(series[idx].iter().rev());
+            let style = RED.mix(0.15 * (idx + 1) as f64).filled();
+            chart.draw_series(std::iter::once(Polygon::new(points, style)))?
+                .label(format!("{:.0}% - {:.0}%", lower.quantile * 100.0, upper.quantile * 100.0))
+                .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], style));
+        }
+        if count % 2 == 1 {
+            chart.draw_series(LineSeries::new(series[count / 2].clone(), RED))?
+                .label(format!("{:.0}%", observable.quantiles[count / 2].quantile * 100.0))
+                .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+        }
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,535 @@
+use crate::*;
+use crate::dde::*;
+use crate::integrators::*;
+use std::cell::Cell;
+use std::sync::Arc;
+
+/// Rate at which a susceptible person of every age group gets infected, including the reduction by measures.
+pub(crate) fn forces_of_infection(sp: &SimulationParameters, previous: &[Compartments], measures_change: f32) -> Vec<f32> {
+    let recovery_rate = 1.0 / (sp.sickness_period_in_days as f32);
+    let base_infection_rate = sp.r_naught * recovery_rate; // Change s to e
+    let infection_rate = base_infection_rate * (1.0 - measures_change);
+
+    // Fraction of infected people met by each age group, weighed by the contacts with every age group.
+    sp.contact_matrix.iter().map(|row| {
+        let infected_contacts: f32 = row.iter()
+            .zip(previous)
+            .map(|(contacts, other)| if other.population > 0.0 { contacts * other.infected / other.population } else { 0.0 })
+            .sum();
+        infection_rate * infected_contacts
+    }).collect()
+}
+
+fn rate_of_change_with_time(sp: &SimulationParameters, previous: &[Compartments], doses: &[DoseRates], measures_change: f32) -> Vec<Compartments> {
+    let forces = forces_of_infection(sp, previous, measures_change);
+
+    let recovery_rate = 1.0 / (sp.sickness_period_in_days as f32); // Change of i to r
+    let incubation_rate = 1.0 / (sp.incubation_period_in_days as f32); // Change of e to i
+    let immunity_waning_rate = 1.0 / (sp.immunity_waning_period_in_days as f32); // Change r to s
+    let Protection { partial, full, two_dose, waning_rate: vaccine_waning_rate } = Protection::of(sp.vaccine.as_ref());
+
+    previous.iter().enumerate().map(|(group_idx, group)| {
+        let Compartments { susceptible, exposed, infected, recovered, population, vaccinated_partially, vaccinated_fully, protected_exposed, protected_infected, .. } = *group;
+        let mortality_rate = sp.age_groups[group_idx].mortality_rate;
+        let hospitalization_rate = sp.age_groups[group_idx].hospitalization_rate;
+        let group_doses = doses.get(group_idx).copied().unwrap_or_default();
+        let force = forces[group_idx];
+
+        // New infections of unvaccinated and vaccinated people. Vaccinated infections are protected against severe disease.
+        let infections = force * susceptible;
+        let partial_infections = force * (1.0 - partial.efficacy_against_infection) * vaccinated_partially;
+        let full_infections = force * (1.0 - full.efficacy_against_infection) * vaccinated_fully;
+        let protected_infections = partial_infections * partial.efficacy_against_severe_disease + full_infections * full.efficacy_against_severe_disease;
+
+        // First doses lead to full vaccination directly for a single dose vaccine.
+        let (to_partial, to_full) = if two_dose { (group_doses.first, group_doses.second) } else { (0.0, group_doses.first) };
+
+        // Only unprotected infected people can die or end up in hospital.
+        let severe_infected = infected - protected_infected;
+        let change_infected = (incubation_rate * exposed) - (recovery_rate * infected) - (sp.natural_death_rate * infected);
+        let change_protected_infected = (incubation_rate * protected_exposed) - (recovery_rate * protected_infected) - (sp.natural_death_rate * protected_infected);
+
+        // Compute the dy/dx for all differential equations in the system. See the report for the definition and explanation.
+        Compartments {
+            susceptible: sp.natural_birth_rate * population - infections - (sp.natural_death_rate * susceptible) + (immunity_waning_rate * recovered)
+                - group_doses.first + vaccine_waning_rate * (vaccinated_partially + vaccinated_fully),
+            exposed: infections + partial_infections + full_infections - incubation_rate * exposed - (sp.natural_death_rate * exposed),
+            infected: change_infected,
+            recovered: (recovery_rate * infected) - (recovery_rate * severe_infected) * mortality_rate - sp.natural_death_rate * recovered - (immunity_waning_rate * recovered),
+            dead: (recovery_rate * severe_infected) * mortality_rate + sp.natural_death_rate * (susceptible + exposed + infected + recovered + vaccinated_partially + vaccinated_fully),
+            population: (sp.natural_birth_rate * population - sp.natural_death_rate * population) - ((recovery_rate * severe_infected) * mortality_rate),
+            hospitalizations: (change_infected - change_protected_infected) * hospitalization_rate,
+            vaccinated_partially: to_partial - group_doses.second - partial_infections - (sp.natural_death_rate + vaccine_waning_rate) * vaccinated_partially,
+            vaccinated_fully: to_full - full_infections - (sp.natural_death_rate + vaccine_waning_rate) * vaccinated_fully,
+            protected_exposed: protected_infections - incubation_rate * protected_exposed - (sp.natural_death_rate * protected_exposed),
+            protected_infected: change_protected_infected
+        }
+    }).collect()
+}
+
+/// Per capita rate at which people of every province travel to each connected province.
+/// Connections with flows carry their flow every day, otherwise the traffic rate is divided equally.
+/// Travel is a round trip: every pair of provinces exchanges the mean of the volumes in both directions,
+/// which keeps the population of every province at its initial value.
+pub(crate) fn migration_rates(graph: &ProvinceGraph, province_parameters: &[SimulationParameters]) -> Vec<Vec<(usize, f32)>> {
+    let count = graph.len();
+    let mut volumes = vec![vec![0.0f32; count]; count];
+    for (origin, (province, sp)) in graph.into_iter().zip(province_parameters).enumerate() {
+        let flows: Option<Vec<f32>> = province.connections.iter().map(|c| c.daily_flow).collect();
+        match flows {
+            Some(flows) => for (c, flow) in province.connections.iter().zip(flows) {
+                volumes[origin][c.province] += flow;
+            },
+            None => for (destination, share) in province.traffic_shares() {
+                volumes[origin][destination] += sp.traffic_rate * share * sp.initial_population as f32;
+            }
+        }
+    }
+
+    (0..count).map(|origin| {
+        let population = province_parameters[origin].initial_population as f32;
+        (0..count)
+            .filter(|destination| *destination != origin && population > 0.0)
+            .map(|destination| (destination, (volumes[origin][destination] + volumes[destination][origin]) / 2.0 / population))
+            .filter(|(_, rate)| *rate > 0.0)
+            .collect()
+    }).collect()
+}
+
+/// Adds the travel of every living compartment between provinces to the rate of change of the system.
+/// Whatever leaves a province arrives in another one, so the total population is conserved.
+fn migrate(derivative: &mut [Vec<Compartments>], state: &[Vec<Compartments>], rates: &[Vec<(usize, f32)>]) {
+    for (province_idx, province_rates) in rates.iter().enumerate() {
+        for &(connected_idx, rate) in province_rates {
+            for (group_idx, group) in state[province_idx].iter().enumerate() {
+                let mut travelling = *group * rate;
+                travelling.dead = 0.0;
+                derivative[province_idx][group_idx] = derivative[province_idx][group_idx] - travelling;
+                derivative[connected_idx][group_idx] += travelling;
+            }
+        }
+    }
+}
+
+/// Represents initial value and what values it needs to repeat before it. Holds a value for every age group.
+#[derive(Debug, Clone)]
+pub struct InitialValue {
+    pub value: Vec<Compartments>,
+    pub repeating_before: Vec<Compartments>
+}
+
+impl InitialValue {
+    /// Initial values of a province. Before the start only the population is known, i.e. nobody has been infected yet.
+    /// Population and initial spreaders are divided over the age groups.
+    pub fn for_parameters(parameters: &SimulationParameters) -> Self {
+        let mut value = vec![];
+        let mut repeating_before = vec![];
+        for group in &parameters.age_groups {
+            let population = parameters.initial_population as f32 * group.population_fraction;
+            let spreaders = parameters.initial_spreaders as f32 * group.population_fraction;
+            value.push(Compartments {
+                susceptible: population - spreaders,
+                exposed: spreaders,
+                population,
+                ..Compartments::default()
+            });
+            repeating_before.push(Compartments {
+                population,
+                ..Compartments::default()
+            });
+        }
+        InitialValue { value, repeating_before }
+    }
+}
+
+/// Function giving the state of every province before the start of the simulation.
+pub type PreHistoryFn = Arc<dyn Fn(f32) -> Vec<Vec<Compartments>> + Send + Sync>;
+
+/// Runs a scenario on a graph of provinces.
+pub struct Simulation<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    pre_history: Option<PreHistoryFn>
+}
+
+impl<'a> Simulation<'a> {
+    /// Creates a simulation of the scenario on the given graph of provinces.
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario) -> Self {
+        Self { graph, scenario, pre_history: None }
+    }
+
+    /// Replaces the constant pre-history of the provinces with a function of time.
+    pub fn with_pre_history(mut self, pre_history: PreHistoryFn) -> Self {
+        self.pre_history = Some(pre_history);
+        self
+    }
+
+    /// Executes the simulation and returns the state of every province at every step.
+    pub fn run(&self) -> SimulationResults {
+        match self.scenario.integrator {
+            IntegratorKind::Euler => self.run_with(&Euler),
+            IntegratorKind::Heun => self.run_with(&Heun),
+            IntegratorKind::Rk4 => self.run_with(&Rk4),
+            IntegratorKind::DormandPrince { absolute_tolerance, relative_tolerance, max_step_size } =>
+                self.run_with(&DormandPrince { absolute_tolerance, relative_tolerance, max_step_size })
+        }
+    }
+
+    /// Executes the simulation using the given integrator.
+    pub fn run_with<I: Integrator>(&self, integrator: &I) -> SimulationResults {
+        simulate(self.graph, self.scenario, self.pre_history.clone(), integrator)
+    }
+}
+
+/// Computes mean density over provinces, used to adjust R0 of every province.
+pub(crate) fn mean_density(graph: &ProvinceGraph) -> f32 {
+    let mut mean_density = 0.0f32;
+    for p in graph {
+        mean_density += p.density_per_square_km as f32;
+    }
+    mean_density / graph.len() as f32
+}
+
+/// Times at which results are stored, from the start up to and including the end of the time span.
+pub(crate) fn output_times(scenario: &Scenario) -> Vec<f32> {
+    let end = scenario.time_span_in_days as f32;
+    generate_range(0.0, end, scenario.output_interval_in_days)
+        .into_iter()
+        .filter(|time| *time <= end + 1e-3)
+        .collect()
+}
+
+/// Builds the results of every province from the state of its age groups at every output time.
+pub(crate) fn province_results(graph: &ProvinceGraph, province_parameters: &[SimulationParameters], outputs: Vec<Vec<Vec<Compartments>>>, measures: Vec<ProvinceMeasures>) -> Vec<ProvinceResults> {
+    graph.into_iter()
+        .zip(province_parameters.iter())
+        .zip(outputs)
+        .zip(measures)
+        .map(|(((province, parameters), values), measures)| {
+            // Age groups are only stored separately when there is more than one.
+            let age_groups = if parameters.age_groups.len() > 1 {
+                parameters.age_groups.iter().enumerate().map(|(group_idx, group)| AgeGroupResults {
+                    name: group.name.clone(),
+                    values: values.iter().map(|v| v[group_idx]).collect()
+                }).collect()
+            } else { vec![] };
+
+            ProvinceResults {
+                name: province.name.clone(),
+                r_naught: parameters.r_naught,
+                sickness_period_in_days: parameters.sickness_period_in_days,
+                incubation_period_in_days: parameters.incubation_period_in_days,
+                mortality_rate: parameters.mortality_rate,
+                values: values.iter().map(|v| v.iter().copied().sum()).collect(),
+                age_groups,
+                measure_events: measures.events
+            }
+        })
+        .collect()
+}
+
+/// Activates or releases the measures of every province at `time`, the time of the current value and the last value of the history.
+pub(crate) fn update_measures(measures: &mut [ProvinceMeasures], province_parameters: &[SimulationParameters], value: &[Vec<Compartments>], history: &History<Vec<Vec<Compartments>>>, time: f32) {
+    for (idx, (province_measures, sp)) in measures.iter_mut().zip(province_parameters).enumerate() {
+        province_measures.update(sp, &value[idx], &ProvinceHistory::new(history, idx), time);
+    }
+}
+
+/// Value of every age group of every province.
+type SystemValue = Vec<Vec<Compartments>>;
+
+/// A scenario prepared for stepping on a graph of provinces: the parameters of every province and everything derived from them.
+pub(crate) struct Model<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    pub(crate) province_parameters: Vec<SimulationParameters>,
+    province_names: Vec<&'a str>,
+    group_names: Vec<&'a str>,
+    migration_rates: Option<Vec<Vec<(usize, f32)>>>,
+    min_delay: f32,
+    max_delay: f32
+}
+
+/// State of a system of provinces during a simulation, together with the history the measures look back into.
+#[derive(Clone)]
+pub(crate) struct ModelState {
+    pub(crate) t: f32,
+    h: f32,
+    pub(crate) value: Vec<Vec<Compartments>>,
+    pub(crate) history: History<Vec<Vec<Compartments>>>,
+    pub(crate) measures: Vec<ProvinceMeasures>,
+    pub(crate) transmission: f32, // Factor on the infection rate of every province. Only data assimilation changes it from 1.
+    pub(crate) statistics: SolverStatistics
+}
+
+impl<'a> Model<'a> {
+    pub(crate) fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario) -> Self {
+        let mean_density = mean_density(graph);
+
+        // Set up each province's parameters
+        let province_parameters: Vec<SimulationParameters> = graph.into_iter().map(|province| scenario.parameters_for(province, mean_density)).collect();
+
+        // Steps may not be larger than the smallest delay, so delayed states never lie beyond the stored history.
+        let delays: Vec<f32> = province_parameters.iter().flat_map(|p| p.delays()).collect();
+        let min_delay = delays.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max_delay = delays.iter().cloned().fold(0.0, f32::max);
+
+        // Migration is part of the system, seeding is applied after every step.
+        let migration_rates = if scenario.enable_traffic && scenario.traffic_model == TrafficModel::Migration {
+            Some(migration_rates(graph, &province_parameters))
+        } else { None };
+
+        Self {
+            graph,
+            scenario,
+            // Names used to apply the priorities of vaccination campaigns.
+            province_names: graph.into_iter().map(|p| p.name.as_str()).collect(),
+            group_names: scenario.age_group_names(),
+            province_parameters,
+            migration_rates,
+            min_delay,
+            max_delay
+        }
+    }
+
+    /// Initial value of every province, and the value repeating before it.
+    fn initial_values(&self) -> (Vec<Vec<Compartments>>, Vec<Vec<Compartments>>) {
+        let mut repeating_before: Vec<Vec<Compartments>> = vec![];
+        let mut value: Vec<Vec<Compartments>> = vec![];
+
+        // Set up initial values for the system. repating_before are for when DDE's are used.
+        for parameters in &self.province_parameters {
+            let t0 = InitialValue::for_parameters(parameters);
+            repeating_before.push(t0.repeating_before);
+            value.push(t0.value);
+        }
+        (value, repeating_before)
+    }
+
+    /// State at time 0, with the pre-history of the provinces given by a function or constant before that.
+    pub(crate) fn initial_state(&self, pre_history: Option<PreHistoryFn>) -> ModelState {
+        let (value, repeating_before) = self.initial_values();
+
+        // The pre-history is what is measured during the first days, before the delay between what happened and what can be measured has passed.
+        let pre_history = match pre_history {
+            Some(function) => PreHistory::Function(function),
+            None => PreHistory::Constant(repeating_before)
+        };
+        let history = History::new(0.0, value.clone(), pre_history);
+        let measures = self.province_parameters.iter().map(ProvinceMeasures::new).collect();
+        let mut state = ModelState { t: 0.0, h: self.scenario.step_size, value, history, measures, transmission: 1.0, statistics: SolverStatistics::default() };
+        self.update_measures(&mut state);
+        state
+    }
+
+    /// State continuing from a stored history, as returned by `History::stored`. The last stored value is the current state.
+    /// Measures continue from the given states, or are activated anew from the current state if they do not match.
+    pub(crate) fn resumed_state(&self, times: Vec<f32>, values: Vec<Vec<Vec<Compartments>>>, measures: Vec<ProvinceMeasures>) -> ModelState {
+        let (_, repeating_before) = self.initial_values();
+        let (t, value) = (*times.last().unwrap(), values.last().unwrap().clone());
+        let history = History::from_stored(0.0, PreHistory::Constant(repeating_before), times, values);
+        let matches = measures.len() == self.province_parameters.len()
+            && measures.iter().zip(&self.province_parameters).all(|(m, sp)| m.states.len() == sp.measures.len());
+        if matches {
+            return ModelState { t, h: self.scenario.step_size, value, history, measures, transmission: 1.0, statistics: SolverStatistics::default() };
+        }
+        let measures = self.province_parameters.iter().map(ProvinceMeasures::new).collect();
+        let mut state = ModelState { t, h: self.scenario.step_size, value, history, measures, transmission: 1.0, statistics: SolverStatistics::default() };
+        self.update_measures(&mut state);
+        state
+    }
+
+    /// Activates or releases the measures of every province at the current time of the state.
+    fn update_measures(&self, state: &mut ModelState) {
+        update_measures(&mut state.measures, &self.province_parameters, &state.value, &state.history, state.t);
+    }
+
+    /// Takes a single step towards `end`, including traffic between provinces. Returns the state before the step,
+    /// the step taken and its size, from which states within the step can be interpolated.
+    pub(crate) fn step<I: Integrator>(&self, integrator: &I, state: &mut ModelState, end: f32) -> (SystemValue, Step<SystemValue>, f32) {
+        let scenario = self.scenario;
+        let evaluations = Cell::new(0);
+        let (taken, step, next_h) = {
+            let (measures, transmission) = (&state.measures, state.transmission);
+            let f = |time: f32, y: &Vec<Vec<Compartments>>| -> Vec<Vec<Compartments>> {
+                evaluations.set(evaluations.get() + 1);
+                // Doses depend on all provinces, as provinces may take priority over each other.
+                let doses = match &scenario.vaccination {
+                    Some(vaccination) => vaccination.doses(time, y, &self.province_names, &self.group_names),
+                    None => vec![vec![]; y.len()]
+                };
+                let mut derivative: Vec<Vec<Compartments>> = y.iter()
+                    .enumerate()
+                    .map(|(idx, province)| {
+                        let sp = &self.province_parameters[idx];
+                        let change = 1.0 - (1.0 - measures[idx].change(sp, time)) * transmission;
+                        rate_of_change_with_time(sp, province, &doses[idx], change)
+                    })
+                    .collect();
+                if let Some(rates) = &self.migration_rates {
+                    migrate(&mut derivative, y, rates);
+                }
+                derivative
+            };
+            advance(integrator, &f, state.t, &state.value, state.h.min(end - state.t).min(self.min_delay), &mut state.statistics)
+        };
+        state.statistics.evaluations += evaluations.get();
+
+        // A step shortened to end exactly at `end` says nothing about the step size, so the next step tries the previous size again.
+        state.h = if end - state.t <= state.h { next_h.max(state.h) } else { next_h };
+        state.t += taken;
+        let previous = std::mem::replace(&mut state.value, step.value.clone());
+
+        // This part is responsible for computing traffic between provinces.
+        if scenario.enable_traffic && scenario.traffic_model == TrafficModel::Seeding {
+            let value = &mut state.value;
+
+            // Effectively turns a few susceptible people in other provinces into exposed.
+            for province_idx in 0..self.province_parameters.len() {
+                let province_e: f32 = value[province_idx].iter().map(|g| g.exposed).sum();
+                let delta_e = self.province_parameters[province_idx].traffic_rate * province_e * taken;
+
+                // Spread out infected cases over new provinces, in proportion to the flows towards them. Simulates effect of 'travelling'.
+                // Within a province, age groups receive cases in proportion to their susceptible people.
+                for (connected_idx, share) in self.graph[province_idx].traffic_shares() {
+                    let delta = delta_e * share;
+                    let connected_s: f32 = value[connected_idx].iter().map(|g| g.susceptible).sum();
+                    if connected_s > delta {
+                        for group in value[connected_idx].iter_mut() {
+                            let group_delta = delta * group.susceptible / connected_s;
+                            group.susceptible -= group_delta;
+                            group.exposed += group_delta;
+                        }
+                    }
+                }
+            }
+        }
+
+        state.history.push(state.t, state.value.clone());
+        state.history.discard_before(state.t - self.max_delay);
+        self.update_measures(state);
+        (previous, step, taken)
+    }
+
+    /// Steps until `end` is reached.
+    pub(crate) fn advance_to<I: Integrator>(&self, integrator: &I, state: &mut ModelState, end: f32) {
+        while end - state.t > 1e-3 {
+            self.step(integrator, state, end);
+        }
+    }
+}
+
+/// Simulates the scenario on the given graph of provinces.
+/// All provinces are integrated together as a single system, traffic is applied after each accepted step.
+fn simulate<I: Integrator>(graph: &ProvinceGraph, scenario: &Scenario, pre_history: Option<PreHistoryFn>, integrator: &I) -> SimulationResults {
+    let model = Model::new(graph, scenario);
+    let mut state = model.initial_state(pre_history);
+
+    // Results are stored at a fixed interval, independent of the steps taken by the integrator.
+    let end = scenario.time_span_in_days as f32;
+    let output_times = output_times(scenario);
+    let mut outputs: Vec<Vec<Vec<Compartments>>> = state.value.iter().map(|s| vec![s.clone()]).collect();
+
+    // Execute steps until the end of the time span is reached.
+    while end - state.t > 1e-3 {
+        let (previous, step, taken) = model.step(integrator, &mut state, end);
+        let t = state.t - taken;
+
+        // Interpolate the output times which fall within this step.
+        for &output_time in &output_times[outputs[0].len()..] {
+            if output_time > t + taken - 1e-3 {
+                break;
+            }
+            let value = integrator.interpolate(&previous, &step, taken, (output_time - t) / taken);
+            for (province_outputs, province_value) in outputs.iter_mut().zip(value) {
+                province_outputs.push(province_value);
+            }
+        }
+
+        // Output times at the end of the step use the state after traffic.
+        if outputs[0].len() < output_times.len() && output_times[outputs[0].len()] <= state.t + 1e-3 {
+            for (province_outputs, province_state) in outputs.iter_mut().zip(&state.value) {
+                province_outputs.push(province_state.clone());
+            }
+        }
+    }
+
+    let provinces = province_results(graph, &model.province_parameters, outputs, state.measures);
+
+    SimulationResults {
+        level: graph.level(),
+        time_span_in_days: scenario.time_span_in_days,
+        start_date: scenario.start_date,
+        times: output_times,
+        statistics: state.statistics,
+        provinces,
+        sewage: scenario.sewage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> ProvinceGraph {
+        let provinces = load_file::<Vec<ProvinceData>>("dataset/provinces.json").unwrap();
+        ProvinceGraphBuilder::new(provinces).build().unwrap().0
+    }
+
+    #[test]
+    fn migration_keeps_the_population_of_every_province() {
+        let graph = graph();
+        let scenario = Scenario {
+            time_span_in_days: 60,
+            traffic_model: TrafficModel::Migration,
+            natural_birth_rate: 0.0,
+            natural_death_rate: 0.0,
+            mortality_rate: 0.0,
+            ..Scenario::default()
+        };
+        let results = Simulation::new(&graph, &scenario).run();
+
+        for province in &results.provinces {
+            let (first, last) = (province.values[0].population, province.values.last().unwrap().population);
+            assert!((last - first).abs() < 1e-4 * first, "{}: {} instead of {}", province.name, last, first);
+        }
+        // Travellers carry the infection from the seed province to the rest of the country.
+        let groningen = results.provinces.iter().find(|p| p.name == "Groningen").unwrap();
+        assert!(groningen.values.last().unwrap().infected > 0.0);
+    }
+
+    #[test]
+    fn migration_moves_people_without_creating_them() {
+        let graph = graph();
+        let scenario = Scenario { traffic_model: TrafficModel::Migration, ..Scenario::default() };
+        let parameters: Vec<SimulationParameters> = (&graph).into_iter().map(|p| scenario.parameters_for(p, mean_density(&graph))).collect();
+        let rates = migration_rates(&graph, &parameters);
+
+        // Provinces with different states, so travel in both directions does not cancel out.
+        let state: Vec<Vec<Compartments>> = (0..graph.len())
+            .map(|idx| vec![Compartments { susceptible: 1000.0 * idx as f32, infected: 10.0, population: 1000.0 * idx as f32 + 10.0, ..Compartments::default() }])
+            .collect();
+        let mut derivative = vec![vec![Compartments::default()]; graph.len()];
+        migrate(&mut derivative, &state, &rates);
+
+        let total: Compartments = derivative.iter().flatten().copied().sum();
+        assert!(total.population.abs() < 1e-2 && total.susceptible.abs() < 1e-2 && total.infected.abs() < 1e-4, "{:?}", total);
+        assert!(derivative.iter().any(|p| p[0].population.abs() > 1.0));
+    }
+
+    #[test]
+    fn stores_results_at_the_output_interval_whatever_the_step_size() {
+        let graph = graph();
+        let adaptive = IntegratorKind::DormandPrince { absolute_tolerance: 1e-2, relative_tolerance: 1e-4, max_step_size: 1.0 };
+        for (integrator, step_size) in [(IntegratorKind::Rk4, 0.3), (adaptive, 0.1)] {
+            let scenario = Scenario { time_span_in_days: 10, integrator, step_size, output_interval_in_days: 0.5, ..Scenario::default() };
+            let results = Simulation::new(&graph, &scenario).run();
+            let expected: Vec<f32> = (0..=20).map(|idx| idx as f32 * 0.5).collect();
+            assert_eq!(results.times, expected, "{:?}", integrator);
+            assert!(results.provinces.iter().all(|p| p.values.len() == expected.len()));
+            // Outputs between steps are interpolated, not repeated from the last step.
+            let seed = results.provinces.iter().find(|p| p.name == scenario.seed_province).unwrap();
+            assert!(seed.values.windows(2).all(|pair| pair[0].exposed != pair[1].exposed));
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use crate::*;
+use crate::dde::*;
+use rand::SeedableRng;
+use rand_distr::{Binomial, Distribution, Poisson};
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+
+/// Samples how many of `n` people experience an event with probability `p`.
+fn binomial(rng: &mut Pcg64, n: f32, p: f32) -> f32 {
+    if n < 1.0 || p <= 0.0 {
+        return 0.0;
+    }
+    Binomial::new(n as u64, p.min(1.0) as f64).unwrap().sample(rng) as f32
+}
+
+/// Samples the number of events happening with the given expected number.
+fn poisson(rng: &mut Pcg64, mean: f32) -> f32 {
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    Poisson::new(mean as f64).unwrap().sample(rng) as f32
+}
+
+/// Divides `n` people randomly over the weights, in proportion to each weight.
+fn split(rng: &mut Pcg64, n: f32, weights: &[f32]) -> Vec<f32> {
+    let mut remaining = n;
+    let mut remaining_weight: f32 = weights.iter().map(|w| w.max(0.0)).sum();
+    weights.iter().map(|weight| {
+        let weight = weight.max(0.0);
+        let count = if remaining_weight > 0.0 { binomial(rng, remaining, weight / remaining_weight) } else { 0.0 };
+        remaining -= count;
+        remaining_weight -= weight;
+        count
+    }).collect()
+}
+
+/// Samples how many of `n` people leave a compartment during a leap of `tau` days, for each of the competing per capita rates.
+/// Never more people leave than there are in the compartment.
+fn leave(rng: &mut Pcg64, n: f32, rates: &[f32], tau: f32) -> Vec<f32> {
+    let total: f32 = rates.iter().map(|r| r.max(0.0)).sum();
+    let leaving = binomial(rng, n, 1.0 - (-total * tau).exp());
+    split(rng, leaving, rates)
+}
+
+/// Rate per person, for a total rate divided over `n` people.
+fn per_capita(rate: f32, n: f32) -> f32 {
+    if n > 0.0 { rate / n } else { 0.0 }
+}
+
+/// Performs a single leap of `tau` days for an age group. Follows the same transitions as the deterministic model,
+/// with whole people moving between compartments. Returns the new state and the number of new infections.
+fn leap_group(rng: &mut Pcg64, sp: &SimulationParameters, group_idx: usize, c: &Compartments, force: f32, doses: DoseRates, tau: f32) -> (Compartments, f32) {
+    let recovery_rate = 1.0 / (sp.sickness_period_in_days as f32);
+    let incubation_rate = 1.0 / (sp.incubation_period_in_days as f32);
+    let immunity_waning_rate = 1.0 / (sp.immunity_waning_period_in_days as f32);
+    let death_rate = sp.natural_death_rate;
+    let Protection { partial, full, two_dose, waning_rate: vaccine_waning_rate } = Protection::of(sp.vaccine.as_ref());
+    let mortality_rate = sp.age_groups[group_idx].mortality_rate;
+    let hospitalization_rate = sp.age_groups[group_idx].hospitalization_rate;
+
+    // Outflows of every compartment. Protected exposed and infected leave separately from the unprotected ones.
+    let s = leave(rng, c.susceptible, &[force, death_rate, per_capita(doses.first, c.susceptible)], tau);
+    let v1 = leave(rng, c.vaccinated_partially, &[force * (1.0 - partial.efficacy_against_infection), death_rate, vaccine_waning_rate, per_capita(doses.second, c.vaccinated_partially)], tau);
+    let v2 = leave(rng, c.vaccinated_fully, &[force * (1.0 - full.efficacy_against_infection), death_rate, vaccine_waning_rate], tau);
+    let e = leave(rng, c.exposed - c.protected_exposed, &[incubation_rate, death_rate], tau);
+    let pe = leave(rng, c.protected_exposed, &[incubation_rate, death_rate], tau);
+    let i = leave(rng, c.infected - c.protected_infected, &[recovery_rate, death_rate], tau);
+    let pi = leave(rng, c.protected_infected, &[recovery_rate, death_rate], tau);
+    let r = leave(rng, c.recovered, &[immunity_waning_rate, death_rate], tau);
+    let births = poisson(rng, sp.natural_birth_rate * c.population * tau);
+
+    // Breakthrough infections are protected against severe disease with the efficacy of the received dose.
+    let protected = binomial(rng, v1[0], partial.efficacy_against_severe_disease) + binomial(rng, v2[0], full.efficacy_against_severe_disease);
+    let deaths = binomial(rng, i[0], mortality_rate);
+    let (to_partial, to_full) = if two_dose { (s[2], v1[3]) } else { (0.0, s[2] + v1[3]) };
+    let sum = |v: &[f32]| v.iter().sum::<f32>();
+
+    let mut next = Compartments {
+        susceptible: c.susceptible - sum(&s) + births + r[0] + v1[2] + v2[2],
+        exposed: c.exposed - sum(&e) - sum(&pe) + s[0] + v1[0] + v2[0],
+        infected: c.infected - sum(&i) - sum(&pi) + e[0] + pe[0],
+        recovered: c.recovered - sum(&r) + i[0] - deaths + pi[0],
+        dead: c.dead + deaths + s[1] + v1[1] + v2[1] + e[1] + pe[1] + i[1] + pi[1] + r[1],
+        vaccinated_partially: c.vaccinated_partially - sum(&v1) + to_partial,
+        vaccinated_fully: c.vaccinated_fully - sum(&v2) + to_full,
+        protected_exposed: c.protected_exposed - sum(&pe) + protected,
+        protected_infected: c.protected_infected - sum(&pi) + pe[0],
+        ..Compartments::default()
+    };
+    next.population = next.susceptible + next.exposed + next.infected + next.recovered + next.vaccinated_partially + next.vaccinated_fully;
+    next.hospitalizations = (next.infected - next.protected_infected) * hospitalization_rate;
+    (next, s[0] + v1[0] + v2[0])
+}
+
+/// Moves random people of every living compartment along the connections during a leap of `tau` days.
+/// Protected exposed and infected travel separately, so they remain part of exposed and infected.
+fn migrate(rng: &mut Pcg64, state: &mut [Vec<Compartments>], rates: &[Vec<(usize, f32)>], parameters: &[SimulationParameters], tau: f32) {
+    let before = state.to_vec();
+    let person = Compartments { population: 1.0, ..Compartments::default() };
+    for (province_idx, province_rates) in rates.iter().enumerate() {
+        let destination_rates: Vec<f32> = province_rates.iter().map(|(_, rate)| *rate).collect();
+        for (group_idx, c) in before[province_idx].iter().enumerate() {
+            let parts = [
+                (c.susceptible, Compartments { susceptible: 1.0, ..person }),
+                (c.exposed - c.protected_exposed, Compartments { exposed: 1.0, ..person }),
+                (c.protected_exposed, Compartments { exposed: 1.0, protected_exposed: 1.0, ..person }),
+                (c.infected - c.protected_infected, Compartments { infected: 1.0, ..person }),
+                (c.protected_infected, Compartments { infected: 1.0, protected_infected: 1.0, ..person }),
+                (c.recovered, Compartments { recovered: 1.0, ..person }),
+                (c.vaccinated_partially, Compartments { vaccinated_partially: 1.0, ..person }),
+                (c.vaccinated_fully, Compartments { vaccinated_fully: 1.0, ..person })
+            ];
+            for (count, unit) in parts.iter() {
+                let moved = leave(rng, *count, &destination_rates, tau);
+                for ((connected_idx, _), travelling) in province_rates.iter().zip(moved) {
+                    state[province_idx][group_idx] = state[province_idx][group_idx] - *unit * travelling;
+                    state[*connected_idx][group_idx] += *unit * travelling;
+                }
+            }
+        }
+    }
+    for (sp, province) in parameters.iter().zip(state.iter_mut()) {
+        for (group_parameters, group) in sp.age_groups.iter().zip(province.iter_mut()) {
+            group.hospitalizations = (group.infected - group.protected_infected) * group_parameters.hospitalization_rate;
+        }
+    }
+}
+
+/// Outcome of a single realization.
+struct Realization {
+    values: Vec<Vec<Compartments>>, // State of every province at every output time, summed over age groups.
+    cumulative_infections: Vec<f32> // Infections during the realization in every province.
+}
+
+/// Runs many realizations of a scenario with whole people, using tau-leaping.
+/// Shows effects the deterministic model can not, such as early outbreaks dying out.
+pub struct StochasticSimulation<'a> {
+    graph: &'a ProvinceGraph,
+    scenario: &'a Scenario,
+    settings: &'a StochasticSettings
+}
+
+impl<'a> StochasticSimulation<'a> {
+    pub fn new(graph: &'a ProvinceGraph, scenario: &'a Scenario, settings: &'a StochasticSettings) -> Self {
+        Self { graph, scenario, settings }
+    }
+
+    /// Executes all realizations in parallel and summarizes them. Realization `i` uses seed `seed + i`.
+    pub fn run(&self) -> EnsembleResults {
+        let mean_density = mean_density(self.graph);
+        let parameters: Vec<SimulationParameters> = self.graph.into_iter().map(|p| self.scenario.parameters_for(p, mean_density)).collect();
+
+        let realizations: Vec<Realization> = (0..self.settings.realizations)
+            .into_par_iter()
+            .map(|idx| self.realization(&parameters, self.settings.seed.wrapping_add(idx as u64)))
+            .collect();
+
+        self.summarize(&realizations)
+    }
+
+    fn realization(&self, parameters: &[SimulationParameters], seed: u64) -> Realization {
+        let mut rng = Pcg64::seed_from_u64(seed);
+        let graph = self.graph;
+        let scenario = self.scenario;
+
+        // Population is rounded per age group, initial spreaders are placed in random age groups.
+        let mut state: Vec<Vec<Compartments>> = vec![];
+        let mut repeating_before: Vec<Vec<Compartments>> = vec![];
+        for sp in parameters {
+            let fractions: Vec<f32> = sp.age_groups.iter().map(|g| g.population_fraction).collect();
+            let spreaders = split(&mut rng, sp.initial_spreaders as f32, &fractions);
+            let populations: Vec<f32> = fractions.iter().map(|f| (sp.initial_population as f32 * f).round()).collect();
+            state.push(populations.iter().zip(&spreaders).map(|(population, spreaders)| Compartments {
+                susceptible: population - spreaders.min(*population),
+                exposed: spreaders.min(*population),
+                population: *population,
+                ..Compartments::default()
+            }).collect());
+            repeating_before.push(populations.iter().map(|population| Compartments { population: *population, ..Compartments::default() }).collect());
+        }
+
+        let mut history = History::new(0.0, state.clone(), PreHistory::Constant(repeating_before));
+        let delays: Vec<f32> = parameters.iter().flat_map(|p| p.delays()).collect();
+        let min_delay = delays.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max_delay = delays.iter().cloned().fold(0.0, f32::max);
+        let mut measures: Vec<ProvinceMeasures> = parameters.iter().map(ProvinceMeasures::new).collect();
+        update_measures(&mut measures, parameters, &state, &history, 0.0);
+
+        let province_names: Vec<&str> = graph.into_iter().map(|p| p.name.as_str()).collect();
+        let group_names = scenario.age_group_names();
+
+        let end = scenario.time_span_in_days as f32;
+        let output_times = output_times(scenario);
+        let mut values: Vec<Vec<Compartments>> = state.iter().map(|s| vec![s.iter().copied().sum()]).collect();
+        let mut cumulative_infections = vec![0.0; state.len()];
+
+        let migration_rates = if scenario.enable_traffic && scenario.traffic_model == TrafficModel::Migration {
+            Some(migration_rates(graph, parameters))
+        } else { None };
+
+        let mut t = 0.0;
+        while end - t > 1e-3 {
+            let tau = self.settings.step_size.min(end - t).min(min_delay);
+
+            let doses = match &scenario.vaccination {
+                Some(vaccination) => vaccination.doses(t, &state, &province_names, &group_names),
+                None => vec![vec![]; state.len()]
+            };
+            let mut next = Vec::with_capacity(state.len());
+            for (province_idx, province) in state.iter().enumerate() {
+                let sp = &parameters[province_idx];
+                let forces = forces_of_infection(sp, province, measures[province_idx].change(sp, t));
+                let mut groups = Vec::with_capacity(province.len());
+                for (group_idx, group) in province.iter().enumerate() {
+                    let group_doses = doses[province_idx].get(group_idx).copied().unwrap_or_default();
+                    let (value, infections) = leap_group(&mut rng, sp, group_idx, group, forces[group_idx], group_doses, tau);
+                    cumulative_infections[province_idx] += infections;
+                    groups.push(value);
+                }
+                next.push(groups);
+            }
+            state = next;
+
+            // Travelling exposed people infect susceptible people in connected provinces, as in the deterministic model.
+            if scenario.enable_traffic && scenario.traffic_model == TrafficModel::Seeding {
+                for province_idx in 0..parameters.len() {
+                    let province_e: f32 = state[province_idx].iter().map(|g| g.exposed).sum();
+                    for (connected_idx, share) in graph[province_idx].traffic_shares() {
+                        let susceptible: Vec<f32> = state[connected_idx].iter().map(|g| g.susceptible).collect();
+                        let cases = poisson(&mut rng, parameters[province_idx].traffic_rate * province_e * tau * share)
+                            .min(susceptible.iter().sum());
+                        for (group, group_cases) in state[connected_idx].iter_mut().zip(split(&mut rng, cases, &susceptible)) {
+                            group.susceptible -= group_cases;
+                            group.exposed += group_cases;
+                        }
+                        cumulative_infections[connected_idx] += cases;
+                    }
+                }
+            }
+
+            if let Some(rates) = &migration_rates {
+                migrate(&mut rng, &mut state, rates, parameters, tau);
+            }
+
+            t += tau;
+            history.push(t, state.clone());
+            history.discard_before(t - max_delay);
+            update_measures(&mut measures, parameters, &state, &history, t);
+
+            while values[0].len() < output_times.len() && output_times[values[0].len()] <= t + 1e-3 {
+                for (province_values, province_state) in values.iter_mut().zip(&state) {
+                    province_values.push(province_state.iter().copied().sum());
+                }
+            }
+        }
+
+        Realization { values, cumulative_infections }
+    }
+
+    /// Computes outbreak probabilities and quantile bands over all realizations.
+    fn summarize(&self, realizations: &[Realization]) -> EnsembleResults {
+        let count = realizations.len() as f32;
+        let threshold = self.settings.outbreak_threshold;
+        let times = output_times(self.scenario);
+
+        let provinces = self.graph.into_iter().enumerate().map(|(province_idx, province)| {
+            let mut quantiles: Vec<QuantileResults> = self.settings.quantiles.iter()
+                .map(|q| QuantileResults { quantile: *q, values: vec![Compartments::default(); times.len()] })
+                .collect();
+
+            for time_idx in 0..times.len() {
+                for compartment in Compartment::ALL.iter() {
+                    let mut samples: Vec<f32> = realizations.iter().map(|r| r.values[province_idx][time_idx][*compartment]).collect();
+                    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    for q in quantiles.iter_mut() {
+                        q.values[time_idx][*compartment] = quantile(&samples, q.quantile);
+                    }
+                }
+            }
+
+            let outbreaks = realizations.iter().filter(|r| r.cumulative_infections[province_idx] >= threshold).count();
+            ProvinceEnsembleResults {
+                name: province.name.clone(),
+                outbreak_probability: outbreaks as f32 / count,
+                quantiles
+            }
+        }).collect();
+
+        let outbreaks = realizations.iter().filter(|r| r.cumulative_infections.iter().sum::<f32>() >= threshold).count();
+        EnsembleResults {
+            realizations: realizations.len(),
+            seed: self.settings.seed,
+            outbreak_probability: outbreaks as f32 / count,
+            times,
+            provinces
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Living and dead people of every output time, summed over the provinces in double precision.
+    fn totals(scenario: &Scenario) -> Vec<f64> {
+        let provinces = load_file::<Vec<ProvinceData>>("dataset/provinces.json").unwrap();
+        let graph = ProvinceGraphBuilder::new(provinces).build().unwrap().0;
+        let settings = StochasticSettings { realizations: 1, ..StochasticSettings::default() };
+        let simulation = StochasticSimulation::new(&graph, scenario, &settings);
+        let mean_density = mean_density(&graph);
+        let parameters: Vec<SimulationParameters> = (&graph).into_iter().map(|p| scenario.parameters_for(p, mean_density)).collect();
+        let realization = simulation.realization(&parameters, 0);
+        (0..realization.values[0].len())
+            .map(|step| realization.values.iter().map(|province| (province[step].population + province[step].dead) as f64).sum())
+            .collect()
+    }
+
+    #[test]
+    fn tau_leaping_conserves_the_population_without_births() {
+        let scenario = Scenario { time_span_in_days: 60, natural_birth_rate: 0.0, ..Scenario::default() };
+        let totals = totals(&scenario);
+        assert!(totals.len() > 1);
+        assert!(totals.iter().all(|total| (total - totals[0]).abs() < 0.5), "{:?}", totals);
+    }
+
+    #[test]
+    fn tau_leaping_conserves_the_population_with_migration() {
+        let scenario = Scenario { time_span_in_days: 60, natural_birth_rate: 0.0, traffic_model: TrafficModel::Migration, ..Scenario::default() };
+        let totals = totals(&scenario);
+        assert!(totals.iter().all(|total| (total - totals[0]).abs() < 0.5), "{:?}", totals);
+    }
+}
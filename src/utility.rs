@@ -23,17 +23,17 @@ pub fn load_file_bin(path: &str) -> Option<Vec<u8>> {
     Some(bytes)
 }
 
-// Function which loads a JSON file and attempts to decode it into T.
-pub fn load_file<T: DeserializeOwned>(path: &str) -> Option<T> {
-    let data = load_file_bin(path)?;
-    let obj: T = match serde_json::from_slice(data.as_slice()) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("{}",e);
-            return None
-        }
-    };
-    Some(obj)
+// Function which loads a JSON file and attempts to decode it into T. The error describes why reading or decoding failed.
+pub fn load_file<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    serde_json::from_slice(data.as_slice()).map_err(|e| e.to_string())
+}
+
+// Function which encodes T as JSON and writes it to a file.
+pub fn save_file<T: Serialize>(path: &str, obj: &T) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(std::io::BufWriter::new(file), obj)?;
+    Ok(())
 }
 
 // Generate float range from start to end using step as stepsize
@@ -55,4 +55,48 @@ pub fn generate_range_from_input(input_len: usize, step: f32) -> Vec<f32> {
         vec.push(0.0 + (i as f32) * step)
     }
     vec
-}
\ No newline at end of file
+}
+
+// Computes the spectral radius of a non-negative square matrix using power iteration.
+pub fn spectral_radius(matrix: &[Vec<f32>]) -> f32 {
+    let mut vector = vec![1.0f32; matrix.len()];
+    let mut radius = 0.0;
+    for _ in 0..1000 {
+        let next: Vec<f32> = matrix.iter().map(|row| row.iter().zip(&vector).map(|(a, v)| a * v).sum()).collect();
+        let norm = next.iter().cloned().fold(0.0, f32::max);
+        if norm <= 0.0 {
+            return 0.0;
+        }
+        vector = next.iter().map(|v| v / norm).collect();
+        if (norm - radius).abs() <= 1e-6 * norm {
+            return norm;
+        }
+        radius = norm;
+    }
+    radius
+}
+
+// Computes quantile q of sorted values, interpolating linearly between the closest ranks.
+pub fn quantile(sorted: &[f32], q: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let position = q.clamp(0.0, 1.0) * (sorted.len() - 1) as f32;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (position - lower as f32)
+}
+
+// Interpolates linearly between the values at sorted times. Returns None outside of the times.
+pub fn interpolate(times: &[f32], values: &[f32], t: f32) -> Option<f32> {
+    let upper = times.iter().position(|time| *time >= t)?;
+    if times[upper] == t {
+        return values.get(upper).copied();
+    }
+    if upper == 0 {
+        return None;
+    }
+    let (t0, t1) = (times[upper - 1], times[upper]);
+    let (v0, v1) = (*values.get(upper - 1)?, *values.get(upper)?);
+    Some(v0 + (v1 - v0) * (t - t0) / (t1 - t0))
+}